@@ -0,0 +1,136 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::sync::broadcast::error::RecvError;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    imgforge_home,
+    logs::{LogEvent, LogHub, LogLine},
+    recipe::Recipe,
+    run_build,
+    secrets::SecretStore,
+};
+
+const BUILD_USAGE: &str = "usage: imgforge-backend build <recipe.toml|recipe.yaml> [--out <dir>]";
+
+/// `imgforge-backend build <recipe> [--out <dir>]`: runs a single build
+/// from a recipe file without starting the web server, streaming the job log
+/// to stdout. Returns the process exit code.
+pub async fn build(args: &[String]) -> i32 {
+    let mut recipe_path = None;
+    let mut out_dir = PathBuf::from(".");
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--out" | "-o" => match args.next() {
+                Some(dir) => out_dir = PathBuf::from(dir),
+                None => return usage_error("--out needs a directory"),
+            },
+            "--help" | "-h" => {
+                println!("{}", BUILD_USAGE);
+                return 0;
+            }
+            _ if recipe_path.is_none() && !arg.starts_with('-') => {
+                recipe_path = Some(PathBuf::from(arg))
+            }
+            _ => return usage_error(&format!("unexpected argument '{}'", arg)),
+        }
+    }
+    let Some(recipe_path) = recipe_path else {
+        return usage_error("missing recipe file");
+    };
+
+    let base_dir = recipe_path.parent().unwrap_or(Path::new(".")).to_path_buf();
+    let config = match Recipe::load(&recipe_path)
+        .and_then(|recipe| recipe.into_config(&base_dir))
+        .and_then(|config| config.validate().map(|_| config))
+    {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("imgforge: {}", e);
+            return 1;
+        }
+    };
+    if let Err(e) = fs::create_dir_all(&out_dir) {
+        eprintln!("imgforge: failed to create {}: {}", out_dir.display(), e);
+        return 1;
+    }
+
+    let secrets = match SecretStore::open(&imgforge_home()) {
+        Ok(secrets) => secrets,
+        Err(e) => {
+            eprintln!("imgforge: failed to open secret store: {}", e);
+            return 1;
+        }
+    };
+    if let Err(e) = secrets.check_references(&config) {
+        eprintln!("imgforge: {}", e);
+        return 1;
+    }
+
+    let logs_dir = imgforge_home().join("logs");
+    if let Err(e) = fs::create_dir_all(&logs_dir) {
+        eprintln!("imgforge: failed to create {}: {}", logs_dir.display(), e);
+        return 1;
+    }
+    let logs = Arc::new(LogHub::new(logs_dir));
+    let job_id = uuid::Uuid::new_v4().to_string();
+    logs.open(&job_id).await;
+    let printer = tokio::spawn(print_log(
+        logs.subscribe(&job_id)
+            .await
+            .expect("log channel was just opened"),
+    ));
+
+    let cancel = CancellationToken::new();
+    let on_interrupt = cancel.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            eprintln!("imgforge: interrupted, stopping build");
+            on_interrupt.cancel();
+        }
+    });
+
+    let result = run_build(
+        job_id.clone(),
+        config,
+        &secrets,
+        out_dir,
+        logs.clone(),
+        cancel.clone(),
+    )
+    .await;
+    logs.close(&job_id).await;
+    let _ = printer.await;
+
+    match result {
+        Ok(_) if cancel.is_cancelled() => 130,
+        Ok(status) if status.success() => 0,
+        Ok(status) => {
+            eprintln!("imgforge: build failed with {}", status);
+            status.code().filter(|&code| code != 0).unwrap_or(1)
+        }
+        Err(e) => {
+            eprintln!("imgforge: {}", e);
+            1
+        }
+    }
+}
+
+async fn print_log(mut rx: tokio::sync::broadcast::Receiver<LogEvent>) {
+    loop {
+        match rx.recv().await {
+            Ok(LogEvent::Line { text, .. }) => println!("{}", LogLine::parse_or_raw(&text).text),
+            Ok(LogEvent::Finished) | Err(RecvError::Closed) => break,
+            Err(RecvError::Lagged(skipped)) => eprintln!("imgforge: skipped {} log lines", skipped),
+        }
+    }
+}
+
+fn usage_error(message: &str) -> i32 {
+    eprintln!("imgforge: {}\n{}", message, BUILD_USAGE);
+    2
+}
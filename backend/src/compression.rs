@@ -0,0 +1,597 @@
+use flate2::read::MultiGzDecoder;
+use std::{
+    fs::File,
+    io::{self, Read},
+    os::unix::fs::FileExt,
+    path::Path,
+};
+use xz2::read::XzDecoder;
+use zip::ZipArchive;
+
+/// Container formats an image can be flashed from, told apart by their
+/// magic bytes rather than the file name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Xz,
+    Gzip,
+    Zstd,
+    Zip,
+}
+
+impl Compression {
+    fn detect(file: &File) -> io::Result<Self> {
+        let mut magic = [0u8; 6];
+        let n = file.read_at(&mut magic, 0)?;
+        let magic = &magic[..n];
+        Ok(if magic.starts_with(b"\xfd7zXZ\x00") {
+            Compression::Xz
+        } else if magic.starts_with(b"\x1f\x8b") {
+            Compression::Gzip
+        } else if magic.starts_with(b"\x28\xb5\x2f\xfd") {
+            Compression::Zstd
+        } else if magic.starts_with(b"PK\x03\x04") {
+            Compression::Zip
+        } else {
+            Compression::None
+        })
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Compression::None => "raw",
+            Compression::Xz => "xz",
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+            Compression::Zip => "zip",
+        }
+    }
+}
+
+/// An image file opened for flashing, possibly compressed.
+pub struct ImageSource {
+    pub compression: Compression,
+    /// Size of the image once decompressed, when the format records it.
+    /// gzip only keeps it modulo 4 GiB, so it is never taken from there.
+    pub size: Option<u64>,
+    /// Size of the file itself.
+    pub file_len: u64,
+    file: File,
+    /// Index of the image inside a zip archive.
+    zip_entry: usize,
+}
+
+impl ImageSource {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        let compression = Compression::detect(&file)?;
+
+        let mut zip_entry = 0;
+        let size = match compression {
+            Compression::None => Some(file_len),
+            Compression::Xz => xz_size(&file, file_len),
+            Compression::Gzip => None,
+            Compression::Zstd => zstd_size(&file, file_len),
+            Compression::Zip => {
+                let mut archive = ZipArchive::new(&file).map_err(invalid)?;
+                zip_entry = zip_image_entry(&mut archive)?;
+                let size = archive.by_index(zip_entry).map_err(invalid)?.size();
+                Some(size)
+            }
+        };
+
+        Ok(ImageSource {
+            compression,
+            size,
+            file_len,
+            file,
+            zip_entry,
+        })
+    }
+
+    /// A lower bound for the decompressed size: the recorded size, or the
+    /// file size when the format does not record one.
+    pub fn min_size(&self) -> u64 {
+        self.size.unwrap_or(self.file_len)
+    }
+
+    /// The file itself, if it is not compressed.
+    pub fn into_raw(self) -> Option<File> {
+        (self.compression == Compression::None).then_some(self.file)
+    }
+
+    /// Calls `f` with a reader that streams the decompressed image.
+    pub fn read<T>(self, f: impl FnOnce(&mut dyn Read) -> T) -> io::Result<T> {
+        let file = self.file;
+        Ok(match self.compression {
+            Compression::None => f(&mut &file),
+            Compression::Xz => f(&mut XzDecoder::new_multi_decoder(file)),
+            Compression::Gzip => f(&mut MultiGzDecoder::new(file)),
+            Compression::Zstd => f(&mut zstd::Decoder::new(file)?),
+            Compression::Zip => {
+                let mut archive = ZipArchive::new(file).map_err(invalid)?;
+                let mut entry = archive.by_index(self.zip_entry).map_err(invalid)?;
+                f(&mut entry)
+            }
+        })
+    }
+}
+
+/// The image inside a zip archive: its only file, or else its only `.img`.
+fn zip_image_entry(archive: &mut ZipArchive<&File>) -> io::Result<usize> {
+    let mut files = Vec::new();
+    for i in 0..archive.len() {
+        let entry = archive.by_index_raw(i).map_err(invalid)?;
+        let hidden = entry.name().starts_with("__MACOSX/")
+            || entry
+                .name()
+                .rsplit('/')
+                .next()
+                .unwrap_or_default()
+                .starts_with('.');
+        if entry.is_file() && !hidden {
+            files.push((i, entry.name().to_string()));
+        }
+    }
+
+    let images: Vec<_> = files
+        .iter()
+        .filter(|(_, name)| name.ends_with(".img"))
+        .collect();
+    match (files.as_slice(), images.as_slice()) {
+        ([(i, _)], _) | (_, [(i, _)]) => Ok(*i),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "zip archive must hold a single image, found {} files",
+                files.len()
+            ),
+        )),
+    }
+}
+
+/// Sums the uncompressed sizes recorded in the index of each stream, walking
+/// backwards from the end of the file as `xz --list` does.
+fn xz_size(file: &File, file_len: u64) -> Option<u64> {
+    const HEADER_LEN: u64 = 12;
+    const MAX_INDEX: u64 = 64 * 1024 * 1024;
+
+    let mut end = file_len;
+    let mut total = 0u64;
+    while end > 0 {
+        // Streams may be followed by padding in 4-byte words of zeros.
+        let mut word = [0u8; 4];
+        loop {
+            file.read_exact_at(&mut word, end.checked_sub(4)?).ok()?;
+            if word != [0; 4] {
+                break;
+            }
+            end -= 4;
+        }
+
+        let mut footer = [0u8; 12];
+        file.read_exact_at(&mut footer, end.checked_sub(HEADER_LEN)?)
+            .ok()?;
+        if &footer[10..] != b"YZ" {
+            return None;
+        }
+        let backward = (u32::from_le_bytes(footer[4..8].try_into().ok()?) as u64 + 1) * 4;
+        if backward > MAX_INDEX {
+            return None;
+        }
+        let index_start = (end - HEADER_LEN).checked_sub(backward)?;
+        let mut index = vec![0u8; backward as usize];
+        file.read_exact_at(&mut index, index_start).ok()?;
+        if index[0] != 0 {
+            return None;
+        }
+
+        let mut pos = 1;
+        let records = read_varint(&index, &mut pos)?;
+        let mut blocks_len = 0u64;
+        for _ in 0..records {
+            let unpadded = read_varint(&index, &mut pos)?;
+            total = total.checked_add(read_varint(&index, &mut pos)?)?;
+            blocks_len = blocks_len.checked_add(unpadded.checked_add(3)? & !3)?;
+        }
+
+        let stream_start = index_start
+            .checked_sub(blocks_len)?
+            .checked_sub(HEADER_LEN)?;
+        let mut magic = [0u8; 6];
+        file.read_exact_at(&mut magic, stream_start).ok()?;
+        if &magic != b"\xfd7zXZ\x00" {
+            return None;
+        }
+        end = stream_start;
+    }
+    Some(total)
+}
+
+/// xz's variable-length integers: 7 bits per byte, low bits first.
+fn read_varint(data: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..63).step_by(7) {
+        let byte = *data.get(*pos)?;
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Sums the content sizes recorded in each frame header. `zstd` records it
+/// unless the input size was unknown when compressing, e.g. when reading a
+/// pipe. Frames do not record their own length, so the next one is found by
+/// walking the block headers; skippable frames are passed over.
+fn zstd_size(file: &File, file_len: u64) -> Option<u64> {
+    const FRAME_MAGIC: u32 = 0xfd2f_b528;
+    const SKIPPABLE_MAGIC: u32 = 0x184d_2a50;
+
+    let mut pos = 0u64;
+    let mut total = 0u64;
+    while pos < file_len {
+        let mut header = [0u8; 18];
+        let n = file.read_at(&mut header, pos).ok()?;
+        let header = &header[..n];
+        let word = |at: usize| Some(u32::from_le_bytes(header.get(at..at + 4)?.try_into().ok()?));
+        let magic = word(0)?;
+        if magic & 0xffff_fff0 == SKIPPABLE_MAGIC {
+            pos = pos.checked_add(8 + word(4)? as u64)?;
+            continue;
+        }
+        if magic != FRAME_MAGIC {
+            return None;
+        }
+        let size = zstd::zstd_safe::get_frame_content_size(header).ok()??;
+        total = total.checked_add(size)?;
+
+        // Magic, descriptor, window, dictionary id and content size.
+        let descriptor = *header.get(4)?;
+        let single_segment = (descriptor >> 5) & 1;
+        let dictionary_len = [0, 1, 2, 4][(descriptor & 3) as usize];
+        let size_len = [single_segment, 2, 4, 8][(descriptor >> 6) as usize];
+        pos += 5 + (1 - single_segment) as u64 + dictionary_len + size_len as u64;
+
+        loop {
+            let mut block = [0u8; 3];
+            file.read_exact_at(&mut block, pos).ok()?;
+            let block = u32::from_le_bytes([block[0], block[1], block[2], 0]);
+            let len = match (block >> 1) & 3 {
+                // Raw and compressed blocks store `size` bytes, RLE blocks
+                // one byte repeated `size` times.
+                0 | 2 => (block >> 3) as u64,
+                1 => 1,
+                _ => return None,
+            };
+            pos = pos.checked_add(3 + len)?;
+            if block & 1 == 1 {
+                break;
+            }
+        }
+        if descriptor & 0x04 != 0 {
+            pos += 4;
+        }
+    }
+    (pos == file_len).then_some(total)
+}
+
+fn invalid(e: zip::result::ZipError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::DeflateEncoder, write::GzEncoder, Crc};
+    use std::io::{Cursor, Write};
+    use xz2::{stream::MtStreamBuilder, write::XzEncoder};
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    fn image(len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|i| (i * 7 % 251) as u8 ^ (i >> 12) as u8)
+            .collect()
+    }
+
+    fn try_open(data: &[u8]) -> io::Result<ImageSource> {
+        let path = std::env::temp_dir().join(format!("imgforge-image-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&path, data).unwrap();
+        let source = ImageSource::open(&path);
+        std::fs::remove_file(&path).unwrap();
+        source
+    }
+
+    fn open(data: &[u8]) -> ImageSource {
+        try_open(data).unwrap()
+    }
+
+    fn decompress(data: &[u8]) -> Vec<u8> {
+        let mut image = Vec::new();
+        open(data)
+            .read(|r| r.read_to_end(&mut image))
+            .unwrap()
+            .unwrap();
+        image
+    }
+
+    /// Opens `data` as an image; checks the recorded size, if any, against
+    /// what actually decompresses.
+    fn probe(data: &[u8]) -> (Compression, Option<u64>) {
+        let source = open(data);
+        let (compression, size) = (source.compression, source.size);
+        let decompressed = source
+            .read(|r| io::copy(r, &mut io::sink()))
+            .unwrap()
+            .unwrap();
+        if let Some(size) = size {
+            assert_eq!(size, decompressed);
+        }
+        (compression, size)
+    }
+
+    fn xz(data: &[u8], block_size: Option<u64>) -> Vec<u8> {
+        let mut builder = MtStreamBuilder::new();
+        builder.threads(1).preset(1);
+        if let Some(block_size) = block_size {
+            builder.block_size(block_size);
+        }
+        let mut encoder = XzEncoder::new_stream(Vec::new(), builder.encoder().unwrap());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn zstd_frame(data: &[u8]) -> Vec<u8> {
+        zstd::bulk::compress(data, 3).unwrap()
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// The ISIZE field of a gzip member's trailer.
+    fn isize_trailer(file: &[u8]) -> u32 {
+        u32::from_le_bytes(file[file.len() - 4..].try_into().unwrap())
+    }
+
+    /// A gzip member of `1 + 258 * matches` zeros, built by hand so that
+    /// images past 4 GiB cost a few megabytes: one fixed-Huffman block with
+    /// a literal zero and then copies of 258 bytes at distance 1. It is
+    /// never decompressed, so the CRC is left zero.
+    fn gzip_zeros(matches: u64) -> Vec<u8> {
+        let mut file = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+        let (mut bits, mut pending) = (0u64, 0u32);
+        let mut put = |file: &mut Vec<u8>, value: u64, len: u32| {
+            bits |= value << pending;
+            pending += len;
+            while pending >= 8 {
+                file.push(bits as u8);
+                bits >>= 8;
+                pending -= 8;
+            }
+        };
+        // Huffman codes go out most significant bit first, hence reversed.
+        put(&mut file, 0b011, 3); // Final block, fixed codes.
+        put(&mut file, 0b0000_1100, 8); // Literal 0.
+        for _ in 0..matches {
+            put(&mut file, 0b1010_0011, 13); // Length 258, then distance 1.
+        }
+        put(&mut file, 0, 7 + 7); // End of block, padded to a byte.
+
+        let len = 1 + 258 * matches;
+        file.extend(0u32.to_le_bytes());
+        file.extend((len as u32).to_le_bytes());
+        file
+    }
+
+    fn zip(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut archive = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in entries {
+            archive
+                .start_file(*name, SimpleFileOptions::default())
+                .unwrap();
+            archive.write_all(data).unwrap();
+        }
+        archive.finish().unwrap().into_inner()
+    }
+
+    /// A single-entry archive as streaming zippers write it: the local
+    /// header leaves the CRC and sizes zero and a data descriptor after the
+    /// data carries them.
+    fn zip_streamed(name: &str, data: &[u8]) -> Vec<u8> {
+        let mut deflated = DeflateEncoder::new(Vec::new(), flate2::Compression::fast());
+        deflated.write_all(data).unwrap();
+        let deflated = deflated.finish().unwrap();
+        let mut crc = Crc::new();
+        crc.update(data);
+        let (crc, packed, unpacked) = (crc.sum(), deflated.len() as u32, data.len() as u32);
+        // Version 2.0, data descriptor flag, deflate, 1980-01-01 00:00.
+        let common = |file: &mut Vec<u8>, sizes: [u32; 3]| {
+            file.extend([20, 0, 8, 0, 8, 0, 0, 0, 0x21, 0]);
+            for field in sizes {
+                file.extend(field.to_le_bytes());
+            }
+            file.extend((name.len() as u16).to_le_bytes());
+            file.extend([0, 0]);
+        };
+
+        let mut file = 0x0403_4b50u32.to_le_bytes().to_vec();
+        common(&mut file, [0, 0, 0]);
+        file.extend(name.as_bytes());
+        file.extend(&deflated);
+        for field in [0x0807_4b50, crc, packed, unpacked] {
+            file.extend(field.to_le_bytes());
+        }
+
+        let directory = file.len() as u32;
+        file.extend(0x0201_4b50u32.to_le_bytes());
+        file.extend([20, 0]);
+        common(&mut file, [crc, packed, unpacked]);
+        // Comment, disk, attributes and the local header's offset.
+        file.extend([0; 14]);
+        file.extend(name.as_bytes());
+        let directory_len = file.len() as u32 - directory;
+
+        file.extend(0x0605_4b50u32.to_le_bytes());
+        file.extend([0, 0, 0, 0, 1, 0, 1, 0]);
+        file.extend(directory_len.to_le_bytes());
+        file.extend(directory.to_le_bytes());
+        file.extend([0, 0]);
+        file
+    }
+
+    #[test]
+    fn xz_single_block() {
+        let data = image(100_000);
+        assert_eq!(probe(&xz(&data, None)), (Compression::Xz, Some(100_000)));
+    }
+
+    #[test]
+    fn xz_multiple_blocks() {
+        let data = image(300_000);
+        let file = xz(&data, Some(64 * 1024));
+        assert_eq!(probe(&file), (Compression::Xz, Some(300_000)));
+    }
+
+    #[test]
+    fn xz_concatenated_streams_with_padding() {
+        let mut file = xz(&image(70_000), Some(16 * 1024));
+        file.extend([0; 8]);
+        file.extend(xz(&image(5_000), None));
+        file.extend([0; 4]);
+        assert_eq!(probe(&file), (Compression::Xz, Some(75_000)));
+    }
+
+    #[test]
+    fn xz_truncated() {
+        let file = xz(&image(100_000), None);
+        assert_eq!(open(&file[..file.len() - 1]).size, None);
+    }
+
+    #[test]
+    fn zstd_single_frame() {
+        let data = image(200_000);
+        assert_eq!(
+            probe(&zstd_frame(&data)),
+            (Compression::Zstd, Some(200_000))
+        );
+
+        // RLE blocks and a checksum.
+        let mut encoder = zstd::bulk::Compressor::new(19).unwrap();
+        encoder.include_checksum(true).unwrap();
+        let file = encoder.compress(&[0u8; 500_000]).unwrap();
+        assert_eq!(probe(&file), (Compression::Zstd, Some(500_000)));
+    }
+
+    #[test]
+    fn zstd_multiple_frames() {
+        let mut file = zstd_frame(&image(150_000));
+        // A skippable frame, as `zstd` itself never writes but `pzstd` does.
+        file.extend(0x184d_2a53u32.to_le_bytes());
+        file.extend(3u32.to_le_bytes());
+        file.extend(b"pad");
+        file.extend(zstd_frame(&image(20_000)));
+        assert_eq!(probe(&file), (Compression::Zstd, Some(170_000)));
+    }
+
+    #[test]
+    fn zstd_without_content_size() {
+        // Streaming compression does not know the size up front.
+        let streamed = zstd::encode_all(&image(50_000)[..], 3).unwrap();
+        assert_eq!(probe(&streamed), (Compression::Zstd, None));
+
+        let mut file = zstd_frame(&image(50_000));
+        file.extend(streamed);
+        assert_eq!(probe(&file), (Compression::Zstd, None));
+
+        let mut truncated = zstd_frame(&image(50_000));
+        truncated.extend(&zstd_frame(&image(50_000))[..20]);
+        assert_eq!(open(&truncated).size, None);
+    }
+
+    #[test]
+    fn gzip_size_is_not_read_from_the_trailer() {
+        let data = image(100_000);
+        let file = gzip(&data);
+        assert_eq!(isize_trailer(&file), 100_000);
+        assert_eq!(probe(&file), (Compression::Gzip, None));
+        assert_eq!(open(&file).min_size(), file.len() as u64);
+        assert_eq!(decompress(&file), data);
+
+        // Each member of a concatenated file has its own trailer; the last
+        // one only covers its own 5000 bytes.
+        let mut file = gzip(&image(70_000));
+        file.extend(gzip(&image(5_000)));
+        assert_eq!(isize_trailer(&file), 5_000);
+        assert_eq!(probe(&file), (Compression::Gzip, None));
+        assert_eq!(decompress(&file).len(), 75_000);
+    }
+
+    #[test]
+    fn gzip_over_4_gib() {
+        let matches = (1u64 << 32) / 258 + 1_000;
+        let len = 1 + 258 * matches;
+        let file = gzip_zeros(matches);
+        // The trailer says 256 KiB or so, for an image of over 4 GiB.
+        assert_eq!(isize_trailer(&file) as u64, len - (1 << 32));
+        let source = open(&file);
+        assert_eq!((source.compression, source.size), (Compression::Gzip, None));
+        assert_eq!(source.min_size(), file.len() as u64);
+
+        // The same construction, small enough to check that it decodes.
+        let file = gzip_zeros(10);
+        let mut zeros = Vec::new();
+        flate2::read::DeflateDecoder::new(&file[10..])
+            .read_to_end(&mut zeros)
+            .unwrap();
+        assert_eq!(zeros, vec![0; 1 + 258 * 10]);
+    }
+
+    #[test]
+    fn zip_with_sizes_in_the_local_header() {
+        let data = image(120_000);
+        let file = zip(&[("os.img", &data)]);
+        // Not stored with a data descriptor.
+        assert_eq!(file[6] & 0x08, 0);
+        assert_eq!(probe(&file), (Compression::Zip, Some(120_000)));
+        assert_eq!(decompress(&file), data);
+    }
+
+    #[test]
+    fn zip_with_a_data_descriptor() {
+        let data = image(120_000);
+        let file = zip_streamed("os.img", &data);
+        assert_eq!(file[6] & 0x08, 0x08);
+        assert_eq!(probe(&file), (Compression::Zip, Some(120_000)));
+        assert_eq!(decompress(&file), data);
+    }
+
+    #[test]
+    fn zip_image_entry_is_picked_among_other_files() {
+        let data = image(10_000);
+        let file = zip(&[
+            ("README.txt", b"Flash me"),
+            ("__MACOSX/._os.img", b"resource fork"),
+            ("images/.os.img.swp", b"swap"),
+            ("images/os.img", &data),
+        ]);
+        assert_eq!(probe(&file), (Compression::Zip, Some(10_000)));
+        assert_eq!(decompress(&file), data);
+
+        let file = zip(&[("firmware.bin", &data)]);
+        assert_eq!(decompress(&file), data);
+
+        for entries in [
+            &[("a.img", &data[..]), ("b.img", &data[..])][..],
+            &[("README.txt", b"Flash me"), ("firmware.bin", &data)],
+        ] {
+            let error = try_open(&zip(entries)).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
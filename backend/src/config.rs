@@ -0,0 +1,779 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{credentials, AppError};
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ImageConfig {
+    pub hostname: String,
+    pub change_username: bool,
+    pub new_username: Option<String>,
+    pub set_root_password: bool,
+    pub root_password: Option<SecretValue>,
+    pub enable_ssh: bool,
+    pub wifi_ssid: Option<String>,
+    pub wifi_password: Option<SecretValue>,
+    /// SSID the sealed `wifi_password` was derived for. Set when sealing, so
+    /// that a key is never reused for a network it does not fit.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wifi_psk_ssid: Option<String>,
+    pub board_type: BoardType,
+    pub mode: BuildMode,
+    pub expand_image: bool,
+    pub extra_size: Option<String>,
+    pub base_image_url: Option<String>,
+    pub preset_image: Option<PresetImage>,
+    pub docker_compose_content: Option<String>,
+    pub custom_script_content: Option<String>,
+    pub inline_command: Option<String>,
+}
+
+/// A password field: either the value itself or `{"secret": "<name>"}`,
+/// a reference into the secret store that is only resolved when the build
+/// runs.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(untagged)]
+pub enum SecretValue {
+    Stored { secret: String },
+    Plain(String),
+}
+
+impl SecretValue {
+    /// The value itself; fails for references that were not resolved.
+    pub fn plain(&self) -> Result<&str, AppError> {
+        match self {
+            SecretValue::Plain(value) => Ok(value),
+            SecretValue::Stored { secret } => Err(AppError::Internal(format!(
+                "Secret {} was not resolved",
+                secret
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum BoardType {
+    RaspberryPi,
+    Jetson,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum BuildMode {
+    Flash,
+    Artifact,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub enum PresetImage {
+    RaspberryPiLite,
+    RadxaDesktop,
+    RadxaServer,
+}
+
+/// A single rejected field, reported to clients as part of a 400 response.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        FieldError {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl ImageConfig {
+    /// Checks every field before a build is queued and reports all problems
+    /// at once.
+    pub fn validate(&self) -> Result<(), AppError> {
+        let mut errors = Vec::new();
+
+        if let Err(message) = validate_hostname(&self.hostname) {
+            errors.push(FieldError::new("hostname", message));
+        }
+
+        match &self.new_username {
+            Some(name) => {
+                if let Err(message) = validate_username(name) {
+                    errors.push(FieldError::new("new_username", message));
+                }
+            }
+            None if self.change_username => errors.push(FieldError::new(
+                "new_username",
+                "required when change_username is set",
+            )),
+            None => {}
+        }
+
+        match &self.root_password {
+            Some(SecretValue::Plain(pw)) if pw.is_empty() => {
+                errors.push(FieldError::new("root_password", "must not be empty"))
+            }
+            Some(SecretValue::Plain(pw)) if pw.contains(['\n', '\r', '\0']) => {
+                errors.push(FieldError::new(
+                    "root_password",
+                    "must not contain line breaks or NUL bytes",
+                ))
+            }
+            Some(_) => {}
+            None if self.set_root_password => errors.push(FieldError::new(
+                "root_password",
+                "required when set_root_password is set",
+            )),
+            None => {}
+        }
+
+        match (&self.wifi_ssid, &self.wifi_password) {
+            (Some(ssid), _) if ssid.is_empty() || ssid.len() > 32 => {
+                errors.push(FieldError::new("wifi_ssid", "must be 1 to 32 bytes long"))
+            }
+            (Some(ssid), _) if ssid.chars().any(char::is_control) => errors.push(FieldError::new(
+                "wifi_ssid",
+                "must not contain control characters",
+            )),
+            (None, Some(_)) => errors.push(FieldError::new(
+                "wifi_ssid",
+                "required when wifi_password is set",
+            )),
+            _ => {}
+        }
+        if let Some(SecretValue::Plain(pass)) = &self.wifi_password {
+            if let Err(message) = validate_wifi_password(pass) {
+                errors.push(FieldError::new("wifi_password", message));
+            }
+            if let Some(ssid) = &self.wifi_ssid {
+                if let Err(e) = check_psk_ssid(ssid, self.wifi_psk_ssid.as_deref(), pass) {
+                    errors.push(e);
+                }
+            }
+        }
+        for (field, value) in [
+            ("root_password", &self.root_password),
+            ("wifi_password", &self.wifi_password),
+        ] {
+            match value {
+                Some(SecretValue::Stored { secret }) if !is_valid_name(secret) => {
+                    errors.push(FieldError::new(field, "invalid secret name"))
+                }
+                // A masked value from a response whose original was not kept.
+                Some(SecretValue::Plain(value)) if value == credentials::MASK => {
+                    errors.push(FieldError::new(field, "is masked; enter the value again"))
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(size) = &self.extra_size {
+            if let Err(message) = validate_extra_size(size) {
+                errors.push(FieldError::new("extra_size", message));
+            }
+        }
+
+        match (&self.preset_image, &self.base_image_url) {
+            (Some(_), Some(_)) => errors.push(FieldError::new(
+                "base_image_url",
+                "cannot be combined with preset_image",
+            )),
+            (None, Some(url)) => {
+                if let Err(message) = validate_image_source(url) {
+                    errors.push(FieldError::new("base_image_url", message));
+                }
+            }
+            (None, None) => errors.push(FieldError::new(
+                "preset_image",
+                "either preset_image or base_image_url is required",
+            )),
+            (Some(_), None) => {}
+        }
+
+        if self.custom_script_content.is_some() && self.inline_command.is_some() {
+            errors.push(FieldError::new(
+                "inline_command",
+                "cannot be combined with custom_script_content",
+            ));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(AppError::Validation(
+                "Invalid configuration".to_string(),
+                errors,
+            ))
+        }
+    }
+
+    /// Replaces the root password with its SHA-512 crypt hash and the Wi-Fi
+    /// passphrase with the derived PSK, recording the SSID it was derived
+    /// for, so that no plaintext is persisted or handed to `imgforge.sh`.
+    /// Secret store references are left for the build to resolve. Returns
+    /// every secret value seen, plaintext and derived, for scrubbing from
+    /// logs. Expects a config that passed [`validate`](Self::validate).
+    pub fn seal(&mut self) -> Result<Vec<String>, AppError> {
+        let mut secrets = Vec::new();
+        if let Some(SecretValue::Plain(password)) = &self.root_password {
+            let hash = credentials::hash_root_password(password)?;
+            secrets.push(password.clone());
+            secrets.push(hash.clone());
+            self.root_password = Some(SecretValue::Plain(hash));
+        }
+        if let Some(SecretValue::Plain(passphrase)) = &self.wifi_password {
+            let ssid = self.wifi_ssid.as_deref().unwrap_or_default();
+            let psk = credentials::wpa_psk(ssid, passphrase);
+            secrets.push(passphrase.clone());
+            secrets.push(psk.clone());
+            self.wifi_password = Some(SecretValue::Plain(psk));
+            self.wifi_psk_ssid = self.wifi_ssid.clone();
+        } else {
+            self.wifi_psk_ssid = None;
+        }
+        secrets.dedup();
+        Ok(secrets)
+    }
+
+    /// Copy safe to return from the API, with secrets masked. References
+    /// into the secret store are only names and stay visible.
+    pub fn redacted(&self) -> Self {
+        let mask = |value: &Option<SecretValue>| match value {
+            Some(SecretValue::Plain(_)) => Some(SecretValue::Plain(credentials::MASK.to_string())),
+            other => other.clone(),
+        };
+        ImageConfig {
+            root_password: mask(&self.root_password),
+            wifi_password: mask(&self.wifi_password),
+            ..self.clone()
+        }
+    }
+
+    /// Returns a copy with `overrides` applied as a JSON merge patch
+    /// (RFC 7386): objects merge recursively, `null` resets a field.
+    pub fn with_overrides(&self, overrides: &serde_json::Value) -> Result<Self, AppError> {
+        let mut value = serde_json::to_value(self)
+            .map_err(|e| AppError::Internal(format!("Failed to serialize config: {}", e)))?;
+        merge_patch(&mut value, overrides);
+        serde_json::from_value(value)
+            .map_err(|e| AppError::BadRequest(format!("Invalid overrides: {}", e)))
+    }
+}
+
+/// Applies an RFC 7386 JSON merge patch to `target` in place.
+pub fn merge_patch(target: &mut serde_json::Value, patch: &serde_json::Value) {
+    let serde_json::Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = serde_json::Value::Object(serde_json::Map::new());
+    }
+    let target = target
+        .as_object_mut()
+        .expect("target was just made an object");
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            merge_patch(
+                target.entry(key.clone()).or_insert(serde_json::Value::Null),
+                value,
+            );
+        }
+    }
+}
+
+/// Merges one layer of a composed recipe or profile into `target`.
+///
+/// Objects merge key by key, lists are concatenated (base entries first,
+/// skipping entries already present), `null` removes a field and any other
+/// value replaces what the earlier layers set.
+pub fn merge_layer(target: &mut serde_json::Value, layer: &serde_json::Value) {
+    match (target, layer) {
+        (serde_json::Value::Object(target), serde_json::Value::Object(layer)) => {
+            for (key, value) in layer {
+                if value.is_null() {
+                    target.remove(key);
+                } else if let Some(existing) = target.get_mut(key) {
+                    merge_layer(existing, value);
+                } else {
+                    target.insert(key.clone(), value.clone());
+                }
+            }
+        }
+        (serde_json::Value::Array(target), serde_json::Value::Array(layer)) => {
+            for value in layer {
+                if !target.contains(value) {
+                    target.push(value.clone());
+                }
+            }
+        }
+        (target, layer) => *target = layer.clone(),
+    }
+}
+
+/// Profile and secret names double as file names, so they are limited to
+/// 1 to 64 letters, digits, `-`, `_` and `.` and may not start with a dot.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// RFC 1123 hostname: dot-separated labels of letters, digits and inner
+/// hyphens, at most 63 characters each and 253 overall.
+fn validate_hostname(hostname: &str) -> Result<(), String> {
+    if hostname.is_empty() {
+        return Err("must not be empty".to_string());
+    }
+    if hostname.len() > 253 {
+        return Err("must be at most 253 characters".to_string());
+    }
+    for label in hostname.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err("each label must be 1 to 63 characters".to_string());
+        }
+        if !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err("may only contain letters, digits, hyphens and dots".to_string());
+        }
+        if label.starts_with('-') || label.ends_with('-') {
+            return Err("labels must not start or end with a hyphen".to_string());
+        }
+    }
+    Ok(())
+}
+
+/// POSIX-portable user name as accepted by `useradd`: lowercase letter or
+/// underscore first, then lowercase letters, digits, `_` or `-`.
+fn validate_username(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > 32 {
+        return Err("must be 1 to 32 characters".to_string());
+    }
+    let mut chars = name.chars();
+    let first = chars.next().unwrap_or_default();
+    if !(first.is_ascii_lowercase() || first == '_') {
+        return Err("must start with a lowercase letter or underscore".to_string());
+    }
+    if !chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-') {
+        return Err("may only contain lowercase letters, digits, '_' and '-'".to_string());
+    }
+    Ok(())
+}
+
+/// WPA2 passphrase (8 to 63 printable ASCII characters) or a raw 64-digit
+/// hex PSK.
+fn validate_wifi_password(pass: &str) -> Result<(), String> {
+    if pass.len() == 64 && pass.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Ok(());
+    }
+    if pass.len() < 8 || pass.len() > 63 {
+        return Err("must be 8 to 63 characters or a 64-digit hex key".to_string());
+    }
+    if !pass.chars().all(|c| c.is_ascii_graphic() || c == ' ') {
+        return Err("may only contain printable ASCII characters".to_string());
+    }
+    Ok(())
+}
+
+/// Rejects a `wifi_password` that is a PSK sealed for another network than
+/// `ssid`: the key is derived from the SSID, so only the passphrase can be
+/// carried over to a new one.
+pub fn check_psk_ssid(
+    ssid: &str,
+    psk_ssid: Option<&str>,
+    password: &str,
+) -> Result<(), FieldError> {
+    match psk_ssid {
+        Some(sealed_for) if sealed_for != ssid && credentials::is_raw_psk(password) => {
+            Err(FieldError::new(
+                "wifi_password",
+                format!(
+                    "was saved for the network '{}'; enter the passphrase again for '{}'",
+                    sealed_for, ssid
+                ),
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Size passed to `truncate -s +SIZE`, e.g. `2G`, `+4096M`.
+fn validate_extra_size(size: &str) -> Result<(), String> {
+    let rest = size.strip_prefix('+').unwrap_or(size);
+    let number = rest.strip_suffix(['K', 'M', 'G', 'T']).unwrap_or(rest);
+    if number.is_empty()
+        || !number.chars().all(|c| c.is_ascii_digit())
+        || number.chars().all(|c| c == '0')
+    {
+        return Err("must be a positive size such as 2G or +4096M".to_string());
+    }
+    Ok(())
+}
+
+/// Base images come from an http(s) URL or an absolute path on the host,
+/// such as a file uploaded through `/api/upload`.
+fn validate_image_source(source: &str) -> Result<(), String> {
+    if source.chars().any(char::is_control) {
+        return Err("must not contain control characters".to_string());
+    }
+    if let Some((scheme, rest)) = source.split_once("://") {
+        if !matches!(scheme, "http" | "https") {
+            return Err(format!(
+                "unsupported URL scheme '{}', use http or https",
+                scheme
+            ));
+        }
+        if rest.is_empty() || rest.starts_with('/') {
+            return Err("URL has no host".to_string());
+        }
+        return Ok(());
+    }
+    if !source.starts_with('/') {
+        return Err("must be an http(s) URL or an absolute path".to_string());
+    }
+    Ok(())
+}
+
+/// Quotes `value` for a POSIX shell so that `source`-ing it yields the value
+/// verbatim, whatever quotes, `$(...)` or newlines it contains.
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> ImageConfig {
+        ImageConfig {
+            hostname: "gateway".to_string(),
+            change_username: false,
+            new_username: None,
+            set_root_password: false,
+            root_password: None,
+            enable_ssh: true,
+            wifi_ssid: None,
+            wifi_password: None,
+            wifi_psk_ssid: None,
+            board_type: BoardType::RaspberryPi,
+            mode: BuildMode::Artifact,
+            expand_image: false,
+            extra_size: None,
+            base_image_url: None,
+            preset_image: Some(PresetImage::RaspberryPiLite),
+            docker_compose_content: None,
+            custom_script_content: None,
+            inline_command: None,
+        }
+    }
+
+    fn rejected_fields(config: &ImageConfig) -> Vec<String> {
+        match config.validate() {
+            Ok(()) => Vec::new(),
+            Err(AppError::Validation(_, errors)) => errors.into_iter().map(|e| e.field).collect(),
+            Err(e) => panic!("unexpected error: {}", e),
+        }
+    }
+
+    fn with_wifi(ssid: &str, password: &str) -> ImageConfig {
+        ImageConfig {
+            wifi_ssid: Some(ssid.to_string()),
+            wifi_password: Some(SecretValue::Plain(password.to_string())),
+            ..config()
+        }
+    }
+
+    #[test]
+    fn seal_records_the_ssid_of_the_psk() {
+        let mut config = with_wifi("Office", "correct horse");
+        config.seal().unwrap();
+        assert_eq!(config.wifi_psk_ssid.as_deref(), Some("Office"));
+        assert_eq!(
+            config.wifi_password,
+            Some(SecretValue::Plain(credentials::wpa_psk(
+                "Office",
+                "correct horse"
+            )))
+        );
+        assert!(rejected_fields(&config).is_empty());
+    }
+
+    #[test]
+    fn sealed_psk_is_rejected_for_another_ssid() {
+        let mut config = with_wifi("Office", "correct horse");
+        config.seal().unwrap();
+        let moved = config
+            .with_overrides(&serde_json::json!({"wifi_ssid": "Lab"}))
+            .unwrap();
+        assert_eq!(rejected_fields(&moved), ["wifi_password"]);
+
+        let mut retyped = config
+            .with_overrides(
+                &serde_json::json!({"wifi_ssid": "Lab", "wifi_password": "battery staple"}),
+            )
+            .unwrap();
+        assert!(rejected_fields(&retyped).is_empty());
+        retyped.seal().unwrap();
+        assert_eq!(retyped.wifi_psk_ssid.as_deref(), Some("Lab"));
+    }
+
+    #[test]
+    fn raw_psk_without_recorded_ssid_is_accepted() {
+        let config = with_wifi("Lab", &"ab".repeat(32));
+        assert!(rejected_fields(&config).is_empty());
+    }
+
+    #[test]
+    fn shell_quote_survives_the_shell() {
+        let values = [
+            "",
+            "plain",
+            "it's",
+            "''",
+            "$(touch /tmp/imgforge-pwned)",
+            "`id`",
+            "$HOME ${HOME} \\$HOME",
+            "line one\nline two\n",
+            "\"double\" and 'single' ; rm -rf / #",
+            "*?[a-z] ~ & | > <",
+        ];
+        for value in values {
+            let quoted = shell_quote(value);
+            // As an argument, and as the env file assignment `imgforge.sh`
+            // sources.
+            for script in [
+                format!("printf %s {}", quoted),
+                format!("VALUE={}\nprintf %s \"$VALUE\"", quoted),
+            ] {
+                let output = std::process::Command::new("bash")
+                    .arg("-c")
+                    .arg(&script)
+                    .output()
+                    .unwrap();
+                assert!(output.status.success(), "{}", script);
+                assert_eq!(
+                    String::from_utf8(output.stdout).unwrap(),
+                    value,
+                    "{}",
+                    script
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn hostnames() {
+        for ok in ["gateway", "pi-01", "a", "node.example.com", &"a".repeat(63)] {
+            assert_eq!(validate_hostname(ok), Ok(()), "{}", ok);
+        }
+        let long = vec!["a".repeat(63); 4].join(".") + ".ab";
+        for bad in [
+            "",
+            "-pi",
+            "pi-",
+            "pi_01",
+            "pi 01",
+            "a..b",
+            ".pi",
+            "pi;reboot",
+            &"a".repeat(64),
+            &long,
+        ] {
+            assert!(validate_hostname(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn usernames() {
+        for ok in ["pi", "_svc", "deploy-bot", "user01", &"a".repeat(32)] {
+            assert_eq!(validate_username(ok), Ok(()), "{}", ok);
+        }
+        for bad in [
+            "",
+            "Pi",
+            "1user",
+            "-user",
+            "user name",
+            "user$",
+            "us.er",
+            &"a".repeat(33),
+        ] {
+            assert!(validate_username(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn wifi_passwords() {
+        let psk = "0123456789abcdefABCDEF".repeat(3)[..64].to_string();
+        for ok in [
+            "12345678",
+            "correct horse battery staple",
+            &"x".repeat(63),
+            &psk,
+        ] {
+            assert_eq!(validate_wifi_password(ok), Ok(()), "{}", ok);
+        }
+        let not_hex = format!("{}g", &psk[..63]);
+        for bad in [
+            "1234567",
+            &"x".repeat(64),
+            &not_hex,
+            "tab\tseparated",
+            "caf\u{e9} au lait",
+        ] {
+            assert!(validate_wifi_password(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn wifi_ssids() {
+        let with_ssid = |ssid: &str| ImageConfig {
+            wifi_ssid: Some(ssid.to_string()),
+            ..config()
+        };
+        for ok in ["Office", "Caf\u{e9} Wi-Fi", &"s".repeat(32)] {
+            assert!(rejected_fields(&with_ssid(ok)).is_empty(), "{}", ok);
+        }
+        for bad in ["", &"s".repeat(33), "line\nbreak"] {
+            assert_eq!(rejected_fields(&with_ssid(bad)), ["wifi_ssid"], "{}", bad);
+        }
+        let orphan = ImageConfig {
+            wifi_password: Some(SecretValue::Plain("12345678".to_string())),
+            ..config()
+        };
+        assert_eq!(rejected_fields(&orphan), ["wifi_ssid"]);
+    }
+
+    #[test]
+    fn root_passwords() {
+        let with_password = |password: Option<&str>| ImageConfig {
+            set_root_password: true,
+            root_password: password.map(|p| SecretValue::Plain(p.to_string())),
+            ..config()
+        };
+        assert!(rejected_fields(&with_password(Some("hunter2 'quoted'"))).is_empty());
+        for bad in [
+            None,
+            Some(""),
+            Some("two\nlines"),
+            Some("nul\0"),
+            Some(credentials::MASK),
+        ] {
+            assert_eq!(
+                rejected_fields(&with_password(bad)),
+                ["root_password"],
+                "{:?}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn new_usernames() {
+        let with_user = |change: bool, name: Option<&str>| ImageConfig {
+            change_username: change,
+            new_username: name.map(str::to_string),
+            ..config()
+        };
+        assert!(rejected_fields(&with_user(true, Some("deploy"))).is_empty());
+        assert_eq!(rejected_fields(&with_user(true, None)), ["new_username"]);
+        assert_eq!(
+            rejected_fields(&with_user(false, Some("Root"))),
+            ["new_username"]
+        );
+    }
+
+    #[test]
+    fn extra_sizes() {
+        for ok in ["1", "2G", "+4096M", "512K", "1T"] {
+            assert_eq!(validate_extra_size(ok), Ok(()), "{}", ok);
+        }
+        for bad in [
+            "",
+            "+",
+            "G",
+            "0",
+            "+0G",
+            "-1G",
+            "2GB",
+            "1.5G",
+            "2g",
+            "1G; reboot",
+        ] {
+            assert!(validate_extra_size(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn image_sources() {
+        for ok in [
+            "https://downloads.raspberrypi.com/os.img.xz",
+            "http://mirror.local/os.img",
+            "/tmp/imgforge-uploads/custom.img",
+        ] {
+            assert_eq!(validate_image_source(ok), Ok(()), "{}", ok);
+        }
+        for bad in [
+            "ftp://mirror.local/os.img",
+            "file:///etc/shadow",
+            "https://",
+            "https:///path",
+            "relative/os.img",
+            "/tmp/os.img\nrm -rf /",
+        ] {
+            assert!(validate_image_source(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn image_source_fields() {
+        let both = ImageConfig {
+            base_image_url: Some("/tmp/os.img".to_string()),
+            ..config()
+        };
+        assert_eq!(rejected_fields(&both), ["base_image_url"]);
+        let neither = ImageConfig {
+            preset_image: None,
+            ..config()
+        };
+        assert_eq!(rejected_fields(&neither), ["preset_image"]);
+        let script_and_command = ImageConfig {
+            custom_script_content: Some("echo hi".to_string()),
+            inline_command: Some("echo hi".to_string()),
+            ..config()
+        };
+        assert_eq!(rejected_fields(&script_and_command), ["inline_command"]);
+    }
+
+    #[test]
+    fn names() {
+        for ok in ["office-wifi", "prod_v2", "a.b", "A", &"n".repeat(64)] {
+            assert!(is_valid_name(ok), "{}", ok);
+        }
+        for bad in [
+            "",
+            ".hidden",
+            "..",
+            "../etc",
+            "a/b",
+            "with space",
+            &"n".repeat(65),
+        ] {
+            assert!(!is_valid_name(bad), "{}", bad);
+        }
+        let reference = ImageConfig {
+            set_root_password: true,
+            root_password: Some(SecretValue::Stored {
+                secret: "../master".to_string(),
+            }),
+            ..config()
+        };
+        assert_eq!(rejected_fields(&reference), ["root_password"]);
+    }
+}
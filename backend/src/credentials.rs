@@ -0,0 +1,49 @@
+use sha1::Sha1;
+
+use crate::AppError;
+
+/// Shown in place of a secret in API responses and stored records.
+pub const MASK: &str = "********";
+
+/// Turns a root password into a SHA-512 crypt hash (`$6$...`) for
+/// `chpasswd -e`. Values that already are such a hash pass through
+/// unchanged, so sealing a config twice is harmless.
+pub fn hash_root_password(password: &str) -> Result<String, AppError> {
+    if is_sha512_crypt(password) {
+        return Ok(password.to_string());
+    }
+    pwhash::sha512_crypt::hash(password)
+        .map_err(|e| AppError::Internal(format!("Failed to hash root password: {}", e)))
+}
+
+fn is_sha512_crypt(value: &str) -> bool {
+    value.starts_with("$6$") && value.matches('$').count() >= 3
+}
+
+/// Derives the raw 256-bit WPA PSK (PBKDF2-HMAC-SHA1, 4096 rounds, SSID as
+/// salt) as the 64 hex digits wpa_supplicant and NetworkManager accept in
+/// place of the passphrase. A value that already is a raw PSK passes through.
+pub fn wpa_psk(ssid: &str, passphrase: &str) -> String {
+    if is_raw_psk(passphrase) {
+        return passphrase.to_ascii_lowercase();
+    }
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha1>(passphrase.as_bytes(), ssid.as_bytes(), 4096, &mut key);
+    hex::encode(key)
+}
+
+pub fn is_raw_psk(value: &str) -> bool {
+    value.len() == 64 && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Replaces every occurrence of `secrets` in `line` with [`MASK`]. Very short
+/// values are skipped; masking them would mangle unrelated output.
+pub fn scrub(line: &str, secrets: &[String]) -> String {
+    let mut line = line.to_string();
+    for secret in secrets {
+        if secret.len() >= 4 && line.contains(secret.as_str()) {
+            line = line.replace(secret.as_str(), MASK);
+        }
+    }
+    line
+}
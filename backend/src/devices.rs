@@ -0,0 +1,757 @@
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    cmp::Reverse,
+    collections::HashSet,
+    ffi::CString,
+    fs::{self, File},
+    io,
+    os::{
+        fd::AsRawFd,
+        unix::fs::{FileExt, MetadataExt},
+    },
+    path::{Path, PathBuf},
+};
+use utoipa::ToSchema;
+
+const SYS_BLOCK: &str = "/sys/block";
+
+/// `BLKRRPART` from `<linux/fs.h>`: re-reads a disk's partition table.
+const BLKRRPART: libc::Ioctl = 0x125f;
+
+/// udev's database of device properties, where it keeps filesystem types
+/// and labels. Missing in most containers.
+const UDEV_DATA: &str = "/run/udev/data";
+
+/// Mount points under these directories hold user data (automounted cards);
+/// any other mount is treated as part of the running system.
+const USER_MOUNT_DIRS: [&str; 2] = ["/media/", "/run/media/"];
+
+/// Manual mounts. Servers keep fixed data disks here too, so only mounts of
+/// removable disks count as user data.
+const MANUAL_MOUNT_DIR: &str = "/mnt/";
+
+/// A disk that can be flashed, as listed by `GET /api/devices`.
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct Device {
+    pub path: String,
+    /// Size for display, e.g. `29.7G`.
+    pub size: String,
+    /// Identifies the medium; pass it to `POST /api/flash`.
+    pub fingerprint: String,
+    #[serde(flatten)]
+    pub disk: BlockDevice,
+}
+
+impl From<BlockDevice> for Device {
+    fn from(disk: BlockDevice) -> Self {
+        Device {
+            path: format!("/dev/{}", disk.name),
+            size: human_size(disk.size_bytes),
+            fingerprint: disk.fingerprint(),
+            disk,
+        }
+    }
+}
+
+/// A whole disk as described by `/sys/block/<name>`.
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct BlockDevice {
+    pub name: String,
+    pub size_bytes: u64,
+    pub removable: bool,
+    pub read_only: bool,
+    pub vendor: Option<String>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    /// `usb`, `mmc`, `nvme`, `sata`, ... when it can be told.
+    pub transport: Option<String>,
+    pub partitions: Vec<Partition>,
+}
+
+/// A partition of a [`BlockDevice`], with what is known of its filesystem.
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct Partition {
+    pub name: String,
+    pub path: String,
+    pub size_bytes: u64,
+    pub fs_type: Option<String>,
+    pub label: Option<String>,
+    /// Where the partition is mounted; empty if it is not.
+    pub mountpoints: Vec<String>,
+}
+
+impl BlockDevice {
+    /// Reads a whole disk's attributes. Fails for partitions and names that
+    /// are not block devices.
+    pub fn probe(name: &str) -> io::Result<Self> {
+        Self::probe_with(name, &mounts()?)
+    }
+
+    fn probe_with(name: &str, mounts: &[Mount]) -> io::Result<Self> {
+        Self::probe_in(Path::new(SYS_BLOCK), name, mounts)
+    }
+
+    /// Like [`probe`](Self::probe), with `sys_block` in place of
+    /// `/sys/block`.
+    pub fn probe_in(sys_block: &Path, name: &str, mounts: &[Mount]) -> io::Result<Self> {
+        let dir = sys_block.join(name);
+        if name.is_empty() || name.contains('/') || !dir.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} is not a whole disk", name),
+            ));
+        }
+        let transport = transport(name, &dir);
+
+        Ok(BlockDevice {
+            name: name.to_string(),
+            size_bytes: size_bytes(&dir),
+            removable: removable(&dir, transport.as_deref()),
+            read_only: read_attr(&dir.join("ro")).as_deref() == Some("1"),
+            vendor: read_attr(&dir.join("device/vendor"))
+                .or_else(|| parent_attr(&dir, "manufacturer")),
+            model: read_attr(&dir.join("device/model"))
+                .or_else(|| read_attr(&dir.join("device/name"))),
+            serial: read_attr(&dir.join("device/serial")).or_else(|| parent_attr(&dir, "serial")),
+            transport,
+            partitions: partitions(name, &dir, mounts),
+        })
+    }
+
+    /// Short token identifying the physical medium: changes when a different
+    /// card ends up behind the same device node.
+    pub fn fingerprint(&self) -> String {
+        let mut hasher = Sha256::new();
+        for part in [
+            self.name.as_str(),
+            &self.size_bytes.to_string(),
+            self.serial.as_deref().unwrap_or_default(),
+            self.model.as_deref().unwrap_or_default(),
+        ] {
+            hasher.update(part.as_bytes());
+            hasher.update([0]);
+        }
+        hex::encode(&hasher.finalize()[..8])
+    }
+}
+
+/// Removable disks with a medium in them, leaving out optical drives and
+/// any disk that holds the running system.
+pub fn scan() -> io::Result<Vec<Device>> {
+    let system = system_disks()?;
+    let mounts = mounts()?;
+
+    let mut devices = Vec::new();
+    for entry in fs::read_dir(SYS_BLOCK)? {
+        let name = entry?.file_name().to_string_lossy().to_string();
+        let Ok(disk) = BlockDevice::probe_with(&name, &mounts) else {
+            continue;
+        };
+        // Card readers without a card report a size of zero.
+        let optical = read_attr(&Path::new(SYS_BLOCK).join(&name).join("device/type")).as_deref()
+            == Some("5");
+        if disk.removable && disk.size_bytes > 0 && !optical && !system.contains(&disk.name) {
+            devices.push(Device::from(disk));
+        }
+    }
+    devices.sort_by(|a, b| a.disk.name.cmp(&b.disk.name));
+    Ok(devices)
+}
+
+/// A cheap summary of what [`scan`] looks at: the disks with their sizes
+/// and partitions, the mount table and swap. Reads nothing from the disks
+/// themselves, so it can be polled; a relabelled filesystem goes unnoticed.
+pub fn layout() -> io::Result<String> {
+    let mut disks = Vec::new();
+    for entry in fs::read_dir(SYS_BLOCK)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let dir = entry.path();
+        let mut parts: Vec<String> = fs::read_dir(&dir)
+            .map(|entries| {
+                entries
+                    .flatten()
+                    .map(|e| e.file_name().to_string_lossy().to_string())
+                    .filter(|part| part.starts_with(&name))
+                    .map(|part| format!("{}:{}", part, size_bytes(&dir.join(&part))))
+                    .collect()
+            })
+            .unwrap_or_default();
+        parts.sort();
+        let ro = read_attr(&dir.join("ro")).unwrap_or_default();
+        disks.push(format!(
+            "{}:{}:{} {}",
+            name,
+            size_bytes(&dir),
+            ro,
+            parts.join(" ")
+        ));
+    }
+    disks.sort();
+
+    Ok(format!(
+        "{}\n{}\n{}",
+        disks.join("\n"),
+        fs::read_to_string("/proc/mounts")?,
+        fs::read_to_string("/proc/swaps").unwrap_or_default()
+    ))
+}
+
+/// Unmounts every filesystem on the disk `name`, nested mounts first, and
+/// returns the mount points. Covers partitions, the disk itself and
+/// device-mapper devices on top of it, e.g. an unlocked LUKS partition.
+pub fn unmount_all(name: &str) -> io::Result<Vec<String>> {
+    let mut targets: Vec<String> = mounts()?
+        .into_iter()
+        .filter(|mount| {
+            block_name(&mount.source)
+                .is_some_and(|source| backing_disks(&source).iter().any(|d| d == name))
+        })
+        .map(|mount| mount.target)
+        .collect();
+    targets.sort();
+    targets.dedup();
+    targets.sort_by_key(|target| Reverse(target.len()));
+
+    for target in &targets {
+        let path = CString::new(target.as_str())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        // SAFETY: umount2(2) reads the NUL-terminated path, which outlives
+        // the call.
+        if unsafe { libc::umount2(path.as_ptr(), 0) } != 0 {
+            let e = io::Error::last_os_error();
+            // Already gone, e.g. stacked mounts on the same target.
+            if e.raw_os_error() == Some(libc::EINVAL) {
+                continue;
+            }
+            return Err(io::Error::new(
+                e.kind(),
+                format!("cannot unmount {}: {}", target, e),
+            ));
+        }
+    }
+    Ok(targets)
+}
+
+/// Makes the kernel read the partition table of `device` again, so the
+/// partitions of a freshly written image show up.
+pub fn reread_partitions(device: &str) -> io::Result<()> {
+    let file = File::open(device)?;
+    // SAFETY: the ioctl takes a descriptor we own and no argument.
+    if unsafe { libc::ioctl(file.as_raw_fd(), BLKRRPART, 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Detaches the USB disk `name` so it can be pulled out: the kernel flushes
+/// and drops the disk, then disconnects the USB device, which powers it off
+/// on most hubs. A USB device that still carries other disks, such as the
+/// other slots of a card reader, stays connected. Returns whether it was
+/// disconnected.
+pub fn power_off(name: &str) -> io::Result<bool> {
+    let not_usb = || io::Error::new(io::ErrorKind::Unsupported, "not a USB device");
+    // Virtual disks such as loop devices have no device behind them.
+    let device =
+        fs::canonicalize(Path::new(SYS_BLOCK).join(name).join("device")).map_err(|_| not_usb())?;
+    let usb = device
+        .ancestors()
+        .take_while(|dir| dir.starts_with("/sys/devices"))
+        .find(|dir| dir.join("idVendor").exists() && dir.join("remove").exists())
+        .map(Path::to_path_buf)
+        .ok_or_else(not_usb)?;
+
+    // SCSI disks, which is what USB storage shows up as.
+    let delete = device.join("delete");
+    if delete.exists() {
+        fs::write(delete, "1")?;
+    }
+
+    for entry in fs::read_dir(SYS_BLOCK)? {
+        let other = fs::canonicalize(entry?.path()).unwrap_or_default();
+        if other.starts_with(&usb) {
+            return Ok(false);
+        }
+    }
+    fs::write(usb.join("remove"), "1")?;
+    Ok(true)
+}
+
+/// Names of the whole disks that hold the root filesystem, swap, or any
+/// mount that is not user data, see [`user_mount`].
+pub fn system_disks() -> io::Result<HashSet<String>> {
+    let mut disks = HashSet::new();
+
+    if let Some(name) = block_name_of_dev(fs::metadata("/")?.dev()) {
+        disks.extend(backing_disks(&name));
+    }
+
+    for mount in mounts()? {
+        let Some(name) = block_name(&mount.source) else {
+            continue;
+        };
+        let backing = backing_disks(&name);
+        if !user_mount(Path::new(SYS_BLOCK), &mount.target, &backing) {
+            disks.extend(backing);
+        }
+    }
+
+    let swaps = fs::read_to_string("/proc/swaps").unwrap_or_default();
+    for line in swaps.lines().skip(1) {
+        if let Some(name) = line.split_whitespace().next().and_then(block_name) {
+            disks.extend(backing_disks(&name));
+        }
+    }
+    Ok(disks)
+}
+
+/// Whether a mount at `target` on `disks` holds user data rather than part
+/// of the running system.
+fn user_mount(sys_block: &Path, target: &str, disks: &[String]) -> bool {
+    if USER_MOUNT_DIRS.iter().any(|dir| target.starts_with(dir)) {
+        return true;
+    }
+    target.starts_with(MANUAL_MOUNT_DIR)
+        && !disks.is_empty()
+        && disks.iter().all(|disk| {
+            let dir = sys_block.join(disk);
+            removable(&dir, transport(disk, &dir).as_deref())
+        })
+}
+
+/// One line of `/proc/mounts`.
+#[derive(Debug, Clone)]
+pub struct Mount {
+    pub source: String,
+    pub target: String,
+    pub fs_type: String,
+}
+
+pub fn mounts() -> io::Result<Vec<Mount>> {
+    let text = fs::read_to_string("/proc/mounts")?;
+    Ok(text
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            Some(Mount {
+                source: unescape_mount_field(fields.next()?),
+                target: unescape_mount_field(fields.next()?),
+                fs_type: fields.next()?.to_string(),
+            })
+        })
+        .collect())
+}
+
+/// The block device name behind a `/dev` path, following symlinks such as
+/// `/dev/disk/by-id/...` or `/dev/mapper/...`.
+pub fn block_name(path: &str) -> Option<String> {
+    if !path.starts_with("/dev/") {
+        return None;
+    }
+    let canonical = fs::canonicalize(path).ok()?;
+    let name = canonical.strip_prefix("/dev").ok()?.to_str()?.to_string();
+    Path::new("/sys/class/block")
+        .join(&name)
+        .exists()
+        .then_some(name)
+}
+
+/// Whole disks underneath a block device: a partition's parent, the
+/// members of a device-mapper or md device, or the disk itself.
+pub fn backing_disks(name: &str) -> Vec<String> {
+    let mut disks = Vec::new();
+    collect_backing_disks(name, &mut disks, 0);
+    disks
+}
+
+fn collect_backing_disks(name: &str, disks: &mut Vec<String>, depth: usize) {
+    let Ok(path) = fs::canonicalize(Path::new("/sys/class/block").join(name)) else {
+        return;
+    };
+    if depth > 8 {
+        return;
+    }
+    if path.join("partition").exists() {
+        if let Some(parent) = path.parent().and_then(|p| p.file_name()) {
+            collect_backing_disks(&parent.to_string_lossy(), disks, depth + 1);
+        }
+        return;
+    }
+    let slaves: Vec<String> = fs::read_dir(path.join("slaves"))
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|e| e.file_name().to_string_lossy().to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+    if slaves.is_empty() {
+        if !disks.iter().any(|d| d == name) {
+            disks.push(name.to_string());
+        }
+    } else {
+        for slave in slaves {
+            collect_backing_disks(&slave, disks, depth + 1);
+        }
+    }
+}
+
+fn block_name_of_dev(dev: u64) -> Option<String> {
+    let (major, minor) = (libc::major(dev), libc::minor(dev));
+    let path = fs::canonicalize(format!("/sys/dev/block/{}:{}", major, minor)).ok()?;
+    Some(path.file_name()?.to_string_lossy().to_string())
+}
+
+/// `usb`, `mmc`, `nvme`, ... when it can be told from the sysfs path.
+fn transport(name: &str, dir: &Path) -> Option<String> {
+    let path = fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf());
+    let path = path.to_string_lossy();
+    let transport = if path.contains("/usb") {
+        "usb"
+    } else if name.starts_with("mmcblk") || path.contains("/mmc_host/") {
+        "mmc"
+    } else if name.starts_with("nvme") {
+        "nvme"
+    } else if path.contains("/ata") {
+        "sata"
+    } else if name.starts_with("vd") || path.contains("/virtio") {
+        "virtio"
+    } else if name.starts_with("loop") {
+        "loop"
+    } else {
+        return None;
+    };
+    Some(transport.to_string())
+}
+
+fn removable(dir: &Path, transport: Option<&str>) -> bool {
+    read_attr(&dir.join("removable")).as_deref() == Some("1")
+        || matches!(transport, Some("usb" | "mmc"))
+}
+
+/// An attribute of the nearest parent device that has it, e.g. the USB
+/// serial number or manufacturer of a card reader.
+fn parent_attr(dir: &Path, attr: &str) -> Option<String> {
+    let mut device: PathBuf = fs::canonicalize(dir.join("device")).ok()?;
+    for _ in 0..6 {
+        if !device.pop() || !device.starts_with("/sys/devices") {
+            break;
+        }
+        if let Some(value) = read_attr(&device.join(attr)) {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn partitions(disk: &str, dir: &Path, mounts: &[Mount]) -> Vec<Partition> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut partitions: Vec<(u32, Partition)> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let number = read_attr(&entry.path().join("partition"))?.parse().ok()?;
+            if !name.starts_with(disk) {
+                return None;
+            }
+            Some((number, partition(&name, &entry.path(), mounts)))
+        })
+        .collect();
+    partitions.sort_by_key(|(number, _)| *number);
+    partitions.into_iter().map(|(_, p)| p).collect()
+}
+
+fn partition(name: &str, dir: &Path, mounts: &[Mount]) -> Partition {
+    let path = format!("/dev/{}", name);
+    let mounted: Vec<&Mount> = mounts
+        .iter()
+        .filter(|m| block_name(&m.source).as_deref() == Some(name))
+        .collect();
+
+    let (mut fs_type, mut label) = read_attr(&dir.join("dev"))
+        .map(|dev| udev_filesystem(&dev))
+        .unwrap_or_default();
+    if fs_type.is_none() {
+        if let Some(sniffed) = sniff_filesystem(Path::new(&path)) {
+            fs_type = Some(sniffed.0.to_string());
+            label = label.or(sniffed.1);
+        }
+    }
+
+    Partition {
+        fs_type: fs_type.or_else(|| mounted.first().map(|m| m.fs_type.clone())),
+        label,
+        mountpoints: mounted.iter().map(|m| m.target.clone()).collect(),
+        size_bytes: size_bytes(dir),
+        name: name.to_string(),
+        path,
+    }
+}
+
+/// Filesystem type and label as recorded by udev for the device numbered
+/// `dev` (`major:minor`).
+fn udev_filesystem(dev: &str) -> (Option<String>, Option<String>) {
+    let data =
+        fs::read_to_string(Path::new(UDEV_DATA).join(format!("b{}", dev))).unwrap_or_default();
+    let property = |key: &str| {
+        data.lines()
+            .find_map(|line| {
+                line.strip_prefix("E:")?
+                    .strip_prefix(key)?
+                    .strip_prefix('=')
+            })
+            .filter(|value| !value.is_empty())
+            .map(str::to_string)
+    };
+    (property("ID_FS_TYPE"), property("ID_FS_LABEL"))
+}
+
+/// Recognizes the filesystems found on single-board computer images from
+/// their superblocks, for hosts without udev. Returns the type and label.
+fn sniff_filesystem(path: &Path) -> Option<(&'static str, Option<String>)> {
+    let file = File::open(path).ok()?;
+    let mut head = vec![0u8; 4096];
+    file.read_exact_at(&mut head, 0).ok()?;
+    let text = |range: std::ops::Range<usize>| {
+        let value = String::from_utf8_lossy(&head[range]);
+        let value = value.trim_end_matches(['\0', ' ']);
+        (!value.is_empty()).then(|| value.to_string())
+    };
+
+    // ext2/3/4: superblock at 1024, magic 0xEF53.
+    if head[1080..1082] == [0x53, 0xef] {
+        let compat = u32::from_le_bytes(head[1116..1120].try_into().ok()?);
+        let incompat = u32::from_le_bytes(head[1120..1124].try_into().ok()?);
+        let kind = if incompat & 0x40 != 0 {
+            "ext4"
+        } else if compat & 0x4 != 0 {
+            "ext3"
+        } else {
+            "ext2"
+        };
+        return Some((kind, text(1144..1160)));
+    }
+    if head[4086..4096] == *b"SWAPSPACE2" {
+        return Some(("swap", text(1052..1068)));
+    }
+    if head[3..11] == *b"EXFAT   " {
+        return Some(("exfat", None));
+    }
+    if head[510..512] == [0x55, 0xaa] {
+        // FAT32 and FAT12/16 keep the label at different offsets.
+        let label = if head[82..87] == *b"FAT32" {
+            text(71..82)
+        } else if head[54..57] == *b"FAT" {
+            text(43..54)
+        } else {
+            return None;
+        };
+        return Some(("vfat", label.filter(|l| l != "NO NAME")));
+    }
+    None
+}
+
+/// The kernel counts in 512-byte sectors regardless of the device's logical
+/// block size.
+fn size_bytes(dir: &Path) -> u64 {
+    read_attr(&dir.join("size"))
+        .and_then(|s| s.parse::<u64>().ok())
+        .unwrap_or(0)
+        * 512
+}
+
+/// Sizes as `lsblk` prints them: `512M`, `29.7G`.
+fn human_size(bytes: u64) -> String {
+    let units = ["B", "K", "M", "G", "T", "P", "E"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    let formatted = format!("{:.1}", value);
+    format!(
+        "{}{}",
+        formatted.strip_suffix(".0").unwrap_or(&formatted),
+        units[unit]
+    )
+}
+
+fn read_attr(path: &Path) -> Option<String> {
+    let value = fs::read_to_string(path).ok()?;
+    let value = value.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// `/proc/mounts` writes spaces, tabs, newlines and backslashes in paths as
+/// octal escapes (`\040`).
+fn unescape_mount_field(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' {
+            let digits = field.get(i + 1..i + 4).unwrap_or_default();
+            if let Ok(byte) = u8::from_str_radix(digits, 8) {
+                out.push(byte);
+                i += 4;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_removable_disks_mounted_under_mnt_are_user_data() {
+        let sys_block =
+            std::env::temp_dir().join(format!("imgforge-mounts-{}", uuid::Uuid::new_v4()));
+        for (disk, removable) in [("sdx", "1"), ("sdy", "0"), ("mmcblk0", "0")] {
+            fs::create_dir_all(sys_block.join(disk)).unwrap();
+            fs::write(sys_block.join(disk).join("removable"), removable).unwrap();
+        }
+        let disks = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+
+        for (target, on, user) in [
+            ("/media/pi/boot", &["sdy"][..], true),
+            ("/run/media/pi/rootfs", &["sdy"], true),
+            ("/mnt/card", &["sdx"], true),
+            ("/mnt/sd", &["mmcblk0"], true),
+            ("/mnt/data", &["sdy"], false),
+            ("/mnt/raid", &["sdx", "sdy"], false),
+            ("/mnt/nfs", &[], false),
+            ("/mnt", &["sdx"], false),
+            ("/srv/card", &["sdx"], false),
+            ("/", &["sdy"], false),
+        ] {
+            assert_eq!(
+                user_mount(&sys_block, target, &disks(on)),
+                user,
+                "{} on {:?}",
+                target,
+                on
+            );
+        }
+        fs::remove_dir_all(&sys_block).unwrap();
+    }
+
+    #[test]
+    fn mount_fields_are_unescaped() {
+        for (field, path) in [
+            ("/media/pi/boot", "/media/pi/boot"),
+            (r"/media/pi/SD\040CARD", "/media/pi/SD CARD"),
+            (r"/mnt/a\011b", "/mnt/a\tb"),
+            (r"/mnt/back\134slash", r"/mnt/back\slash"),
+            (r"/mnt/new\012line", "/mnt/new\nline"),
+            (r"/mnt/x\04", r"/mnt/x\04"),
+            (r"/mnt/x\09y", r"/mnt/x\09y"),
+            (r"\040\040", "  "),
+        ] {
+            assert_eq!(unescape_mount_field(field), path, "{}", field);
+        }
+    }
+
+    #[test]
+    fn sizes_read_like_lsblk() {
+        for (bytes, size) in [
+            (0, "0B"),
+            (1023, "1023B"),
+            (1024, "1K"),
+            (1536, "1.5K"),
+            (512 << 20, "512M"),
+            (31_914_983_424, "29.7G"),
+            (2 << 40, "2T"),
+            (u64::MAX, "16E"),
+        ] {
+            assert_eq!(human_size(bytes), size, "{}", bytes);
+        }
+    }
+
+    /// A 4 KiB image with `fields` written at their offsets.
+    fn head(fields: &[(usize, &[u8])]) -> Vec<u8> {
+        let mut head = vec![0u8; 4096];
+        for (offset, bytes) in fields {
+            head[*offset..offset + bytes.len()].copy_from_slice(bytes);
+        }
+        head
+    }
+
+    #[test]
+    fn filesystems_are_recognized_by_their_superblock() {
+        const EXT_MAGIC: (usize, &[u8]) = (1080, &[0x53, 0xef]);
+        const BOOT_SIGNATURE: (usize, &[u8]) = (510, &[0x55, 0xaa]);
+        let cases = [
+            (
+                "ext4",
+                head(&[EXT_MAGIC, (1120, &[0x46, 0, 0, 0]), (1144, b"rootfs")]),
+                Some(("ext4", Some("rootfs"))),
+            ),
+            (
+                "ext3",
+                head(&[EXT_MAGIC, (1116, &[0x04, 0, 0, 0])]),
+                Some(("ext3", None)),
+            ),
+            (
+                "ext2",
+                head(&[EXT_MAGIC, (1144, b"data\0\0\0\0")]),
+                Some(("ext2", Some("data"))),
+            ),
+            (
+                "swap",
+                head(&[(1052, b"swap0"), (4086, b"SWAPSPACE2")]),
+                Some(("swap", Some("swap0"))),
+            ),
+            (
+                "exfat",
+                head(&[(3, b"EXFAT   "), BOOT_SIGNATURE]),
+                Some(("exfat", None)),
+            ),
+            (
+                "fat32",
+                head(&[(71, b"BOOT       "), (82, b"FAT32   "), BOOT_SIGNATURE]),
+                Some(("vfat", Some("BOOT"))),
+            ),
+            (
+                "fat32 without a label",
+                head(&[(71, b"NO NAME    "), (82, b"FAT32   "), BOOT_SIGNATURE]),
+                Some(("vfat", None)),
+            ),
+            (
+                "fat16",
+                head(&[(43, b"CIDATA     "), (54, b"FAT16   "), BOOT_SIGNATURE]),
+                Some(("vfat", Some("CIDATA"))),
+            ),
+            (
+                "fat12",
+                head(&[(54, b"FAT12   "), BOOT_SIGNATURE]),
+                Some(("vfat", None)),
+            ),
+            // An MBR has the boot signature but no filesystem.
+            ("mbr", head(&[BOOT_SIGNATURE]), None),
+            ("zeros", head(&[]), None),
+            ("short", vec![0x53; 2048], None),
+        ];
+
+        let dir = std::env::temp_dir().join(format!("imgforge-sniff-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        for (name, data, expected) in cases {
+            let path = dir.join(name);
+            fs::write(&path, data).unwrap();
+            let found = sniff_filesystem(&path);
+            let found = found
+                .as_ref()
+                .map(|(kind, label)| (*kind, label.as_deref()));
+            assert_eq!(found, expected, "{}", name);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,414 @@
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::jobs::DeviceStatus;
+
+/// Named stages of a build or flash job, in the order they usually run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    Download,
+    Decompress,
+    Resize,
+    Mount,
+    Customize,
+    Unmount,
+    Store,
+    Flash,
+    Verify,
+}
+
+impl Phase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Phase::Download => "download",
+            Phase::Decompress => "decompress",
+            Phase::Resize => "resize",
+            Phase::Mount => "mount",
+            Phase::Customize => "customize",
+            Phase::Unmount => "unmount",
+            Phase::Store => "store",
+            Phase::Flash => "flash",
+            Phase::Verify => "verify",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        Some(match s {
+            "download" => Phase::Download,
+            "decompress" => Phase::Decompress,
+            "resize" => Phase::Resize,
+            "mount" => Phase::Mount,
+            "customize" => Phase::Customize,
+            "unmount" => Phase::Unmount,
+            "store" => Phase::Store,
+            "flash" => Phase::Flash,
+            "verify" => Phase::Verify,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum PhaseState {
+    Started,
+    Finished,
+    Failed,
+}
+
+/// Typed job event sent over the job WebSocket as `{"type": ..., ...}`.
+///
+/// Events travel through the job log as marker lines such as
+/// `::phase-start::download` or `::progress::download 42`, which
+/// `imgforge.sh` prints itself and the backend writes for its own phases.
+/// Keeping them in the log means a client that connects late gets the same
+/// events on replay.
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum JobEvent {
+    Phase {
+        phase: Phase,
+        state: PhaseState,
+    },
+    Progress {
+        phase: Phase,
+        percent: f32,
+    },
+    /// Throughput of a phase that streams data, such as flashing. Set
+    /// `device` for one device of a multi-device flash; without it the
+    /// numbers are for reading the image.
+    Transfer {
+        phase: Phase,
+        bytes: u64,
+        total: Option<u64>,
+        bytes_per_sec: u64,
+        eta_secs: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        device: Option<String>,
+    },
+    /// A device of a flash job moved to a new status.
+    Device {
+        device: String,
+        status: DeviceStatus,
+    },
+    Warning {
+        message: String,
+    },
+}
+
+impl JobEvent {
+    pub fn started(phase: Phase) -> Self {
+        JobEvent::Phase {
+            phase,
+            state: PhaseState::Started,
+        }
+    }
+
+    pub fn finished(phase: Phase) -> Self {
+        JobEvent::Phase {
+            phase,
+            state: PhaseState::Finished,
+        }
+    }
+
+    pub fn failed(phase: Phase) -> Self {
+        JobEvent::Phase {
+            phase,
+            state: PhaseState::Failed,
+        }
+    }
+
+    /// Parses a marker line; ordinary log lines return `None`.
+    pub fn parse(line: &str) -> Option<Self> {
+        let rest = line.strip_prefix("::")?;
+        let (tag, value) = rest.split_once("::")?;
+        let value = value.trim();
+
+        match tag {
+            "phase-start" => Some(JobEvent::started(Phase::parse(value)?)),
+            "phase-end" => Some(JobEvent::finished(Phase::parse(value)?)),
+            "phase-failed" => Some(JobEvent::failed(Phase::parse(value)?)),
+            "progress" => {
+                let (phase, percent) = value.split_once(' ')?;
+                Some(JobEvent::Progress {
+                    phase: Phase::parse(phase)?,
+                    percent: percent
+                        .trim()
+                        .parse()
+                        .ok()
+                        .filter(|p: &f32| p.is_finite())?,
+                })
+            }
+            "transfer" => {
+                let mut fields = value.split_whitespace();
+                let phase = Phase::parse(fields.next()?)?;
+                // Unknown values are written as `-`; anything else must be a number.
+                let mut number = || match fields.next()? {
+                    "-" => Some(None),
+                    field => field.parse::<u64>().ok().map(Some),
+                };
+                Some(JobEvent::Transfer {
+                    phase,
+                    bytes: number()??,
+                    total: number()?,
+                    bytes_per_sec: number()??,
+                    eta_secs: number()?,
+                    device: fields.next().map(str::to_string),
+                })
+            }
+            "device" => {
+                let (status, device) = value.split_once(' ')?;
+                Some(JobEvent::Device {
+                    device: device.to_string(),
+                    status: DeviceStatus::parse(status)?,
+                })
+            }
+            "warning" => Some(JobEvent::Warning {
+                message: value.to_string(),
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn to_marker(&self) -> String {
+        match self {
+            JobEvent::Phase { phase, state } => {
+                let tag = match state {
+                    PhaseState::Started => "phase-start",
+                    PhaseState::Finished => "phase-end",
+                    PhaseState::Failed => "phase-failed",
+                };
+                format!("::{}::{}", tag, phase.as_str())
+            }
+            JobEvent::Progress { phase, percent } => {
+                format!("::progress::{} {:.1}", phase.as_str(), percent)
+            }
+            JobEvent::Transfer {
+                phase,
+                bytes,
+                total,
+                bytes_per_sec,
+                eta_secs,
+                device,
+            } => {
+                let optional = |n: &Option<u64>| n.map_or("-".to_string(), |n| n.to_string());
+                let mut marker = format!(
+                    "::transfer::{} {} {} {} {}",
+                    phase.as_str(),
+                    bytes,
+                    optional(total),
+                    bytes_per_sec,
+                    optional(eta_secs)
+                );
+                if let Some(device) = device {
+                    marker.push(' ');
+                    marker.push_str(device);
+                }
+                marker
+            }
+            JobEvent::Device { device, status } => {
+                format!("::device::{} {}", status.as_str(), device)
+            }
+            JobEvent::Warning { message } => format!("::warning::{}", message),
+        }
+    }
+}
+
+/// Follows a job's output to know which phase is running and turns tool
+/// progress output (curl's `--progress-bar`) into progress markers.
+#[derive(Debug, Default)]
+pub struct PhaseTracker {
+    current: Option<Phase>,
+    last_percent: Option<u32>,
+}
+
+impl PhaseTracker {
+    /// Returns the line to store in the job log, or `None` to drop it
+    /// (progress updates that did not move a whole percent).
+    pub fn rewrite(&mut self, line: &str) -> Option<String> {
+        if let Some(event) = JobEvent::parse(line) {
+            self.observe(&event);
+            return Some(line.to_string());
+        }
+
+        let Some(phase) = self.current else {
+            return Some(line.to_string());
+        };
+        let Some(percent) = parse_progress_bar(line) else {
+            return Some(line.to_string());
+        };
+
+        if self.last_percent == Some(percent as u32) {
+            return None;
+        }
+        self.last_percent = Some(percent as u32);
+        Some(JobEvent::Progress { phase, percent }.to_marker())
+    }
+
+    pub fn observe(&mut self, event: &JobEvent) {
+        if let JobEvent::Phase { phase, state } = event {
+            self.last_percent = None;
+            self.current = match state {
+                PhaseState::Started => Some(*phase),
+                PhaseState::Finished | PhaseState::Failed => None,
+            };
+        }
+    }
+
+    /// Phase that was started but never finished, i.e. where a failed job
+    /// stopped.
+    pub fn current(&self) -> Option<Phase> {
+        self.current
+    }
+}
+
+/// Parses curl's `--progress-bar` output, e.g. `######     42.3%`. curl can
+/// overshoot when the server under-reports the size; that counts as 100.
+fn parse_progress_bar(line: &str) -> Option<f32> {
+    let line = line.trim();
+    let percent = line.strip_suffix('%')?.rsplit(' ').next()?;
+    if !line.starts_with('#') && !line.starts_with(percent) {
+        return None;
+    }
+    let percent: f32 = percent.parse().ok()?;
+    percent.is_finite().then(|| percent.clamp(0.0, 100.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PHASES: [Phase; 9] = [
+        Phase::Download,
+        Phase::Decompress,
+        Phase::Resize,
+        Phase::Mount,
+        Phase::Customize,
+        Phase::Unmount,
+        Phase::Store,
+        Phase::Flash,
+        Phase::Verify,
+    ];
+
+    fn round_trip(event: JobEvent) {
+        let marker = event.to_marker();
+        assert_eq!(JobEvent::parse(&marker), Some(event), "{}", marker);
+    }
+
+    #[test]
+    fn markers_round_trip() {
+        for phase in PHASES {
+            assert_eq!(Phase::parse(phase.as_str()), Some(phase));
+            round_trip(JobEvent::started(phase));
+            round_trip(JobEvent::finished(phase));
+            round_trip(JobEvent::failed(phase));
+            round_trip(JobEvent::Progress {
+                phase,
+                percent: 42.5,
+            });
+        }
+        round_trip(JobEvent::Progress {
+            phase: Phase::Flash,
+            percent: 100.0,
+        });
+        for (total, eta_secs, device) in [
+            (Some(8 << 30), Some(95), Some("/dev/sdb".to_string())),
+            (None, None, None),
+        ] {
+            round_trip(JobEvent::Transfer {
+                phase: Phase::Flash,
+                bytes: 1 << 30,
+                total,
+                bytes_per_sec: 30 << 20,
+                eta_secs,
+                device,
+            });
+        }
+        for status in [
+            DeviceStatus::Pending,
+            DeviceStatus::Writing,
+            DeviceStatus::Verifying,
+            DeviceStatus::Success,
+            DeviceStatus::Failed,
+            DeviceStatus::Cancelled,
+        ] {
+            round_trip(JobEvent::Device {
+                device: "/dev/sdc".to_string(),
+                status,
+            });
+        }
+        round_trip(JobEvent::Warning {
+            message: "Failed to re-read the partition table: busy :: retry".to_string(),
+        });
+    }
+
+    #[test]
+    fn ordinary_and_malformed_lines_are_not_events() {
+        for line in [
+            "",
+            "Downloading base image...",
+            ":: phase-start :: download",
+            "::phase-start:download",
+            "x::phase-start::download",
+            "::phase-start::",
+            "::phase-start::install",
+            "::phase-done::download",
+            "::progress::download",
+            "::progress::download fast",
+            "::progress::download nan",
+            "::progress::download inf",
+            "::progress::42 download",
+            "::transfer::flash 10",
+            "::transfer::flash ten - 5 -",
+            "::transfer::flash 10 - 5 soon",
+            "::transfer::burn 10 - 5 -",
+            "::device::success",
+            "::device::done /dev/sdb",
+        ] {
+            assert_eq!(JobEvent::parse(line), None, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn progress_bars() {
+        for (line, percent) in [
+            ("######                          21.4%", Some(21.4)),
+            ("  ################################ 100.0%", Some(100.0)),
+            ("0.0%", Some(0.0)),
+            ("###### 130.2%", Some(100.0)),
+            ("-5%", Some(0.0)),
+            ("###### nan%", None),
+            ("###### inf%", None),
+            ("###### abc%", None),
+            ("######", None),
+            ("Disk usage at 42%", None),
+            ("%", None),
+        ] {
+            assert_eq!(parse_progress_bar(line), percent, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn tracker_turns_progress_bars_into_markers() {
+        let mut tracker = PhaseTracker::default();
+        // Outside a phase there is nothing to attribute progress to.
+        assert_eq!(tracker.rewrite("## 10.0%").as_deref(), Some("## 10.0%"));
+
+        let start = JobEvent::started(Phase::Download).to_marker();
+        assert_eq!(tracker.rewrite(&start), Some(start));
+        assert_eq!(
+            tracker.rewrite("## 10.0%").as_deref(),
+            Some("::progress::download 10.0")
+        );
+        assert_eq!(tracker.rewrite("## 10.7%"), None);
+        assert_eq!(
+            tracker.rewrite("### 11.0%").as_deref(),
+            Some("::progress::download 11.0")
+        );
+        assert_eq!(tracker.current(), Some(Phase::Download));
+
+        tracker.rewrite(&JobEvent::failed(Phase::Download).to_marker());
+        assert_eq!(tracker.current(), None);
+    }
+}
@@ -0,0 +1,1101 @@
+use serde::Deserialize;
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::error;
+use utoipa::ToSchema;
+
+use crate::{
+    compression::{Compression, ImageSource},
+    config::FieldError,
+    devices::{self, BlockDevice},
+    events::{JobEvent, Phase},
+    jobs::{DeviceStatus, FlashDevice, FlashTarget, JobStore},
+    logs::{LogHub, LogStream},
+    writer::{self, Digests, FlashError, Progress},
+    AppError,
+};
+
+/// Body of `POST /api/flash`.
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct FlashRequest {
+    /// Name of a built image, as listed by `GET /api/images`.
+    pub image_id: Option<String>,
+    /// Absolute path of an image file on the backend host, e.g. an upload.
+    /// Give either this or `image_id`.
+    pub image_path: Option<String>,
+    /// Whole-disk device node, e.g. `/dev/sdb`. Give either this or
+    /// `devices`.
+    pub device: Option<String>,
+    /// The device's `fingerprint` from `GET /api/devices`. Flashing is
+    /// refused if a different medium is behind `device` by then.
+    pub fingerprint: Option<String>,
+    /// Several devices to write the image to at once.
+    #[serde(default)]
+    pub devices: Vec<DeviceSelection>,
+    /// Read the device back after writing and compare it with the image.
+    #[serde(default)]
+    pub verify: bool,
+    /// Power off the device once it has been written (and verified).
+    #[serde(default)]
+    pub eject_after: bool,
+    /// Size of the image in bytes as the client saw it; the request is
+    /// rejected if the file has changed since.
+    pub expected_size: Option<u64>,
+}
+
+/// A device picked for flashing, with the `fingerprint` it was listed with.
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceSelection {
+    pub device: String,
+    pub fingerprint: String,
+}
+
+impl FlashRequest {
+    /// Checks every field and resolves the image, so that a bad request is
+    /// rejected before a job exists for it. Built images are looked up in
+    /// `images_dir`.
+    pub fn validate(self, images_dir: &Path) -> Result<FlashTarget, AppError> {
+        self.validate_with(images_dir, check_device)
+    }
+
+    /// [`validate`](Self::validate) with `check` in place of
+    /// [`check_device`].
+    fn validate_with(
+        mut self,
+        images_dir: &Path,
+        check: impl Fn(&str, u64, &str) -> Result<BlockDevice, FieldError>,
+    ) -> Result<FlashTarget, AppError> {
+        let mut errors = Vec::new();
+
+        let image = match (&self.image_id, &self.image_path) {
+            (Some(_), Some(_)) => {
+                errors.push(FieldError::new(
+                    "image_path",
+                    "give either image_id or image_path, not both",
+                ));
+                None
+            }
+            (None, None) => {
+                errors.push(FieldError::new(
+                    "image_id",
+                    "image_id or image_path is required",
+                ));
+                None
+            }
+            (Some(id), None) => match image_id_path(images_dir, id) {
+                Ok(path) => Some(("image_id", path)),
+                Err(message) => {
+                    errors.push(FieldError::new("image_id", message));
+                    None
+                }
+            },
+            (None, Some(path)) if !Path::new(path).is_absolute() => {
+                errors.push(FieldError::new("image_path", "must be an absolute path"));
+                None
+            }
+            (None, Some(path)) => Some(("image_path", path.clone())),
+        };
+
+        let source = image
+            .as_ref()
+            .and_then(|(field, path)| match fs::metadata(path) {
+                Ok(meta) if meta.is_file() => match ImageSource::open(Path::new(path)) {
+                    Ok(source) => Some(source),
+                    Err(e) => {
+                        errors.push(FieldError::new(*field, format!("cannot be read: {}", e)));
+                        None
+                    }
+                },
+                Ok(_) => {
+                    errors.push(FieldError::new(*field, "is not a regular file"));
+                    None
+                }
+                Err(e) => {
+                    errors.push(FieldError::new(*field, format!("cannot be read: {}", e)));
+                    None
+                }
+            });
+
+        match (source.as_ref().map(|s| s.file_len), self.expected_size) {
+            (Some(0), _) => errors.push(FieldError::new(
+                image
+                    .as_ref()
+                    .map(|(field, _)| *field)
+                    .unwrap_or("image_path"),
+                "is empty",
+            )),
+            (Some(actual), Some(expected)) if actual != expected => errors.push(FieldError::new(
+                "expected_size",
+                format!("image is {} bytes, expected {}", actual, expected),
+            )),
+            _ => {}
+        }
+
+        let image_size = source.as_ref().map(ImageSource::min_size);
+        let mut devices = Vec::new();
+        for (prefix, selection) in self.take_devices(&mut errors) {
+            match check(
+                &selection.device,
+                image_size.unwrap_or(0),
+                &selection.fingerprint,
+            ) {
+                Ok(disk) => {
+                    let device = format!("/dev/{}", disk.name);
+                    if devices.iter().any(|d: &FlashDevice| d.device == device) {
+                        errors.push(FieldError::new(
+                            format!("{}device", prefix),
+                            format!("{} is listed more than once", selection.device),
+                        ));
+                    }
+                    devices.push(FlashDevice::new(device, selection.fingerprint));
+                }
+                Err(e) => errors.push(FieldError::new(format!("{}{}", prefix, e.field), e.message)),
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(AppError::Validation(
+                "Invalid flash request".to_string(),
+                errors,
+            ));
+        }
+        let (_, image_path) = image.expect("validated above");
+        Ok(FlashTarget {
+            image_path,
+            devices,
+            verify: self.verify,
+            eject_after: self.eject_after,
+            image_size,
+        })
+    }
+
+    /// Takes the devices to write out of the request, each with the prefix
+    /// its field errors get: none for `device`, `devices[i].` for an entry
+    /// of the list.
+    fn take_devices(&mut self, errors: &mut Vec<FieldError>) -> Vec<(String, DeviceSelection)> {
+        let devices = std::mem::take(&mut self.devices);
+        match (self.device.take(), self.fingerprint.take()) {
+            (Some(_), _) if !devices.is_empty() => {
+                errors.push(FieldError::new(
+                    "devices",
+                    "give either device or devices, not both",
+                ));
+                Vec::new()
+            }
+            (Some(device), Some(fingerprint)) => {
+                vec![(
+                    String::new(),
+                    DeviceSelection {
+                        device,
+                        fingerprint,
+                    },
+                )]
+            }
+            (Some(_), None) => {
+                errors.push(FieldError::new("fingerprint", "is required"));
+                Vec::new()
+            }
+            (None, _) if devices.is_empty() => {
+                errors.push(FieldError::new("device", "device or devices is required"));
+                Vec::new()
+            }
+            (None, fingerprint) => {
+                if fingerprint.is_some() {
+                    errors.push(FieldError::new(
+                        "fingerprint",
+                        "give each entry of devices its own fingerprint",
+                    ));
+                }
+                devices
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, selection)| (format!("devices[{}].", i), selection))
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Path of a built image. Ids are plain file names, so they cannot point
+/// outside `images_dir`.
+fn image_id_path(images_dir: &Path, id: &str) -> Result<String, String> {
+    let mut components = Path::new(id).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => {}
+        _ => return Err("must be the name of an image from /api/images".to_string()),
+    }
+    let path = images_dir.join(id);
+    if !path.exists() {
+        return Err(format!("no image named '{}'", id));
+    }
+    Ok(path.to_string_lossy().to_string())
+}
+
+/// Refuses anything but a whole removable disk that is not part of the
+/// running system, can hold `image_size` bytes and still carries the medium
+/// identified by `fingerprint`. Runs when a flash is requested and again
+/// right before writing, since a card can be swapped while the job queues.
+pub fn check_device(
+    device: &str,
+    image_size: u64,
+    fingerprint: &str,
+) -> Result<BlockDevice, FieldError> {
+    let refuse = |message: String| FieldError::new("device", message);
+
+    let name = devices::block_name(device)
+        .ok_or_else(|| refuse(format!("{} is not a block device", device)))?;
+    let system = devices::system_disks()
+        .map_err(|e| refuse(format!("cannot check whether {} is in use: {}", device, e)))?;
+    check_disk(
+        device,
+        BlockDevice::probe(&name),
+        &system,
+        image_size,
+        fingerprint,
+    )
+}
+
+/// The checks of [`check_device`] on what was found behind `device`: `disk`
+/// as probed, and the `system` disks of the running system.
+fn check_disk(
+    device: &str,
+    disk: io::Result<BlockDevice>,
+    system: &HashSet<String>,
+    image_size: u64,
+    fingerprint: &str,
+) -> Result<BlockDevice, FieldError> {
+    let refuse = |message: String| FieldError::new("device", message);
+
+    let disk = disk.map_err(|_| {
+        refuse(format!(
+            "{} is not a whole disk; select the disk, not a partition",
+            device
+        ))
+    })?;
+    if !disk.removable {
+        return Err(refuse(format!("{} is not a removable disk", device)));
+    }
+    if disk.read_only {
+        return Err(refuse(format!("{} is read-only", device)));
+    }
+    if system.contains(&disk.name) {
+        return Err(refuse(format!(
+            "{} holds the running system (root filesystem, swap or a system mount)",
+            device
+        )));
+    }
+    if disk.size_bytes < image_size {
+        return Err(refuse(format!(
+            "{} is too small: {} bytes, the image needs {}",
+            device, disk.size_bytes, image_size
+        )));
+    }
+    if disk.fingerprint() != fingerprint {
+        return Err(FieldError::new(
+            "fingerprint",
+            format!(
+                "{} is not the device that was listed; refresh the device list",
+                device
+            ),
+        ));
+    }
+    Ok(disk)
+}
+
+/// Runs a flash job: checks the devices again, unmounts them, writes the
+/// image to all of them at once and, if asked, reads each one back. Devices
+/// that made it then get their partition table re-read and are ejected if
+/// the request said so. A device that fails is marked as such in the job and
+/// the others carry on; the job fails if any device did.
+pub async fn run(
+    job_id: &str,
+    target: FlashTarget,
+    jobs: Arc<JobStore>,
+    logs: Arc<LogHub>,
+    cancel: &CancellationToken,
+) -> Result<(), AppError> {
+    let image = ImageSource::open(Path::new(&target.image_path)).map_err(|e| {
+        AppError::Internal(format!("Cannot read image {}: {}", target.image_path, e))
+    })?;
+    let mut board = Board {
+        job_id: job_id.to_string(),
+        devices: target.devices,
+        verify: target.verify,
+        jobs,
+        logs,
+    };
+
+    // A card can be swapped while the job waits in the queue.
+    for index in 0..board.devices.len() {
+        let device = &board.devices[index];
+        if let Err(e) = check_device(&device.device, image.min_size(), &device.fingerprint) {
+            let message = format!("Refusing to flash {}: {}", device.device, e.message);
+            board.fail(index, message).await;
+        }
+    }
+
+    // Cancelled while queued: leave the cards, and their mounts, alone.
+    if cancel.is_cancelled() {
+        for index in board.active() {
+            board.set_status(index, DeviceStatus::Cancelled).await;
+        }
+        return Err(AppError::Internal("Flash cancelled".to_string()));
+    }
+
+    // Desktops automount cards as they go in; writing underneath a mounted
+    // filesystem leaves a corrupt card once the automounter flushes it.
+    for index in board.active() {
+        let device = board.devices[index].device.clone();
+        match unmount(&device).await {
+            Ok(targets) => {
+                for target in targets {
+                    board
+                        .log(&format!("Unmounted {} ({})", target, device))
+                        .await;
+                }
+            }
+            Err(e) => {
+                let message = format!("Refusing to flash {}: {}", device, e);
+                board.fail(index, message).await;
+            }
+        }
+    }
+    if board.active().is_empty() {
+        board.event(&JobEvent::failed(Phase::Flash)).await;
+        return Err(AppError::Conflict(board.failures()));
+    }
+
+    if image.compression != Compression::None {
+        let size = match image.size {
+            Some(size) => format!("{} bytes uncompressed", size),
+            None => "uncompressed size unknown".to_string(),
+        };
+        let message = format!(
+            "Decompressing {} image while writing ({})",
+            image.compression.as_str(),
+            size
+        );
+        board.log(&message).await;
+    }
+
+    board.event(&JobEvent::started(Phase::Flash)).await;
+    let digests = match write(&mut board, image, cancel).await {
+        Ok(digests) => digests,
+        Err(e) => {
+            // Devices still writing were stopped because of the source.
+            for index in 0..board.devices.len() {
+                if !matches!(
+                    board.devices[index].status,
+                    DeviceStatus::Failed | DeviceStatus::Success
+                ) {
+                    board.fail_with(index, "Flashing", &e).await;
+                }
+            }
+            board.event(&JobEvent::failed(Phase::Flash)).await;
+            return Err(AppError::Internal(match e {
+                FlashError::Cancelled => "Flash cancelled".to_string(),
+                e => format!("Flashing failed: {}", e),
+            }));
+        }
+    };
+    board.end_phase(Phase::Flash).await;
+
+    if let Some(digests) = digests.filter(|_| !board.active().is_empty()) {
+        board
+            .log(&format!("Image SHA-256: {}", digests.sha256()))
+            .await;
+        board.event(&JobEvent::started(Phase::Verify)).await;
+        verify(&mut board, &target.image_path, digests, cancel).await;
+        board.end_phase(Phase::Verify).await;
+    }
+
+    if cancel.is_cancelled() {
+        return Err(AppError::Internal("Flash cancelled".to_string()));
+    }
+    for index in 0..board.devices.len() {
+        if board.devices[index].status == DeviceStatus::Success {
+            release(&board, index, target.eject_after).await;
+        }
+    }
+    if board
+        .devices
+        .iter()
+        .any(|d| d.status == DeviceStatus::Failed)
+    {
+        return Err(AppError::Internal(board.failures()));
+    }
+    Ok(())
+}
+
+/// Unmounts everything on `device`; see [`devices::unmount_all`].
+async fn unmount(device: &str) -> io::Result<Vec<String>> {
+    let name = devices::block_name(device)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no longer a block device"))?;
+    tokio::task::spawn_blocking(move || devices::unmount_all(&name))
+        .await
+        .map_err(io::Error::other)?
+}
+
+/// Hands a written device back to the host: re-reads its partition table
+/// and, if `eject` is set, powers it off. Neither failing fails the device,
+/// whose contents are already written; they are logged as warnings.
+async fn release(board: &Board, index: usize, eject: bool) {
+    let device = board.devices[index].device.clone();
+    let Some(name) = devices::block_name(&device) else {
+        return;
+    };
+
+    let path = device.clone();
+    match tokio::task::spawn_blocking(move || devices::reread_partitions(&path)).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => {
+            let message = format!("Cannot re-read the partition table of {}: {}", device, e);
+            board.event(&JobEvent::Warning { message }).await;
+        }
+        Err(e) => error!("Re-reading partitions of {} panicked: {}", device, e),
+    }
+    if !eject {
+        return;
+    }
+
+    // The new partitions may have been automounted already.
+    let result = tokio::task::spawn_blocking(move || {
+        devices::unmount_all(&name)?;
+        devices::power_off(&name)
+    })
+    .await
+    .map_err(io::Error::other)
+    .and_then(|result| result);
+    match result {
+        Ok(true) => {
+            board
+                .log(&format!("Powered off {}; it is safe to remove", device))
+                .await
+        }
+        Ok(false) => {
+            let message = format!(
+                "Ejected {}; it is safe to remove, but its USB device stays on for its other disks",
+                device
+            );
+            board.log(&message).await;
+        }
+        Err(e) => {
+            let message = format!("Cannot eject {}: {}", device, e);
+            board.event(&JobEvent::Warning { message }).await;
+        }
+    }
+}
+
+/// Sent by the blocking workers of a phase to [`Board::relay`]. Devices are
+/// identified by their position among the devices taking part.
+enum Update {
+    /// Bytes read from the image (`None`) or through a device so far.
+    Progress(Option<usize>, Progress),
+    Written(usize, Result<u64, FlashError>),
+    Verified(usize, Result<String, FlashError>),
+}
+
+/// Writes the image to every device still active, hashing it on the way
+/// when a verify pass follows. Fails only if the image cannot be read or
+/// the job is cancelled; devices that fail are marked on the board.
+async fn write(
+    board: &mut Board,
+    image: ImageSource,
+    cancel: &CancellationToken,
+) -> Result<Option<Digests>, FlashError> {
+    let active = board.active();
+    let paths: Vec<PathBuf> = active
+        .iter()
+        .map(|&index| PathBuf::from(&board.devices[index].device))
+        .collect();
+    let total = image.size;
+    let verify = board.verify;
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    let cancel = cancel.clone();
+    let task = tokio::task::spawn_blocking(move || {
+        let mut digests = verify.then(Digests::default);
+        let on_progress = |position, progress| {
+            let _ = tx.send(Update::Progress(position, progress));
+        };
+        let on_done = |position, result| {
+            let _ = tx.send(Update::Written(position, result));
+        };
+        image
+            .read(|reader| {
+                writer::write_image(
+                    reader,
+                    &paths,
+                    total,
+                    &cancel,
+                    digests.as_mut(),
+                    &on_progress,
+                    &on_done,
+                )
+            })
+            .map_err(|source| FlashError::Read { offset: 0, source })??;
+        Ok(digests)
+    });
+
+    for &index in &active {
+        board.set_status(index, DeviceStatus::Writing).await;
+    }
+    board.relay(Phase::Flash, &active, rx).await;
+    join(task).await
+}
+
+/// Reads every written device back in parallel, bypassing the page cache,
+/// and compares it with the `digests` taken by [`write`]. Devices that do
+/// not match are marked failed with the offset of the first difference.
+async fn verify(board: &mut Board, image_path: &str, digests: Digests, cancel: &CancellationToken) {
+    let active = board.active();
+    let devices: Vec<(PathBuf, u64)> = active
+        .iter()
+        .map(|&index| {
+            let device = &board.devices[index];
+            (PathBuf::from(&device.device), device.bytes_written)
+        })
+        .collect();
+    // Only used to pinpoint the first differing byte of a mismatch, which
+    // needs random access to the image.
+    let image = ImageSource::open(Path::new(image_path))
+        .ok()
+        .and_then(ImageSource::into_raw);
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    let cancel = cancel.clone();
+    let task = tokio::task::spawn_blocking(move || {
+        thread::scope(|scope| {
+            for (position, (device, length)) in devices.iter().enumerate() {
+                let (tx, digests, image, cancel) = (tx.clone(), &digests, &image, &cancel);
+                scope.spawn(move || {
+                    let result = writer::verify_device(
+                        device,
+                        *length,
+                        digests,
+                        image.as_ref(),
+                        cancel,
+                        |progress| {
+                            let _ = tx.send(Update::Progress(Some(position), progress));
+                        },
+                    );
+                    let _ = tx.send(Update::Verified(position, result));
+                });
+            }
+        })
+    });
+
+    for &index in &active {
+        board.set_status(index, DeviceStatus::Verifying).await;
+    }
+    board.relay(Phase::Verify, &active, rx).await;
+    join(task).await;
+}
+
+/// The devices of a running flash job. Status changes are saved to the job
+/// record right away and announced as `device` events; progress is saved
+/// once per [`SAVE_INTERVAL`].
+struct Board {
+    job_id: String,
+    devices: Vec<FlashDevice>,
+    verify: bool,
+    jobs: Arc<JobStore>,
+    logs: Arc<LogHub>,
+}
+
+/// How often per-device progress is written to the job record.
+const SAVE_INTERVAL: Duration = Duration::from_secs(2);
+
+impl Board {
+    /// Indices of the devices that have neither failed nor been cancelled.
+    fn active(&self) -> Vec<usize> {
+        (0..self.devices.len())
+            .filter(|&index| {
+                !matches!(
+                    self.devices[index].status,
+                    DeviceStatus::Failed | DeviceStatus::Cancelled
+                )
+            })
+            .collect()
+    }
+
+    /// Handles the updates of a phase until its workers are done. Progress
+    /// is logged as `transfer` events for the image (`None`) and for each
+    /// device, and as a `progress` event for the phase whenever the average
+    /// of the devices still running moves a whole percent.
+    async fn relay(
+        &mut self,
+        phase: Phase,
+        active: &[usize],
+        mut rx: mpsc::UnboundedReceiver<Update>,
+    ) {
+        let mut percents = vec![Some(0f32); active.len()];
+        let mut last_percent = None;
+        let mut last_save = Instant::now();
+        while let Some(update) = rx.recv().await {
+            match update {
+                Update::Progress(position, progress) => {
+                    let device = position.map(|position| {
+                        let device = &mut self.devices[active[position]];
+                        match phase {
+                            Phase::Verify => device.bytes_verified = progress.written,
+                            _ => device.bytes_written = progress.written,
+                        }
+                        percents[position] = Some(progress.percent().unwrap_or(0.0));
+                        device.device.clone()
+                    });
+                    self.event(&transfer_event(phase, device, &progress)).await;
+                }
+                Update::Written(position, result) => {
+                    percents[position] = result.is_ok().then_some(100.0);
+                    self.written(active[position], result).await;
+                }
+                Update::Verified(position, result) => {
+                    percents[position] = result.is_ok().then_some(100.0);
+                    self.verified(active[position], result).await;
+                }
+            }
+
+            let running: Vec<f32> = percents.iter().flatten().copied().collect();
+            if !running.is_empty() {
+                let percent = running.iter().sum::<f32>() / running.len() as f32;
+                if last_percent != Some(percent as u32) {
+                    last_percent = Some(percent as u32);
+                    self.event(&JobEvent::Progress { phase, percent }).await;
+                }
+            }
+            if last_save.elapsed() >= SAVE_INTERVAL {
+                last_save = Instant::now();
+                self.save().await;
+            }
+        }
+        self.save().await;
+    }
+
+    async fn written(&mut self, index: usize, result: Result<u64, FlashError>) {
+        match result {
+            Ok(bytes) => {
+                let device = &mut self.devices[index];
+                device.bytes_written = bytes;
+                let message = format!("Wrote {} bytes to {}", bytes, device.device);
+                self.log(&message).await;
+                if !self.verify {
+                    self.set_status(index, DeviceStatus::Success).await;
+                }
+            }
+            Err(e) => self.fail_with(index, "Flashing", &e).await,
+        }
+    }
+
+    async fn verified(&mut self, index: usize, result: Result<String, FlashError>) {
+        match result {
+            Ok(sha256) => {
+                let device = &mut self.devices[index];
+                device.verified = Some(true);
+                device.bytes_verified = device.bytes_written;
+                let message = format!(
+                    "Verified {}: SHA-256 {} matches the image",
+                    device.device, sha256
+                );
+                self.log(&message).await;
+                self.set_status(index, DeviceStatus::Success).await;
+            }
+            Err(e) => {
+                if let FlashError::Mismatch { .. } = e {
+                    self.devices[index].verified = Some(false);
+                }
+                self.fail_with(index, "Verifying", &e).await;
+            }
+        }
+    }
+
+    async fn set_status(&mut self, index: usize, status: DeviceStatus) {
+        let device = &mut self.devices[index];
+        device.status = status;
+        let event = JobEvent::Device {
+            device: device.device.clone(),
+            status,
+        };
+        self.event(&event).await;
+        self.save().await;
+    }
+
+    /// Marks a device that stopped while being written or verified: failed,
+    /// or cancelled if the job was.
+    async fn fail_with(&mut self, index: usize, action: &str, error: &FlashError) {
+        if let FlashError::Cancelled = error {
+            self.set_status(index, DeviceStatus::Cancelled).await;
+            return;
+        }
+        let message = format!(
+            "{} {} failed: {}",
+            action, self.devices[index].device, error
+        );
+        self.fail(index, message).await;
+    }
+
+    async fn fail(&mut self, index: usize, message: String) {
+        error!("Flash job {}: {}", self.job_id, message);
+        self.log(&message).await;
+        self.devices[index].error = Some(message);
+        self.set_status(index, DeviceStatus::Failed).await;
+    }
+
+    /// Finishes a phase as failed if no device got through it.
+    async fn end_phase(&self, phase: Phase) {
+        let event = if self.active().is_empty() {
+            JobEvent::failed(phase)
+        } else {
+            JobEvent::finished(phase)
+        };
+        self.event(&event).await;
+    }
+
+    /// Summary of the devices that failed, for the job's error.
+    fn failures(&self) -> String {
+        let failed: Vec<&str> = self
+            .devices
+            .iter()
+            .filter_map(|d| d.error.as_deref())
+            .collect();
+        format!(
+            "{} of {} devices failed: {}",
+            failed.len(),
+            self.devices.len(),
+            failed.join("; ")
+        )
+    }
+
+    async fn event(&self, event: &JobEvent) {
+        self.logs.event(&self.job_id, event).await;
+    }
+
+    async fn log(&self, message: &str) {
+        self.logs
+            .append(&self.job_id, LogStream::System, message)
+            .await;
+    }
+
+    async fn save(&self) {
+        let devices = self.devices.clone();
+        self.jobs
+            .update(&self.job_id, |job| {
+                if let Some(flash) = job.flash.as_mut() {
+                    flash.devices = devices;
+                }
+            })
+            .await;
+    }
+}
+
+async fn join<T>(task: tokio::task::JoinHandle<T>) -> T {
+    match task.await {
+        Ok(result) => result,
+        // Blocking tasks cannot be aborted, so this is a panic.
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+fn transfer_event(phase: Phase, device: Option<String>, progress: &Progress) -> JobEvent {
+    JobEvent::Transfer {
+        phase,
+        bytes: progress.written,
+        total: progress.total,
+        bytes_per_sec: progress.bytes_per_sec(),
+        eta_secs: progress.eta().map(|eta| eta.as_secs()),
+        device,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `/sys/block` with one 1 GiB card reader, `sdx`, and its first
+    /// partition.
+    struct Sysfs(PathBuf);
+
+    impl Sysfs {
+        fn new() -> Self {
+            let root =
+                std::env::temp_dir().join(format!("imgforge-sysfs-{}", uuid::Uuid::new_v4()));
+            let disk = root.join("sdx");
+            fs::create_dir_all(disk.join("sdx1")).unwrap();
+            for (attr, value) in [("size", "2097152"), ("removable", "1"), ("ro", "0")] {
+                fs::write(disk.join(attr), value).unwrap();
+            }
+            fs::write(disk.join("sdx1/partition"), "1").unwrap();
+            fs::write(disk.join("sdx1/size"), "2095104").unwrap();
+            Sysfs(root)
+        }
+
+        fn probe(&self, name: &str) -> io::Result<BlockDevice> {
+            BlockDevice::probe_in(&self.0, name, &[])
+        }
+    }
+
+    impl Drop for Sysfs {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn check(
+        disk: io::Result<BlockDevice>,
+        system: &[&str],
+        image_size: u64,
+        fingerprint: &str,
+    ) -> Result<BlockDevice, FieldError> {
+        let system = system.iter().map(|s| s.to_string()).collect();
+        check_disk("/dev/sdx", disk, &system, image_size, fingerprint)
+    }
+
+    fn refusal(result: Result<BlockDevice, FieldError>) -> (String, String) {
+        let e = result.expect_err("device was accepted");
+        (e.field, e.message)
+    }
+
+    #[test]
+    fn accepts_the_listed_card() {
+        let sysfs = Sysfs::new();
+        let disk = sysfs.probe("sdx").unwrap();
+        assert_eq!(disk.size_bytes, 1 << 30);
+        assert_eq!(disk.partitions.len(), 1);
+        let fingerprint = disk.fingerprint();
+        let checked = check(Ok(disk), &["vda"], 1 << 30, &fingerprint).unwrap();
+        assert_eq!(checked.name, "sdx");
+    }
+
+    #[test]
+    fn refuses_another_medium() {
+        let sysfs = Sysfs::new();
+        let listed = sysfs.probe("sdx").unwrap().fingerprint();
+        // Another card of another size went in since it was listed.
+        fs::write(sysfs.0.join("sdx/size"), "4194304").unwrap();
+        let (field, message) = refusal(check(sysfs.probe("sdx"), &[], 0, &listed));
+        assert_eq!(field, "fingerprint");
+        assert!(
+            message.contains("not the device that was listed"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn refuses_a_partition() {
+        let sysfs = Sysfs::new();
+        let disk = sysfs.probe("sdx1");
+        let (field, message) = refusal(check(disk, &[], 0, ""));
+        assert_eq!(field, "device");
+        assert!(message.contains("not a whole disk"), "{}", message);
+    }
+
+    #[test]
+    fn refuses_a_system_disk() {
+        let sysfs = Sysfs::new();
+        let disk = sysfs.probe("sdx").unwrap();
+        let fingerprint = disk.fingerprint();
+        let (field, message) = refusal(check(Ok(disk), &["vda", "sdx"], 0, &fingerprint));
+        assert_eq!(field, "device");
+        assert!(message.contains("running system"), "{}", message);
+    }
+
+    #[test]
+    fn refuses_fixed_and_read_only_disks() {
+        let sysfs = Sysfs::new();
+        fs::write(sysfs.0.join("sdx/ro"), "1").unwrap();
+        let disk = sysfs.probe("sdx").unwrap();
+        let fingerprint = disk.fingerprint();
+        let (_, message) = refusal(check(Ok(disk), &[], 0, &fingerprint));
+        assert!(message.contains("read-only"), "{}", message);
+
+        fs::write(sysfs.0.join("sdx/removable"), "0").unwrap();
+        let disk = sysfs.probe("sdx").unwrap();
+        let fingerprint = disk.fingerprint();
+        let (_, message) = refusal(check(Ok(disk), &[], 0, &fingerprint));
+        assert!(message.contains("not a removable disk"), "{}", message);
+    }
+
+    #[test]
+    fn refuses_a_disk_smaller_than_the_image() {
+        let sysfs = Sysfs::new();
+        let disk = sysfs.probe("sdx").unwrap();
+        let fingerprint = disk.fingerprint();
+        let (field, message) = refusal(check(Ok(disk), &[], (1 << 30) + 1, &fingerprint));
+        assert_eq!(field, "device");
+        assert!(message.contains("too small"), "{}", message);
+    }
+
+    /// Requests whose devices all pass the checks; `/dev/disk/by-id/card`
+    /// is another name for `/dev/sdx`.
+    fn validate(request: serde_json::Value, images_dir: &Path) -> Result<FlashTarget, AppError> {
+        let request: FlashRequest = serde_json::from_value(request).unwrap();
+        request.validate_with(images_dir, |device, _, _| {
+            let name = match device {
+                "/dev/disk/by-id/card" => "sdx",
+                other => other.trim_start_matches("/dev/"),
+            };
+            Ok(BlockDevice {
+                name: name.to_string(),
+                size_bytes: 1 << 30,
+                removable: true,
+                read_only: false,
+                vendor: None,
+                model: None,
+                serial: None,
+                transport: Some("usb".to_string()),
+                partitions: Vec::new(),
+            })
+        })
+    }
+
+    fn rejected_fields(result: Result<FlashTarget, AppError>) -> Vec<String> {
+        match result {
+            Err(AppError::Validation(message, errors)) => {
+                assert_eq!(message, "Invalid flash request");
+                errors.into_iter().map(|e| e.field).collect()
+            }
+            other => panic!(
+                "expected a validation error, got {:?}",
+                other.map(|t| t.devices)
+            ),
+        }
+    }
+
+    fn images_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("imgforge-images-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("gateway.img"), [0u8; 4096]).unwrap();
+        dir
+    }
+
+    #[test]
+    fn flash_request_resolves_image_and_devices() {
+        let dir = images_dir();
+        let request = serde_json::json!({
+            "image_id": "gateway.img",
+            "devices": [
+                {"device": "/dev/sdx", "fingerprint": "a"},
+                {"device": "/dev/sdy", "fingerprint": "b"},
+            ],
+        });
+        let target = validate(request, &dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(target.image_path, dir.join("gateway.img").to_string_lossy());
+        assert_eq!(target.image_size, Some(4096));
+        let devices: Vec<_> = target.devices.iter().map(|d| d.device.as_str()).collect();
+        assert_eq!(devices, ["/dev/sdx", "/dev/sdy"]);
+    }
+
+    #[test]
+    fn flash_request_refuses_a_device_listed_twice() {
+        let dir = images_dir();
+        let request = serde_json::json!({
+            "image_id": "gateway.img",
+            "devices": [
+                {"device": "/dev/sdx", "fingerprint": "a"},
+                {"device": "/dev/sdy", "fingerprint": "b"},
+                {"device": "/dev/disk/by-id/card", "fingerprint": "a"},
+            ],
+        });
+        let fields = rejected_fields(validate(request, &dir));
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(fields, ["devices[2].device"]);
+    }
+
+    #[test]
+    fn flash_request_refuses_image_ids_outside_the_images() {
+        let dir = images_dir();
+        fs::write(dir.with_extension("img"), [0u8; 4096]).unwrap();
+        let outside = format!(
+            "../{}",
+            dir.with_extension("img")
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+        );
+        for id in [
+            outside.as_str(),
+            "..",
+            "/etc/passwd",
+            "sub/gateway.img",
+            "./gateway.img",
+            "",
+        ] {
+            let request = serde_json::json!({
+                "image_id": id,
+                "device": "/dev/sdx",
+                "fingerprint": "a",
+            });
+            assert_eq!(
+                rejected_fields(validate(request, &dir)),
+                ["image_id"],
+                "{}",
+                id
+            );
+        }
+        fs::remove_file(dir.with_extension("img")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn verify_progress_leaves_the_write_count() {
+        let dir = std::env::temp_dir().join(format!("imgforge-board-{}", uuid::Uuid::new_v4()));
+        let jobs = Arc::new(JobStore::load(dir.join("jobs")).unwrap());
+        let target = FlashTarget {
+            image_path: "/tmp/os.img".to_string(),
+            devices: vec![FlashDevice::new("/dev/sdx".to_string(), String::new())],
+            verify: true,
+            eject_after: false,
+            image_size: Some(4096),
+        };
+        let job = crate::jobs::BuildJob::flash(target.clone());
+        jobs.insert(job.clone()).await;
+        let mut board = Board {
+            job_id: job.id.clone(),
+            devices: target.devices,
+            verify: true,
+            jobs: jobs.clone(),
+            logs: Arc::new(LogHub::new(dir.join("logs"))),
+        };
+        let progress = |written| Progress {
+            written,
+            total: Some(4096),
+            elapsed: Duration::from_secs(1),
+        };
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        tx.send(Update::Progress(Some(0), progress(2048))).unwrap();
+        tx.send(Update::Written(0, Ok(4096))).unwrap();
+        drop(tx);
+        board.relay(Phase::Flash, &[0], rx).await;
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        tx.send(Update::Progress(Some(0), progress(0))).unwrap();
+        tx.send(Update::Progress(Some(0), progress(1024))).unwrap();
+        drop(tx);
+        board.relay(Phase::Verify, &[0], rx).await;
+        let device = &jobs.get(&job.id).await.unwrap().flash.unwrap().devices[0];
+        assert_eq!((device.bytes_written, device.bytes_verified), (4096, 1024));
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        tx.send(Update::Verified(0, Ok("sha".to_string()))).unwrap();
+        drop(tx);
+        board.relay(Phase::Verify, &[0], rx).await;
+        let device = &jobs.get(&job.id).await.unwrap().flash.unwrap().devices[0];
+        assert_eq!((device.bytes_written, device.bytes_verified), (4096, 4096));
+        assert_eq!(device.status, DeviceStatus::Success);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
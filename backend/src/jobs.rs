@@ -0,0 +1,481 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+use utoipa::ToSchema;
+
+use crate::{
+    config::{BoardType, BuildMode, ImageConfig},
+    AppError,
+};
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct BuildJob {
+    pub id: String,
+    pub kind: JobKind,
+    pub status: JobStatus,
+    #[serde(default)]
+    pub priority: i32,
+    /// 1-based position in the scheduler queue while the job is `Queued`.
+    #[serde(default)]
+    pub queue_position: Option<usize>,
+    pub created_at: String,
+    #[serde(default)]
+    pub started_at: Option<String>,
+    #[serde(default)]
+    pub finished_at: Option<String>,
+    #[serde(default)]
+    pub exit_code: Option<i32>,
+    #[serde(default)]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub mode: Option<BuildMode>,
+    #[serde(default)]
+    pub board: Option<BoardType>,
+    #[serde(default)]
+    pub config: Option<ImageConfig>,
+    #[serde(default)]
+    pub flash: Option<FlashTarget>,
+    /// Profile the configuration was taken from, if any.
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// Job whose configuration this build was re-run from.
+    #[serde(default)]
+    pub rebuilt_from: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum JobKind {
+    Build,
+    Flash,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Success,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn is_terminal(&self) -> bool {
+        !matches!(self, JobStatus::Queued | JobStatus::Running)
+    }
+}
+
+/// How a job's runner ended; see [`JobStore::finish`].
+#[derive(Debug)]
+pub enum JobOutcome {
+    Success,
+    Failed(String),
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(from = "StoredFlashTarget")]
+pub struct FlashTarget {
+    pub image_path: String,
+    /// Devices written at once from a single read of the image.
+    pub devices: Vec<FlashDevice>,
+    #[serde(default)]
+    pub verify: bool,
+    #[serde(default)]
+    pub eject_after: bool,
+    /// Bytes to write, when known.
+    #[serde(default)]
+    pub image_size: Option<u64>,
+}
+
+/// One device of a flash job and how far it has got.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct FlashDevice {
+    pub device: String,
+    /// Identity of the medium the client picked; see `flash::check_device`.
+    #[serde(default)]
+    pub fingerprint: String,
+    #[serde(default)]
+    pub status: DeviceStatus,
+    #[serde(default)]
+    pub bytes_written: u64,
+    /// Bytes read back and compared so far.
+    #[serde(default)]
+    pub bytes_verified: u64,
+    /// Whether the read-back matched the image; unset until verified.
+    #[serde(default)]
+    pub verified: Option<bool>,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl FlashDevice {
+    pub fn new(device: String, fingerprint: String) -> Self {
+        FlashDevice {
+            device,
+            fingerprint,
+            status: DeviceStatus::Pending,
+            bytes_written: 0,
+            bytes_verified: 0,
+            verified: None,
+            error: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceStatus {
+    #[default]
+    Pending,
+    Writing,
+    Verifying,
+    Success,
+    Failed,
+    Cancelled,
+}
+
+impl DeviceStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeviceStatus::Pending => "pending",
+            DeviceStatus::Writing => "writing",
+            DeviceStatus::Verifying => "verifying",
+            DeviceStatus::Success => "success",
+            DeviceStatus::Failed => "failed",
+            DeviceStatus::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        Some(match s {
+            "pending" => DeviceStatus::Pending,
+            "writing" => DeviceStatus::Writing,
+            "verifying" => DeviceStatus::Verifying,
+            "success" => DeviceStatus::Success,
+            "failed" => DeviceStatus::Failed,
+            "cancelled" => DeviceStatus::Cancelled,
+            _ => return None,
+        })
+    }
+}
+
+/// [`FlashTarget`] as stored; jobs saved before flashing to several devices
+/// at once name a single `device`.
+#[derive(Deserialize)]
+struct StoredFlashTarget {
+    image_path: String,
+    #[serde(default)]
+    devices: Vec<FlashDevice>,
+    #[serde(default)]
+    device: Option<String>,
+    #[serde(default)]
+    fingerprint: String,
+    #[serde(default)]
+    verify: bool,
+    #[serde(default)]
+    eject_after: bool,
+    #[serde(default)]
+    image_size: Option<u64>,
+}
+
+impl From<StoredFlashTarget> for FlashTarget {
+    fn from(stored: StoredFlashTarget) -> Self {
+        let mut devices = stored.devices;
+        if let Some(device) = stored.device {
+            devices.push(FlashDevice::new(device, stored.fingerprint));
+        }
+        FlashTarget {
+            image_path: stored.image_path,
+            devices,
+            verify: stored.verify,
+            eject_after: stored.eject_after,
+            image_size: stored.image_size,
+        }
+    }
+}
+
+impl BuildJob {
+    /// The record keeps `config` with its secrets masked; their sealed
+    /// values go to the secret store, see `SecretStore::keep_job_secrets`.
+    pub fn build(config: &ImageConfig) -> Self {
+        BuildJob {
+            mode: Some(config.mode.clone()),
+            board: Some(config.board_type.clone()),
+            config: Some(config.redacted()),
+            ..BuildJob::new(JobKind::Build)
+        }
+    }
+
+    pub fn flash(target: FlashTarget) -> Self {
+        BuildJob {
+            flash: Some(target),
+            ..BuildJob::new(JobKind::Flash)
+        }
+    }
+
+    /// Copy safe to return from the API, with secrets in the stored config
+    /// masked. Only records written before secrets moved to the secret
+    /// store hold any.
+    pub fn redacted(self) -> Self {
+        BuildJob {
+            config: self.config.as_ref().map(ImageConfig::redacted),
+            ..self
+        }
+    }
+
+    fn new(kind: JobKind) -> Self {
+        BuildJob {
+            id: uuid::Uuid::new_v4().to_string(),
+            kind,
+            status: JobStatus::Queued,
+            priority: 0,
+            queue_position: None,
+            created_at: chrono::Utc::now().to_rfc3339(),
+            started_at: None,
+            finished_at: None,
+            exit_code: None,
+            error: None,
+            mode: None,
+            board: None,
+            config: None,
+            flash: None,
+            profile: None,
+            rebuilt_from: None,
+        }
+    }
+}
+
+/// Jobs kept in memory and mirrored to one JSON file per job under
+/// `~/.imgforge/jobs`, so the job list survives a backend restart.
+pub struct JobStore {
+    dir: PathBuf,
+    jobs: Mutex<Vec<BuildJob>>,
+    cancels: Mutex<HashMap<String, CancellationToken>>,
+}
+
+impl JobStore {
+    pub fn load(dir: PathBuf) -> std::io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+
+        let mut jobs = Vec::new();
+        for entry in fs::read_dir(&dir)?.flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            match fs::read(&path).map(|data| serde_json::from_slice::<BuildJob>(&data)) {
+                Ok(Ok(job)) => jobs.push(job),
+                Ok(Err(e)) => warn!("Skipping unreadable job file {}: {}", path.display(), e),
+                Err(e) => warn!("Failed to read job file {}: {}", path.display(), e),
+            }
+        }
+        jobs.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+
+        // Anything still marked running was interrupted by the restart.
+        for job in jobs.iter_mut() {
+            if !job.status.is_terminal() {
+                job.status = JobStatus::Failed;
+                job.queue_position = None;
+                job.finished_at = Some(chrono::Utc::now().to_rfc3339());
+                job.error = Some("Interrupted by backend restart".to_string());
+                write_job(&dir, job);
+            }
+        }
+
+        info!("Loaded {} jobs from {}", jobs.len(), dir.display());
+        Ok(JobStore {
+            dir,
+            jobs: Mutex::new(jobs),
+            cancels: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Registers a new job and returns the token its runner must watch for
+    /// cancellation.
+    pub async fn insert(&self, job: BuildJob) -> CancellationToken {
+        let token = CancellationToken::new();
+        self.cancels
+            .lock()
+            .await
+            .insert(job.id.clone(), token.clone());
+        self.persist(&job);
+        self.jobs.lock().await.push(job);
+        token
+    }
+
+    pub async fn list(&self) -> Vec<BuildJob> {
+        self.jobs.lock().await.clone()
+    }
+
+    pub async fn get(&self, id: &str) -> Option<BuildJob> {
+        self.jobs.lock().await.iter().find(|j| j.id == id).cloned()
+    }
+
+    /// Applies `f` to the job and writes the result back to disk.
+    pub async fn update<F>(&self, id: &str, f: F) -> Option<BuildJob>
+    where
+        F: FnOnce(&mut BuildJob),
+    {
+        let mut jobs = self.jobs.lock().await;
+        let job = jobs.iter_mut().find(|j| j.id == id)?;
+        f(job);
+        self.persist(job);
+        Some(job.clone())
+    }
+
+    /// Marks a queued job as picked up by the scheduler.
+    pub async fn start(&self, id: &str) {
+        self.update(id, |job| {
+            job.status = JobStatus::Running;
+            job.queue_position = None;
+            job.started_at = Some(chrono::Utc::now().to_rfc3339());
+        })
+        .await;
+    }
+
+    /// Marks a job that was removed from the queue before it ever ran.
+    pub async fn mark_cancelled(&self, id: &str) -> Option<BuildJob> {
+        self.cancels.lock().await.remove(id);
+        self.update(id, |job| {
+            job.status = JobStatus::Cancelled;
+            job.queue_position = None;
+            job.finished_at = Some(chrono::Utc::now().to_rfc3339());
+        })
+        .await
+    }
+
+    /// Asks the runner of a running job to stop. The job is marked
+    /// `Cancelled` by [`JobStore::finish`] once its process has exited.
+    pub async fn cancel(&self, id: &str) -> Result<BuildJob, AppError> {
+        let job = self
+            .get(id)
+            .await
+            .ok_or_else(|| AppError::NotFound(format!("Job {} not found", id)))?;
+
+        if job.status.is_terminal() {
+            return Err(AppError::Conflict(format!(
+                "Job {} has already finished",
+                id
+            )));
+        }
+
+        if let Some(token) = self.cancels.lock().await.get(id) {
+            info!("Cancelling job {}", id);
+            token.cancel();
+        }
+
+        Ok(job)
+    }
+
+    /// Records how a build or flash ended. A job whose cancellation was
+    /// requested counts as `Cancelled` whatever its runner reports, since
+    /// stopping it may have made it fail. `exit_code` is that of the build
+    /// process, when there was one.
+    pub async fn finish(&self, id: &str, outcome: JobOutcome, exit_code: Option<i32>) {
+        let cancelled = self
+            .cancels
+            .lock()
+            .await
+            .remove(id)
+            .is_some_and(|token| token.is_cancelled());
+        let outcome = if cancelled {
+            JobOutcome::Cancelled
+        } else {
+            outcome
+        };
+
+        self.update(id, |job| {
+            job.finished_at = Some(chrono::Utc::now().to_rfc3339());
+            job.exit_code = exit_code;
+            match outcome {
+                JobOutcome::Success => job.status = JobStatus::Success,
+                JobOutcome::Failed(message) => {
+                    job.status = JobStatus::Failed;
+                    job.error = Some(message);
+                }
+                JobOutcome::Cancelled => job.status = JobStatus::Cancelled,
+            }
+        })
+        .await;
+    }
+
+    fn persist(&self, job: &BuildJob) {
+        write_job(&self.dir, job);
+    }
+}
+
+fn write_job(dir: &Path, job: &BuildJob) {
+    let path = dir.join(format!("{}.json", job.id));
+    let tmp = path.with_extension("json.tmp");
+    let result = serde_json::to_vec_pretty(job)
+        .map_err(std::io::Error::from)
+        .and_then(|data| fs::write(&tmp, data))
+        .and_then(|_| fs::rename(&tmp, &path));
+
+    if let Err(e) = result {
+        error!("Failed to persist job {}: {}", job.id, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn finish_records_the_outcome() {
+        let dir = std::env::temp_dir().join(format!("imgforge-jobs-{}", uuid::Uuid::new_v4()));
+        let store = JobStore::load(dir.clone()).unwrap();
+        let target = |device: &str| FlashTarget {
+            image_path: "/tmp/os.img".to_string(),
+            devices: vec![FlashDevice::new(device.to_string(), String::new())],
+            verify: false,
+            eject_after: false,
+            image_size: None,
+        };
+
+        let ok = BuildJob::flash(target("/dev/sdx"));
+        store.insert(ok.clone()).await;
+        store.finish(&ok.id, JobOutcome::Success, None).await;
+        let ok = store.get(&ok.id).await.unwrap();
+        assert_eq!(
+            (ok.status, ok.exit_code, ok.error),
+            (JobStatus::Success, None, None)
+        );
+
+        let failed = BuildJob::flash(target("/dev/sdy"));
+        store.insert(failed.clone()).await;
+        let outcome = JobOutcome::Failed("Process exited with exit status: 2".to_string());
+        store.finish(&failed.id, outcome, Some(2)).await;
+        let failed = store.get(&failed.id).await.unwrap();
+        assert_eq!(failed.status, JobStatus::Failed);
+        assert_eq!(failed.exit_code, Some(2));
+        assert_eq!(
+            failed.error.as_deref(),
+            Some("Process exited with exit status: 2")
+        );
+
+        // Stopping a runner usually makes it fail; that is not its outcome.
+        let cancelled = BuildJob::flash(target("/dev/sdz"));
+        store.insert(cancelled.clone()).await;
+        store.cancel(&cancelled.id).await.unwrap();
+        let outcome = JobOutcome::Failed("Flash cancelled".to_string());
+        store.finish(&cancelled.id, outcome, None).await;
+        let cancelled = store.get(&cancelled.id).await.unwrap();
+        assert_eq!(cancelled.status, JobStatus::Cancelled);
+
+        // The record on disk says the same.
+        let reloaded = JobStore::load(dir.clone()).unwrap();
+        let status = reloaded.get(&cancelled.id).await.unwrap().status;
+        assert_eq!(status, JobStatus::Cancelled);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
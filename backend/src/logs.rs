@@ -0,0 +1,452 @@
+use serde::Serialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    sync::{
+        broadcast::{self, error::RecvError},
+        Mutex,
+    },
+};
+use tracing::{error, info, warn};
+use utoipa::ToSchema;
+
+use crate::{credentials, events::JobEvent};
+
+/// Lines a slow WebSocket client may fall behind before it has to catch up
+/// from the log file.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// Where a log line came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum LogStream {
+    Stdout,
+    Stderr,
+    /// Written by the backend itself, e.g. phase markers it emits.
+    System,
+}
+
+impl LogStream {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogStream::Stdout => "stdout",
+            LogStream::Stderr => "stderr",
+            LogStream::System => "system",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "stdout" => Some(LogStream::Stdout),
+            "stderr" => Some(LogStream::Stderr),
+            "system" => Some(LogStream::System),
+            _ => None,
+        }
+    }
+}
+
+/// One line of a job log, stored as `<rfc3339 timestamp> <stream> <text>`.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct LogLine {
+    pub timestamp: String,
+    pub stream: LogStream,
+    pub text: String,
+}
+
+impl LogLine {
+    pub fn new(stream: LogStream, text: &str) -> Self {
+        LogLine {
+            timestamp: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            stream,
+            text: text.to_string(),
+        }
+    }
+
+    /// Parses a stored line. Lines from logs written before timestamps were
+    /// recorded come back as untimed stdout.
+    pub fn parse_or_raw(line: &str) -> Self {
+        LogLine::parse(line).unwrap_or_else(|| LogLine {
+            timestamp: String::new(),
+            stream: LogStream::Stdout,
+            text: line.to_string(),
+        })
+    }
+
+    pub fn parse(line: &str) -> Option<Self> {
+        let (timestamp, rest) = line.split_once(' ')?;
+        let (stream, text) = rest.split_once(' ').unwrap_or((rest, ""));
+        Some(LogLine {
+            timestamp: timestamp.to_string(),
+            stream: LogStream::parse(stream)?,
+            text: text.to_string(),
+        })
+    }
+
+    pub fn format(&self) -> String {
+        format!("{} {} {}", self.timestamp, self.stream.as_str(), self.text)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum LogEvent {
+    /// A formatted [`LogLine`]; `seq` is its 0-based line number in the
+    /// job log.
+    Line { seq: u64, text: String },
+    /// The job reached a terminal status and no more lines will follow.
+    Finished,
+}
+
+/// Job logs on disk plus a live broadcast of lines for followers.
+///
+/// Logs are stored as `~/.imgforge/logs/<job id>.log`. A channel exists from
+/// the moment a job is submitted until it finishes; followers replay the
+/// file first and then continue from the broadcast.
+pub struct LogHub {
+    dir: PathBuf,
+    channels: Mutex<HashMap<String, Arc<JobLog>>>,
+}
+
+struct JobLog {
+    tx: broadcast::Sender<LogEvent>,
+    writer: Mutex<LogWriter>,
+}
+
+struct LogWriter {
+    file: Option<File>,
+    lines: u64,
+    /// Values masked out of every line before it is stored or sent.
+    secrets: Vec<String>,
+}
+
+impl LogHub {
+    pub fn new(dir: PathBuf) -> Self {
+        LogHub {
+            dir,
+            channels: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn path(&self, job_id: &str) -> PathBuf {
+        self.dir.join(format!("{}.log", job_id))
+    }
+
+    /// Starts accepting lines and followers for a newly submitted job.
+    pub async fn open(&self, job_id: &str) {
+        let (tx, _) = broadcast::channel(CHANNEL_CAPACITY);
+        let log = JobLog {
+            tx,
+            writer: Mutex::new(LogWriter {
+                file: None,
+                lines: 0,
+                secrets: Vec::new(),
+            }),
+        };
+        self.channels
+            .lock()
+            .await
+            .insert(job_id.to_string(), Arc::new(log));
+    }
+
+    /// Appends a timestamped line to the job log and forwards it to
+    /// followers.
+    pub async fn append(&self, job_id: &str, stream: LogStream, text: &str) {
+        let Some(log) = self.channels.lock().await.get(job_id).cloned() else {
+            return;
+        };
+        let mut writer = log.writer.lock().await;
+        let text = credentials::scrub(text, &writer.secrets);
+        let line = LogLine::new(stream, &text).format();
+
+        if writer.file.is_none() {
+            match OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.path(job_id))
+                .await
+            {
+                Ok(file) => writer.file = Some(file),
+                Err(e) => error!("Failed to open log for job {}: {}", job_id, e),
+            }
+        }
+        if let Some(file) = writer.file.as_mut() {
+            let _ = file.write_all(format!("{}\n", line).as_bytes()).await;
+            // Followers read the file back, so it must not sit in a buffer.
+            let _ = file.flush().await;
+        }
+
+        let seq = writer.lines;
+        writer.lines += 1;
+        let _ = log.tx.send(LogEvent::Line { seq, text: line });
+    }
+
+    /// Registers secret values to mask in the job's log from now on.
+    pub async fn redact(&self, job_id: &str, secrets: Vec<String>) {
+        let Some(log) = self.channels.lock().await.get(job_id).cloned() else {
+            return;
+        };
+        let mut writer = log.writer.lock().await;
+        for secret in secrets {
+            if !writer.secrets.contains(&secret) {
+                writer.secrets.push(secret);
+            }
+        }
+    }
+
+    /// Masks the job's registered secrets in `text`, for output that is
+    /// logged elsewhere as well.
+    pub async fn scrub(&self, job_id: &str, text: &str) -> String {
+        let Some(log) = self.channels.lock().await.get(job_id).cloned() else {
+            return text.to_string();
+        };
+        let writer = log.writer.lock().await;
+        credentials::scrub(text, &writer.secrets)
+    }
+
+    /// Records a backend-generated event in the job log.
+    pub async fn event(&self, job_id: &str, event: &JobEvent) {
+        self.append(job_id, LogStream::System, &event.to_marker())
+            .await;
+    }
+
+    /// Tells followers the job is done and drops its channel.
+    pub async fn close(&self, job_id: &str) {
+        if let Some(log) = self.channels.lock().await.remove(job_id) {
+            let _ = log.tx.send(LogEvent::Finished);
+        }
+    }
+
+    /// Subscribes to live lines, or `None` once the job has finished.
+    pub async fn subscribe(&self, job_id: &str) -> Option<broadcast::Receiver<LogEvent>> {
+        self.channels
+            .lock()
+            .await
+            .get(job_id)
+            .map(|log| log.tx.subscribe())
+    }
+
+    /// Follows a job log from its first line until the job finishes.
+    pub async fn follow(&self, job_id: &str) -> LogFollower<'_> {
+        // Subscribe before replaying so no line falls between the two;
+        // anything seen in both is skipped by its sequence number.
+        let live = self.subscribe(job_id).await;
+        let backlog = self.read_lines(job_id, 0).await.into();
+        LogFollower {
+            hub: self,
+            job_id: job_id.to_string(),
+            live,
+            backlog,
+            delivered: 0,
+        }
+    }
+
+    /// Reads the stored log, skipping the first `skip` lines.
+    pub async fn read_lines(&self, job_id: &str, skip: u64) -> Vec<String> {
+        let Ok(file) = File::open(self.path(job_id)).await else {
+            return Vec::new();
+        };
+
+        let mut lines = BufReader::new(file).lines();
+        let mut out = Vec::new();
+        let mut index = 0;
+        while let Ok(Some(line)) = lines.next_line().await {
+            if index >= skip {
+                out.push(line);
+            }
+            index += 1;
+        }
+        out
+    }
+
+    /// Deletes logs of finished jobs that have not been written to for
+    /// longer than `max_age`.
+    pub async fn prune(&self, max_age: Duration) -> usize {
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return 0;
+        };
+        let active = self.channels.lock().await;
+        let now = SystemTime::now();
+
+        let mut removed = 0;
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Some(job_id) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            if active.contains_key(job_id) {
+                continue;
+            }
+            let expired = entry
+                .metadata()
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|modified| now.duration_since(modified).ok())
+                .is_some_and(|age| age > max_age);
+            if !expired {
+                continue;
+            }
+            match fs::remove_file(&path) {
+                Ok(()) => removed += 1,
+                Err(e) => warn!("Failed to remove old log {}: {}", path.display(), e),
+            }
+        }
+        removed
+    }
+
+    /// Prunes old logs now and then once an hour.
+    pub async fn run_retention(self: Arc<Self>, max_age: Duration) {
+        let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            let removed = self.prune(max_age).await;
+            if removed > 0 {
+                info!("Removed {} job logs past retention", removed);
+            }
+        }
+    }
+}
+
+/// Lines of one job log: the stored ones first, then live ones as they are
+/// appended. See [`LogHub::follow`].
+pub struct LogFollower<'a> {
+    hub: &'a LogHub,
+    job_id: String,
+    /// `None` once the job has finished; the file is then the whole story.
+    live: Option<broadcast::Receiver<LogEvent>>,
+    backlog: VecDeque<String>,
+    delivered: u64,
+}
+
+impl LogFollower<'_> {
+    /// The next formatted line, or `None` once the job has finished and
+    /// every line was returned.
+    pub async fn next(&mut self) -> Option<String> {
+        loop {
+            if let Some(line) = self.backlog.pop_front() {
+                self.delivered += 1;
+                return Some(line);
+            }
+            match self.live.as_mut()?.recv().await {
+                Ok(LogEvent::Line { seq, text }) => {
+                    if seq < self.delivered {
+                        continue;
+                    }
+                    self.delivered = seq + 1;
+                    return Some(text);
+                }
+                Ok(LogEvent::Finished) | Err(RecvError::Closed) => self.live = None,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(
+                        "Follower of job {} lagged by {} lines, catching up from disk",
+                        self.job_id, skipped
+                    );
+                    self.backlog = self
+                        .hub
+                        .read_lines(&self.job_id, self.delivered)
+                        .await
+                        .into();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    struct Dir(PathBuf);
+
+    impl Drop for Dir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn hub() -> (LogHub, Dir) {
+        let dir = std::env::temp_dir().join(format!("imgforge-logs-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        (LogHub::new(dir.clone()), Dir(dir))
+    }
+
+    async fn texts(mut log: LogFollower<'_>) -> Vec<String> {
+        let mut texts = Vec::new();
+        while let Some(line) = log.next().await {
+            texts.push(LogLine::parse(&line).unwrap().text);
+        }
+        texts
+    }
+
+    fn age(path: &Path, days: u64) {
+        let modified = SystemTime::now() - Duration::from_secs(days * 24 * 60 * 60);
+        fs::File::options()
+            .append(true)
+            .open(path)
+            .and_then(|file| file.set_modified(modified))
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn prune_removes_only_expired_logs_of_finished_jobs() {
+        let (hub, _dir) = hub();
+        for (job, days) in [("old", 30), ("older", 90), ("recent", 2)] {
+            fs::write(hub.path(job), "").unwrap();
+            age(&hub.path(job), days);
+        }
+        // A job that has been running since before the cutoff.
+        hub.open("running").await;
+        hub.append("running", LogStream::Stdout, "still going")
+            .await;
+        age(&hub.path("running"), 30);
+
+        assert_eq!(hub.prune(Duration::from_secs(7 * 24 * 60 * 60)).await, 2);
+        let mut left: Vec<_> = fs::read_dir(&hub.dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["recent.log", "running.log"]);
+    }
+
+    #[tokio::test]
+    async fn late_followers_replay_the_log_before_live_lines() {
+        let (hub, _dir) = hub();
+        hub.open("job").await;
+        hub.append("job", LogStream::Stdout, "one").await;
+        hub.append("job", LogStream::Stderr, "two").await;
+
+        let log = hub.follow("job").await;
+        hub.append("job", LogStream::Stdout, "three").await;
+        hub.event("job", &JobEvent::finished(crate::events::Phase::Flash))
+            .await;
+        hub.close("job").await;
+        let expected = ["one", "two", "three", "::phase-end::flash"];
+        assert_eq!(texts(log).await, expected);
+
+        // Once the job is over the stored log is all there is.
+        assert_eq!(texts(hub.follow("job").await).await, expected);
+    }
+
+    #[tokio::test]
+    async fn lagging_followers_catch_up_from_disk() {
+        let (hub, _dir) = hub();
+        hub.open("job").await;
+        let log = hub.follow("job").await;
+
+        let lines = CHANNEL_CAPACITY + 100;
+        for n in 0..lines {
+            hub.append("job", LogStream::Stdout, &n.to_string()).await;
+        }
+        hub.close("job").await;
+
+        let expected: Vec<_> = (0..lines).map(|n| n.to_string()).collect();
+        assert_eq!(texts(log).await, expected);
+    }
+}
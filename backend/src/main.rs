@@ -1,7 +1,8 @@
 use axum::{
+    body::Bytes,
     extract::{
         ws::{Message, WebSocket, WebSocketUpgrade},
-        Multipart, Path, State,
+        Multipart, Path, Query, State,
     },
     http::{header, StatusCode},
     response::{IntoResponse, Response},
@@ -12,96 +13,209 @@ use serde::{Deserialize, Serialize};
 use std::{
     fs,
     path::PathBuf,
-    process::Stdio,
+    process::{ExitStatus, Stdio},
     sync::Arc,
+    time::Duration,
 };
-use tokio::{
-    io::{AsyncBufReadExt, BufReader},
-    process::Command,
-    sync::Mutex,
-};
+use tokio::process::Command;
 use tower_http::{
     cors::CorsLayer,
     services::ServeDir,
     trace::TraceLayer,
 };
-use tracing::{error, info};
-use uuid::Uuid;
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ImageConfig {
-    pub hostname: String,
-    pub change_username: bool,
-    pub new_username: Option<String>,
-    pub set_root_password: bool,
-    pub root_password: Option<String>,
-    pub enable_ssh: bool,
-    pub wifi_ssid: Option<String>,
-    pub wifi_password: Option<String>,
-    pub board_type: BoardType,
-    pub mode: BuildMode,
-    pub expand_image: bool,
-    pub extra_size: Option<String>,
-    pub base_image_url: Option<String>,
-    pub preset_image: Option<PresetImage>,
-    pub docker_compose_content: Option<String>,
-    pub custom_script_content: Option<String>,
-    pub inline_command: Option<String>,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "lowercase")]
-pub enum BoardType {
-    RaspberryPi,
-    Jetson,
+use tokio::sync::broadcast::error::RecvError;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+
+mod cli;
+mod compression;
+mod config;
+mod credentials;
+mod devices;
+mod events;
+mod flash;
+mod jobs;
+mod logs;
+mod openapi;
+mod process;
+mod profiles;
+mod queue;
+mod recipe;
+mod secrets;
+mod watcher;
+mod workspace;
+mod writer;
+
+use config::{shell_quote, BoardType, BuildMode, FieldError, ImageConfig, PresetImage};
+use devices::Device;
+use events::{JobEvent, Phase, PhaseTracker};
+use flash::FlashRequest;
+use jobs::{BuildJob, FlashTarget, JobOutcome, JobStore};
+use logs::{LogHub, LogLine, LogStream};
+use profiles::{Profile, ProfileDefinition, ProfileStore};
+use queue::Scheduler;
+use secrets::{SecretInfo, SecretStore};
+use watcher::{DeviceEvent, DeviceWatcher};
+use workspace::Workspace;
+
+#[derive(Clone)]
+struct AppState {
+    jobs: Arc<JobStore>,
+    scheduler: Arc<Scheduler>,
+    logs: Arc<LogHub>,
+    profiles: Arc<ProfileStore>,
+    secrets: Arc<SecretStore>,
+    devices: Arc<DeviceWatcher>,
+    upload_dir: PathBuf,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "lowercase")]
-pub enum BuildMode {
-    Flash,
-    Artifact,
+/// Body of `POST /api/build` when building from a saved profile instead of
+/// a full `ImageConfig`.
+#[derive(Debug, Deserialize, ToSchema)]
+struct ProfileBuildRequest {
+    profile: String,
+    /// JSON merge patch applied on top of the profile's configuration.
+    #[serde(default)]
+    overrides: Option<serde_json::Value>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum PresetImage {
-    RaspberryPiLite,
-    RadxaDesktop,
-    RadxaServer,
+#[derive(Debug, Deserialize, ToSchema)]
+struct CreateProfileRequest {
+    name: String,
+    #[serde(flatten)]
+    definition: ProfileDefinition,
 }
 
-#[derive(Debug, Serialize)]
-pub struct Device {
-    pub name: String,
-    pub path: String,
-    pub size: String,
-    pub removable: bool,
+// Not `Debug`, so the value cannot end up in a log by accident.
+#[derive(Deserialize, ToSchema)]
+struct CreateSecretRequest {
+    name: String,
+    value: String,
 }
 
-#[derive(Debug, Serialize, Clone)]
-pub struct BuildJob {
-    pub id: String,
-    pub status: JobStatus,
-    pub created_at: String,
+#[derive(Deserialize, ToSchema)]
+struct UpdateSecretRequest {
+    value: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct LogParams {
+    /// Index of the first line to return.
+    #[serde(default)]
+    offset: usize,
+    /// Maximum number of lines; all remaining lines when omitted.
+    limit: Option<usize>,
+    #[serde(default)]
+    #[param(inline)]
+    format: LogFormat,
+}
+
+#[derive(Debug, Default, Deserialize, ToSchema)]
 #[serde(rename_all = "lowercase")]
-pub enum JobStatus {
-    Running,
-    Success,
-    Failed,
-    Cancelled,
+enum LogFormat {
+    #[default]
+    Json,
+    Text,
 }
 
-#[derive(Clone)]
-struct AppState {
-    jobs: Arc<Mutex<Vec<BuildJob>>>,
-    upload_dir: PathBuf,
+/// One page of a job log, as returned by `GET /api/jobs/{id}/log`.
+#[derive(Debug, Serialize, ToSchema)]
+struct LogPage {
+    job_id: String,
+    offset: usize,
+    /// Offset to request next to continue after this page.
+    next_offset: usize,
+    /// Lines in the whole log so far.
+    total: usize,
+    lines: Vec<LogLine>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct ImageList {
+    /// Newest first.
+    images: Vec<ImageFile>,
+    storage_path: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct ImageFile {
+    name: String,
+    path: String,
+    size_mb: u64,
+    /// Modification time in seconds since the Unix epoch.
+    modified: Option<u64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct UploadResponse {
+    path: String,
+    message: String,
+}
+
+/// Body of every error response.
+#[derive(Debug, Serialize, ToSchema)]
+struct ErrorResponse {
+    error: String,
+    /// Rejected fields, for configurations that failed validation.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    fields: Vec<FieldError>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SubmitParams {
+    /// Higher priorities leave the queue first.
+    #[serde(default)]
+    priority: i32,
+}
+
+/// Root of persistent storage, `$IMGFORGE_HOME` or `~/.imgforge`.
+fn imgforge_home() -> PathBuf {
+    std::env::var("IMGFORGE_HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| {
+            let home = std::env::var("HOME").expect("HOME environment variable not set");
+            PathBuf::from(home).join(".imgforge")
+        })
+}
+
+/// Moves sealed secrets out of job records written before they were kept in
+/// the secret store.
+async fn mask_job_records(jobs: &JobStore, secrets: &SecretStore) {
+    for job in jobs.list().await {
+        let Some(config) = &job.config else {
+            continue;
+        };
+        let masked = config.redacted();
+        if masked.root_password == config.root_password
+            && masked.wifi_password == config.wifi_password
+        {
+            continue;
+        }
+        match secrets.keep_job_secrets(&job.id, config) {
+            Ok(()) => {
+                jobs.update(&job.id, |job| job.config = Some(masked)).await;
+            }
+            Err(e) => warn!("Failed to move secrets out of job {}: {}", job.id, e),
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("build") {
+        // Keep stdout for the build log itself.
+        tracing_subscriber::fmt()
+            .with_target(false)
+            .compact()
+            .with_writer(std::io::stderr)
+            .init();
+        std::process::exit(cli::build(&args[2..]).await);
+    }
+
     tracing_subscriber::fmt()
         .with_target(false)
         .compact()
@@ -110,35 +224,74 @@ async fn main() {
     info!("Starting imgforge backend server...");
 
     // Use ~/.imgforge for persistent storage
-    let imgforge_home = std::env::var("IMGFORGE_HOME")
-        .unwrap_or_else(|_| {
-            let home = std::env::var("HOME").expect("HOME environment variable not set");
-            format!("{}/.imgforge", home)
-        });
-
-    let imgforge_path = PathBuf::from(&imgforge_home);
+    let imgforge_path = imgforge_home();
     fs::create_dir_all(&imgforge_path).expect("Failed to create imgforge home directory");
     fs::create_dir_all(imgforge_path.join("images")).expect("Failed to create images directory");
     fs::create_dir_all(imgforge_path.join("configs")).expect("Failed to create configs directory");
+    fs::create_dir_all(imgforge_path.join("logs")).expect("Failed to create logs directory");
 
     let upload_dir = PathBuf::from("/tmp/imgforge-uploads");
     fs::create_dir_all(&upload_dir).expect("Failed to create upload directory");
 
+    let jobs = Arc::new(JobStore::load(imgforge_path.join("jobs")).expect("Failed to load job store"));
+    let scheduler = Arc::new(Scheduler::from_env(jobs.clone()));
+
+    let profiles = Arc::new(
+        ProfileStore::new(imgforge_path.join("profiles")).expect("Failed to create profile store"),
+    );
+    let secrets = Arc::new(SecretStore::open(&imgforge_path).expect("Failed to open secret store"));
+    mask_job_records(&jobs, &secrets).await;
+
+    let logs = Arc::new(LogHub::new(imgforge_path.join("logs")));
+    let retention_days: u64 = std::env::var("IMGFORGE_LOG_RETENTION_DAYS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(30);
+    tokio::spawn(
+        logs.clone()
+            .run_retention(Duration::from_secs(retention_days * 24 * 60 * 60)),
+    );
+
+    let devices = Arc::new(DeviceWatcher::new());
+    tokio::spawn(devices.clone().run());
+
     let state = AppState {
-        jobs: Arc::new(Mutex::new(Vec::new())),
+        jobs,
+        scheduler,
+        logs,
+        profiles,
+        secrets,
+        devices,
         upload_dir,
     };
 
     let app = Router::new()
         .route("/api/health", get(health_check))
+        .route("/api/openapi.json", get(openapi_json))
         .route("/api/devices", get(list_devices))
         .route("/api/images", get(list_images))
         .route("/api/wifi-devices", get(list_wifi_devices))
         .route("/api/build", post(create_build))
         .route("/api/flash", post(flash_device))
+        .route("/api/profiles", get(list_profiles).post(create_profile))
+        .route(
+            "/api/profiles/:name",
+            get(get_profile).put(update_profile).delete(delete_profile),
+        )
+        .route("/api/profiles/:name/resolved", get(get_resolved_profile))
+        .route("/api/secrets", get(list_secrets).post(create_secret))
+        .route(
+            "/api/secrets/:name",
+            get(get_secret).put(update_secret).delete(delete_secret),
+        )
         .route("/api/jobs", get(list_jobs))
         .route("/api/jobs/:id", get(get_job))
+        .route("/api/jobs/:id/log", get(get_job_log))
+        .route("/api/jobs/:id/log.txt", get(download_job_log))
+        .route("/api/jobs/:id/cancel", post(cancel_job))
+        .route("/api/jobs/:id/rebuild", post(rebuild_job))
         .route("/api/upload", post(upload_file))
+        .route("/api/ws/devices", get(devices_ws_handler))
         .route("/api/ws/:job_id", get(ws_handler))
         .nest_service("/", ServeDir::new("/app/frontend"))
         .layer(CorsLayer::permissive())
@@ -159,6 +312,12 @@ async fn main() {
         .expect("Server failed to start");
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/health",
+    tag = "system",
+    responses((status = 200, description = "Backend is up", body = Object))
+)]
 async fn health_check() -> Json<serde_json::Value> {
     Json(serde_json::json!({
         "status": "ok",
@@ -166,34 +325,34 @@ async fn health_check() -> Json<serde_json::Value> {
     }))
 }
 
-async fn list_devices() -> Result<Json<Vec<Device>>, AppError> {
-    let output = Command::new("lsblk")
-        .args(["-ndo", "NAME,SIZE,TYPE,HOTPLUG"])
-        .output()
-        .await
-        .map_err(|e| AppError::Internal(format!("Failed to list devices: {}", e)))?;
-
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let devices: Vec<Device> = stdout
-        .lines()
-        .filter_map(|line| {
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.len() >= 4 && parts[2] == "disk" && parts[3] == "1" {
-                Some(Device {
-                    name: parts[0].to_string(),
-                    path: format!("/dev/{}", parts[0]),
-                    size: parts[1].to_string(),
-                    removable: true,
-                })
-            } else {
-                None
-            }
-        })
-        .collect();
+/// OpenAPI 3 description of this API.
+async fn openapi_json() -> Json<utoipa::openapi::OpenApi> {
+    Json(openapi::ApiDoc::openapi())
+}
 
-    Ok(Json(devices))
+/// Removable disks that can be flashed, as last seen by the device watcher.
+#[utoipa::path(
+    get,
+    path = "/api/devices",
+    tag = "devices",
+    responses(
+        (status = 200, body = Vec<Device>),
+    )
+)]
+async fn list_devices(State(state): State<AppState>) -> Json<Vec<Device>> {
+    Json(state.devices.list().await)
 }
 
+/// Wi-Fi networks known to NetworkManager on the host.
+#[utoipa::path(
+    get,
+    path = "/api/wifi-devices",
+    tag = "devices",
+    responses(
+        (status = 200, body = Vec<String>),
+        (status = 500, body = ErrorResponse),
+    )
+)]
 async fn list_wifi_devices() -> Result<Json<Vec<String>>, AppError> {
     let output = Command::new("bash")
         .args(["-c", "grep -r '^ssid=' /etc/NetworkManager/system-connections/ 2>/dev/null | cut -d= -f2"])
@@ -217,19 +376,26 @@ async fn list_wifi_devices() -> Result<Json<Vec<String>>, AppError> {
     Ok(Json(wifi_devices))
 }
 
-async fn list_images() -> Result<Json<serde_json::Value>, AppError> {
-    let imgforge_home = std::env::var("IMGFORGE_HOME")
-        .unwrap_or_else(|_| {
-            let home = std::env::var("HOME").expect("HOME environment variable not set");
-            format!("{}/.imgforge", home)
-        });
-
-    let images_dir = PathBuf::from(&imgforge_home).join("images");
+/// File names listed as images; compressed ones are decompressed while
+/// flashing.
+const IMAGE_EXTENSIONS: [&str; 5] = [".img", ".img.xz", ".img.gz", ".img.zst", ".zip"];
+
+/// Images built in artifact mode.
+#[utoipa::path(
+    get,
+    path = "/api/images",
+    tag = "images",
+    responses((status = 200, body = ImageList))
+)]
+async fn list_images() -> Result<Json<ImageList>, AppError> {
+    let images_dir = imgforge_home().join("images");
+    let storage_path = images_dir.to_string_lossy().to_string();
 
     if !images_dir.exists() {
-        return Ok(Json(serde_json::json!({
-            "images": []
-        })));
+        return Ok(Json(ImageList {
+            images: Vec::new(),
+            storage_path,
+        }));
     }
 
     let mut images = Vec::new();
@@ -239,19 +405,19 @@ async fn list_images() -> Result<Json<serde_json::Value>, AppError> {
             if let Ok(metadata) = entry.metadata() {
                 if metadata.is_file() {
                     if let Some(filename) = entry.file_name().to_str() {
-                        if filename.ends_with(".img") || filename.ends_with(".img.xz") {
+                        if IMAGE_EXTENSIONS.iter().any(|ext| filename.ends_with(ext)) {
                             let size_mb = metadata.len() / 1024 / 1024;
                             let modified = metadata.modified()
                                 .ok()
                                 .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
                                 .map(|d| d.as_secs());
 
-                            images.push(serde_json::json!({
-                                "name": filename,
-                                "path": entry.path().to_string_lossy().to_string(),
-                                "size_mb": size_mb,
-                                "modified": modified,
-                            }));
+                            images.push(ImageFile {
+                                name: filename.to_string(),
+                                path: entry.path().to_string_lossy().to_string(),
+                                size_mb,
+                                modified,
+                            });
                         }
                     }
                 }
@@ -261,91 +427,580 @@ async fn list_images() -> Result<Json<serde_json::Value>, AppError> {
 
     // Sort by modified time (newest first)
     images.sort_by(|a, b| {
-        let a_time = a["modified"].as_u64().unwrap_or(0);
-        let b_time = b["modified"].as_u64().unwrap_or(0);
+        let a_time = a.modified.unwrap_or(0);
+        let b_time = b.modified.unwrap_or(0);
         b_time.cmp(&a_time)
     });
 
-    Ok(Json(serde_json::json!({
-        "images": images,
-        "storage_path": images_dir.to_string_lossy().to_string(),
-    })))
+    Ok(Json(ImageList {
+        images,
+        storage_path,
+    }))
 }
 
+/// Queues a build from a full `ImageConfig` or from a saved profile.
+#[utoipa::path(
+    post,
+    path = "/api/build",
+    tag = "jobs",
+    params(SubmitParams),
+    request_body = openapi::BuildRequest,
+    responses(
+        (status = 200, description = "Build queued", body = BuildJob),
+        (status = 400, description = "Invalid configuration or unknown profile or secret", body = ErrorResponse),
+        (status = 404, description = "Profile not found", body = ErrorResponse),
+    )
+)]
 async fn create_build(
     State(state): State<AppState>,
-    Json(config): Json<ImageConfig>,
+    Query(params): Query<SubmitParams>,
+    Json(body): Json<serde_json::Value>,
 ) -> Result<Json<BuildJob>, AppError> {
-    let job_id = Uuid::new_v4().to_string();
+    let (mut config, profile) = if body.get("profile").is_some() {
+        let request: ProfileBuildRequest = serde_json::from_value(body)
+            .map_err(|e| AppError::BadRequest(format!("Invalid build request: {}", e)))?;
+        let profile = state.profiles.resolve(&request.profile)?;
+        let config = match &request.overrides {
+            Some(overrides) => profile.with_overrides(overrides)?,
+            None => profile,
+        };
+        (config, Some(request.profile))
+    } else {
+        let config: ImageConfig = serde_json::from_value(body)
+            .map_err(|e| AppError::BadRequest(format!("Invalid build configuration: {}", e)))?;
+        (config, None)
+    };
+    config.validate()?;
+    state.secrets.check_references(&config)?;
+    let secrets = config.seal()?;
+
     let job = BuildJob {
-        id: job_id.clone(),
-        status: JobStatus::Running,
-        created_at: chrono::Utc::now().to_rfc3339(),
+        priority: params.priority,
+        profile,
+        ..BuildJob::build(&config)
     };
+    submit_build(&state, job, config, secrets).await
+}
 
-    state.jobs.lock().await.push(job.clone());
+/// Re-runs a previous build with its stored `ImageConfig`, optionally
+/// adjusted by a JSON merge patch in the request body.
+#[utoipa::path(
+    post,
+    path = "/api/jobs/{id}/rebuild",
+    tag = "jobs",
+    params(("id" = String, Path, description = "Job to re-run"), SubmitParams),
+    request_body(content = Option<Object>, description = "JSON merge patch (RFC 7386) for the stored `ImageConfig`"),
+    responses(
+        (status = 200, description = "Build queued", body = BuildJob),
+        (status = 400, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+async fn rebuild_job(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(params): Query<SubmitParams>,
+    body: Bytes,
+) -> Result<Json<BuildJob>, AppError> {
+    let previous = state
+        .jobs
+        .get(&id)
+        .await
+        .ok_or_else(|| AppError::NotFound(format!("Job {} not found", id)))?;
+    let mut config = previous.config.ok_or_else(|| {
+        AppError::BadRequest(format!("Job {} has no stored build configuration", id))
+    })?;
+    state.secrets.restore_job_secrets(&id, &mut config)?;
+
+    let mut config = if body.is_empty() {
+        config
+    } else {
+        let overrides: serde_json::Value = serde_json::from_slice(&body)
+            .map_err(|e| AppError::BadRequest(format!("Invalid overrides: {}", e)))?;
+        config.with_overrides(&overrides)?
+    };
+    config.validate()?;
+    state.secrets.check_references(&config)?;
+    let secrets = config.seal()?;
 
-    tokio::spawn(async move {
-        if let Err(e) = run_build(job_id, config).await {
-            error!("Build failed: {}", e);
-        }
-    });
+    let job = BuildJob {
+        priority: params.priority,
+        profile: previous.profile,
+        rebuilt_from: Some(id),
+        ..BuildJob::build(&config)
+    };
+    submit_build(&state, job, config, secrets).await
+}
 
-    Ok(Json(job))
+async fn submit_build(
+    state: &AppState,
+    job: BuildJob,
+    config: ImageConfig,
+    secrets: Vec<String>,
+) -> Result<Json<BuildJob>, AppError> {
+    let priority = job.priority;
+    let job_id = job.id.clone();
+    let kind = job.kind.clone();
+
+    state.secrets.keep_job_secrets(&job_id, &config)?;
+    let cancel = state.jobs.insert(job).await;
+    state.logs.open(&job_id).await;
+    state.logs.redact(&job_id, secrets).await;
+
+    let jobs = state.jobs.clone();
+    let logs = state.logs.clone();
+    let store = state.secrets.clone();
+    let id = job_id.clone();
+    let task = async move {
+        let images = imgforge_home().join("images");
+        let result = run_build(id.clone(), config, &store, images, logs.clone(), cancel).await;
+        let (outcome, exit_code) = match result {
+            Ok(status) if status.success() => (JobOutcome::Success, status.code()),
+            Ok(status) => (
+                JobOutcome::Failed(format!("Process exited with {}", status)),
+                status.code(),
+            ),
+            Err(e) => {
+                error!("Build failed: {}", e);
+                (JobOutcome::Failed(e.to_string()), None)
+            }
+        };
+        jobs.finish(&id, outcome, exit_code).await;
+        logs.close(&id).await;
+    };
+    state.scheduler.submit(&kind, &job_id, priority, task).await;
+
+    submitted_job(state, &job_id).await
 }
 
+/// Queues writing an image to a device.
+#[utoipa::path(
+    post,
+    path = "/api/flash",
+    tag = "jobs",
+    params(SubmitParams),
+    request_body = FlashRequest,
+    responses(
+        (status = 200, description = "Flash queued", body = BuildJob),
+        (status = 400, description = "Invalid request; nothing was queued", body = ErrorResponse),
+    )
+)]
 async fn flash_device(
     State(state): State<AppState>,
+    Query(params): Query<SubmitParams>,
     Json(payload): Json<serde_json::Value>,
 ) -> Result<Json<BuildJob>, AppError> {
-    let job_id = Uuid::new_v4().to_string();
+    let request: FlashRequest = serde_json::from_value(payload)
+        .map_err(|e| AppError::BadRequest(format!("Invalid flash request: {}", e)))?;
+    let target = request.validate(&imgforge_home().join("images"))?;
+
     let job = BuildJob {
-        id: job_id.clone(),
-        status: JobStatus::Running,
-        created_at: chrono::Utc::now().to_rfc3339(),
+        priority: params.priority,
+        ..BuildJob::flash(target.clone())
+    };
+    let job_id = job.id.clone();
+    let kind = job.kind.clone();
+
+    let cancel = state.jobs.insert(job).await;
+    state.logs.open(&job_id).await;
+
+    let jobs = state.jobs.clone();
+    let logs = state.logs.clone();
+    let id = job_id.clone();
+    let task = async move {
+        let result = run_flash(id.clone(), target, jobs.clone(), logs.clone(), cancel).await;
+        let outcome = match result {
+            Ok(()) => JobOutcome::Success,
+            Err(e) => {
+                error!("Flash failed: {}", e);
+                JobOutcome::Failed(e.to_string())
+            }
+        };
+        jobs.finish(&id, outcome, None).await;
+        logs.close(&id).await;
     };
+    state.scheduler.submit(&kind, &job_id, params.priority, task).await;
 
-    state.jobs.lock().await.push(job.clone());
+    submitted_job(&state, &job_id).await
+}
 
-    let image_path = payload["image_path"]
-        .as_str()
-        .ok_or_else(|| AppError::BadRequest("Missing image_path".to_string()))?
-        .to_string();
-    let device = payload["device"]
-        .as_str()
-        .ok_or_else(|| AppError::BadRequest("Missing device".to_string()))?
-        .to_string();
+/// Current view of a just-submitted job, including its queue position.
+async fn submitted_job(state: &AppState, job_id: &str) -> Result<Json<BuildJob>, AppError> {
+    state
+        .jobs
+        .get(job_id)
+        .await
+        .map(|job| Json(job.redacted()))
+        .ok_or_else(|| AppError::Internal(format!("Job {} disappeared after submission", job_id)))
+}
 
-    tokio::spawn(async move {
-        if let Err(e) = run_flash(job_id, image_path, device).await {
-            error!("Flash failed: {}", e);
-        }
-    });
+#[utoipa::path(
+    get,
+    path = "/api/profiles",
+    tag = "profiles",
+    responses((status = 200, body = Vec<Profile>))
+)]
+async fn list_profiles(State(state): State<AppState>) -> Result<Json<Vec<Profile>>, AppError> {
+    let profiles = state.profiles.list()?;
+    Ok(Json(profiles.into_iter().map(Profile::redacted).collect()))
+}
 
-    Ok(Json(job))
+#[utoipa::path(
+    post,
+    path = "/api/profiles",
+    tag = "profiles",
+    request_body = CreateProfileRequest,
+    responses(
+        (status = 201, body = Profile),
+        (status = 400, body = ErrorResponse),
+        (status = 409, description = "Profile already exists", body = ErrorResponse),
+    )
+)]
+async fn create_profile(
+    State(state): State<AppState>,
+    Json(request): Json<CreateProfileRequest>,
+) -> Result<(StatusCode, Json<Profile>), AppError> {
+    let definition = state.profiles.prepare(&request.name, request.definition)?;
+    let profile = state.profiles.create(&request.name, definition).await?;
+    info!("Created profile {}", profile.name);
+    Ok((StatusCode::CREATED, Json(profile.redacted())))
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/profiles/{name}",
+    tag = "profiles",
+    params(("name" = String, Path)),
+    responses(
+        (status = 200, body = Profile),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+async fn get_profile(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<Profile>, AppError> {
+    state.profiles.get(&name).map(|p| Json(p.redacted()))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/profiles/{name}",
+    tag = "profiles",
+    params(("name" = String, Path)),
+    request_body = ProfileDefinition,
+    responses(
+        (status = 200, body = Profile),
+        (status = 400, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+async fn update_profile(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Json(definition): Json<ProfileDefinition>,
+) -> Result<Json<Profile>, AppError> {
+    let definition = state.profiles.prepare(&name, definition)?;
+    let profile = state.profiles.update(&name, definition).await?;
+    Ok(Json(profile.redacted()))
+}
+
+/// Effective configuration of a profile after `extend` and `include`s.
+#[utoipa::path(
+    get,
+    path = "/api/profiles/{name}/resolved",
+    tag = "profiles",
+    params(("name" = String, Path)),
+    responses(
+        (status = 200, body = ImageConfig),
+        (status = 400, description = "Profile is incomplete or has a cycle", body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+async fn get_resolved_profile(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<ImageConfig>, AppError> {
+    state.profiles.resolve(&name).map(|c| Json(c.redacted()))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/profiles/{name}",
+    tag = "profiles",
+    params(("name" = String, Path)),
+    responses(
+        (status = 204, description = "Profile deleted"),
+        (status = 404, body = ErrorResponse),
+        (status = 409, description = "Another profile extends or includes it", body = ErrorResponse),
+    )
+)]
+async fn delete_profile(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, AppError> {
+    state.profiles.delete(&name).await?;
+    info!("Deleted profile {}", name);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/secrets",
+    tag = "secrets",
+    responses((status = 200, body = Vec<SecretInfo>))
+)]
+async fn list_secrets(State(state): State<AppState>) -> Result<Json<Vec<SecretInfo>>, AppError> {
+    state.secrets.list().map(Json)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/secrets",
+    tag = "secrets",
+    request_body = CreateSecretRequest,
+    responses(
+        (status = 201, body = SecretInfo),
+        (status = 400, body = ErrorResponse),
+        (status = 409, description = "Secret already exists", body = ErrorResponse),
+    )
+)]
+async fn create_secret(
+    State(state): State<AppState>,
+    Json(request): Json<CreateSecretRequest>,
+) -> Result<(StatusCode, Json<SecretInfo>), AppError> {
+    let secret = state.secrets.create(&request.name, &request.value).await?;
+    info!("Created secret {}", secret.name);
+    Ok((StatusCode::CREATED, Json(secret)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/secrets/{name}",
+    tag = "secrets",
+    params(("name" = String, Path)),
+    responses(
+        (status = 200, body = SecretInfo),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+async fn get_secret(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<SecretInfo>, AppError> {
+    state.secrets.get(&name).map(Json)
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/secrets/{name}",
+    tag = "secrets",
+    params(("name" = String, Path)),
+    request_body = UpdateSecretRequest,
+    responses(
+        (status = 200, body = SecretInfo),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+async fn update_secret(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Json(request): Json<UpdateSecretRequest>,
+) -> Result<Json<SecretInfo>, AppError> {
+    let secret = state.secrets.update(&name, &request.value).await?;
+    info!("Updated secret {}", name);
+    Ok(Json(secret))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/secrets/{name}",
+    tag = "secrets",
+    params(("name" = String, Path)),
+    responses(
+        (status = 204, description = "Secret deleted"),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+async fn delete_secret(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, AppError> {
+    state.secrets.delete(&name).await?;
+    info!("Deleted secret {}", name);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/jobs",
+    tag = "jobs",
+    responses((status = 200, body = Vec<BuildJob>))
+)]
 async fn list_jobs(State(state): State<AppState>) -> Json<Vec<BuildJob>> {
-    let jobs = state.jobs.lock().await;
-    Json(jobs.clone())
+    Json(
+        state
+            .jobs
+            .list()
+            .await
+            .into_iter()
+            .map(BuildJob::redacted)
+            .collect(),
+    )
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/jobs/{id}",
+    tag = "jobs",
+    params(("id" = String, Path)),
+    responses(
+        (status = 200, body = BuildJob),
+        (status = 404, body = ErrorResponse),
+    )
+)]
 async fn get_job(
     State(state): State<AppState>,
     Path(id): Path<String>,
 ) -> Result<Json<BuildJob>, AppError> {
-    let jobs = state.jobs.lock().await;
-    jobs.iter()
-        .find(|j| j.id == id)
-        .cloned()
-        .map(Json)
+    state
+        .jobs
+        .get(&id)
+        .await
+        .map(|job| Json(job.redacted()))
         .ok_or_else(|| AppError::NotFound(format!("Job {} not found", id)))
 }
 
+/// Returns `limit` log lines starting at line `offset`, as JSON entries or
+/// as plain text.
+#[utoipa::path(
+    get,
+    path = "/api/jobs/{id}/log",
+    tag = "jobs",
+    params(("id" = String, Path), LogParams),
+    responses(
+        (status = 200, content(
+            (LogPage = "application/json"),
+            (String = "text/plain"),
+        )),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+async fn get_job_log(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(params): Query<LogParams>,
+) -> Result<Response, AppError> {
+    if state.jobs.get(&id).await.is_none() {
+        return Err(AppError::NotFound(format!("Job {} not found", id)));
+    }
+
+    let all = state.logs.read_lines(&id, 0).await;
+    let total = all.len();
+    let page: Vec<String> = all
+        .into_iter()
+        .skip(params.offset)
+        .take(params.limit.unwrap_or(usize::MAX))
+        .collect();
+    let next_offset = params.offset.min(total) + page.len();
+
+    let response = match params.format {
+        LogFormat::Text => {
+            let mut body = page.join("\n");
+            if !body.is_empty() {
+                body.push('\n');
+            }
+            (
+                [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+                body,
+            )
+                .into_response()
+        }
+        LogFormat::Json => {
+            let lines: Vec<LogLine> = page.iter().map(|l| LogLine::parse_or_raw(l)).collect();
+            Json(LogPage {
+                job_id: id,
+                offset: params.offset,
+                next_offset,
+                total,
+                lines,
+            })
+            .into_response()
+        }
+    };
+    Ok(response)
+}
+
+/// The complete log as a file download.
+#[utoipa::path(
+    get,
+    path = "/api/jobs/{id}/log.txt",
+    tag = "jobs",
+    params(("id" = String, Path)),
+    responses(
+        (status = 200, body = String, content_type = "text/plain"),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+async fn download_job_log(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Response, AppError> {
+    if state.jobs.get(&id).await.is_none() {
+        return Err(AppError::NotFound(format!("Job {} not found", id)));
+    }
+
+    let body = tokio::fs::read(state.logs.path(&id)).await.unwrap_or_default();
+    let disposition = format!("attachment; filename=\"imgforge-{}.log\"", id);
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/plain; charset=utf-8".to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+/// Removes a queued job from the queue or stops a running one.
+#[utoipa::path(
+    post,
+    path = "/api/jobs/{id}/cancel",
+    tag = "jobs",
+    params(("id" = String, Path)),
+    responses(
+        (status = 202, description = "Cancellation requested", body = BuildJob),
+        (status = 404, body = ErrorResponse),
+        (status = 409, description = "Job already finished", body = ErrorResponse),
+    )
+)]
+async fn cancel_job(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<(StatusCode, Json<BuildJob>), AppError> {
+    let job = state.scheduler.cancel(&id).await?;
+    if job.status.is_terminal() {
+        // Dropped from the queue, so no runner will close the log.
+        state.logs.close(&id).await;
+    }
+    Ok((StatusCode::ACCEPTED, Json(job.redacted())))
+}
+
+/// Stores an uploaded file (e.g. a base image) on the backend host.
+#[utoipa::path(
+    post,
+    path = "/api/upload",
+    tag = "images",
+    request_body(content_type = "multipart/form-data", content = Object),
+    responses(
+        (status = 200, body = UploadResponse),
+        (status = 400, body = ErrorResponse),
+    )
+)]
 async fn upload_file(
     State(state): State<AppState>,
     mut multipart: Multipart,
-) -> Result<Json<serde_json::Value>, AppError> {
+) -> Result<Json<UploadResponse>, AppError> {
     let mut file_path = None;
 
     while let Some(field) = multipart
@@ -370,155 +1025,279 @@ async fn upload_file(
 
     file_path
         .map(|path| {
-            Json(serde_json::json!({
-                "path": path,
-                "message": "File uploaded successfully"
-            }))
+            Json(UploadResponse {
+                path,
+                message: "File uploaded successfully".to_string(),
+            })
         })
         .ok_or_else(|| AppError::BadRequest("No file provided".to_string()))
 }
 
+/// Live job log over a WebSocket: each stored line as text, event markers
+/// as JSON, and a final `{"type": "status", ...}` message.
+#[utoipa::path(
+    get,
+    path = "/api/ws/{job_id}",
+    tag = "jobs",
+    params(("job_id" = String, Path)),
+    responses(
+        (status = 101, description = "Switching to the WebSocket protocol"),
+        (status = 404, body = ErrorResponse),
+    )
+)]
 async fn ws_handler(
     ws: WebSocketUpgrade,
+    State(state): State<AppState>,
     Path(job_id): Path<String>,
-) -> Response {
-    ws.on_upgrade(|socket| handle_socket(socket, job_id))
+) -> Result<Response, AppError> {
+    if state.jobs.get(&job_id).await.is_none() {
+        return Err(AppError::NotFound(format!("Job {} not found", job_id)));
+    }
+    Ok(ws.on_upgrade(|socket| handle_socket(socket, state, job_id)))
 }
 
-async fn handle_socket(mut socket: WebSocket, job_id: String) {
+/// Replays the job log, follows it live until the job finishes, then sends a
+/// final `{"type": "status", ...}` message and closes.
+async fn handle_socket(mut socket: WebSocket, state: AppState, job_id: String) {
     info!("WebSocket connected for job: {}", job_id);
 
-    let log_file = format!("/tmp/imgforge-{}.log", job_id);
+    let mut log = state.logs.follow(&job_id).await;
+    while let Some(line) = log.next().await {
+        if socket.send(log_message(line)).await.is_err() {
+            info!("WebSocket for job {} disconnected", job_id);
+            return;
+        }
+    }
 
-    if let Ok(file) = tokio::fs::File::open(&log_file).await {
-        let reader = BufReader::new(file);
-        let mut lines = reader.lines();
+    if let Some(job) = state.jobs.get(&job_id).await {
+        let status = serde_json::json!({
+            "type": "status",
+            "status": job.status,
+            "exit_code": job.exit_code,
+            "error": job.error,
+        });
+        let _ = socket.send(Message::Text(status.to_string())).await;
+    }
 
-        while let Ok(Some(line)) = lines.next_line().await {
-            if socket
-                .send(Message::Text(line))
-                .await
-                .is_err()
-            {
-                break;
+    let _ = socket.send(Message::Close(None)).await;
+}
+
+/// Log lines go out as stored (`<timestamp> <stream> <text>`); event
+/// markers become JSON objects.
+fn log_message(line: String) -> Message {
+    let event = LogLine::parse(&line).and_then(|entry| JobEvent::parse(&entry.text));
+    match event {
+        Some(event) => Message::Text(serde_json::to_string(&event).unwrap_or(line)),
+        None => Message::Text(line),
+    }
+}
+
+/// Live device list over a WebSocket: a `{"type": "devices", ...}` message
+/// with every flashable device, then `added`, `removed` and `changed` events
+/// as cards come and go.
+#[utoipa::path(
+    get,
+    path = "/api/ws/devices",
+    tag = "devices",
+    responses((status = 101, description = "Switching to the WebSocket protocol"))
+)]
+async fn devices_ws_handler(ws: WebSocketUpgrade, State(state): State<AppState>) -> Response {
+    ws.on_upgrade(|socket| handle_devices_socket(socket, state.devices))
+}
+
+async fn handle_devices_socket(mut socket: WebSocket, watcher: Arc<DeviceWatcher>) {
+    let (devices, mut rx) = watcher.subscribe().await;
+    if send_device_event(&mut socket, &DeviceEvent::Devices { devices })
+        .await
+        .is_err()
+    {
+        return;
+    }
+
+    loop {
+        tokio::select! {
+            event = rx.recv() => {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(_)) => {
+                        // Start over from a fresh list.
+                        let (devices, fresh) = watcher.subscribe().await;
+                        rx = fresh;
+                        DeviceEvent::Devices { devices }
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                if send_device_event(&mut socket, &event).await.is_err() {
+                    break;
+                }
             }
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
         }
     }
+}
 
-    let _ = socket.send(Message::Close(None)).await;
+async fn send_device_event(socket: &mut WebSocket, event: &DeviceEvent) -> Result<(), axum::Error> {
+    let text = serde_json::to_string(event).unwrap_or_default();
+    socket.send(Message::Text(text)).await
 }
 
-async fn run_build(job_id: String, config: ImageConfig) -> Result<(), AppError> {
+/// Runs `imgforge.sh` for one build; artifact builds store their image in
+/// `output_dir`. Secret references are resolved here, so their values only
+/// ever exist in the job's workspace and never in the job record.
+async fn run_build(
+    job_id: String,
+    config: ImageConfig,
+    secrets: &SecretStore,
+    output_dir: PathBuf,
+    logs: Arc<LogHub>,
+    cancel: CancellationToken,
+) -> Result<ExitStatus, AppError> {
     info!("Starting build job: {}", job_id);
 
-    let env_file = format!("/tmp/imgforge-{}.env", job_id);
-    let log_file = format!("/tmp/imgforge-{}.log", job_id);
+    // Plain values were already sealed when submitted through the API;
+    // recipes and resolved references arrive in plaintext.
+    let mut config = config;
+    secrets.resolve(&mut config)?;
+    config.validate()?;
+    logs.redact(&job_id, config.seal()?).await;
+
+    let workspace = Workspace::create(&imgforge_home().join("workspaces"), &job_id)
+        .map_err(|e| AppError::Internal(format!("Failed to create workspace: {}", e)))?;
+
+    let result = build_in_workspace(&job_id, config, &workspace, &output_dir, logs, &cancel).await;
+    workspace.cleanup().await;
+    result
+}
+
+/// Appends `KEY='value'` to an env file that `imgforge.sh` sources, quoting
+/// the value so it cannot break out of the assignment.
+fn push_env(env: &mut String, key: &str, value: impl std::fmt::Display) {
+    env.push_str(&format!("{}={}\n", key, shell_quote(&value.to_string())));
+}
+
+/// Copy of an env file for the job record, with secret values masked.
+fn redact_env(env: &str) -> String {
+    env.lines()
+        .map(|line| match line.split_once('=') {
+            Some((key @ ("ROOTPW_HASH" | "WIFI_PSK"), _)) => {
+                format!("{}={}\n", key, shell_quote(credentials::MASK))
+            }
+            _ => format!("{}\n", line),
+        })
+        .collect()
+}
 
-    let mut env_content = String::new();
-    env_content.push_str(&format!("HOSTNAME={}\n", config.hostname));
-    env_content.push_str(&format!(
-        "CHANGE_USERNAME={}\n",
-        if config.change_username { "y" } else { "n" }
-    ));
+fn yes_no(flag: bool) -> &'static str {
+    if flag {
+        "y"
+    } else {
+        "n"
+    }
+}
+
+async fn build_in_workspace(
+    job_id: &str,
+    config: ImageConfig,
+    workspace: &Workspace,
+    output_dir: &std::path::Path,
+    logs: Arc<LogHub>,
+    cancel: &CancellationToken,
+) -> Result<ExitStatus, AppError> {
+    let mut env = String::new();
+    push_env(&mut env, "HOSTNAME", &config.hostname);
+    push_env(&mut env, "CHANGE_USERNAME", yes_no(config.change_username));
     if let Some(username) = config.new_username {
-        env_content.push_str(&format!("NEW_USERNAME={}\n", username));
+        push_env(&mut env, "NEW_USERNAME", username);
     }
-    env_content.push_str(&format!(
-        "SET_ROOTPW={}\n",
-        if config.set_root_password { "y" } else { "n" }
-    ));
-    if let Some(pw) = config.root_password {
-        env_content.push_str(&format!("ROOTPW={}\n", pw));
+    push_env(&mut env, "SET_ROOTPW", yes_no(config.set_root_password));
+    if let Some(hash) = config.root_password {
+        push_env(&mut env, "ROOTPW_HASH", hash.plain()?);
     }
-    env_content.push_str(&format!(
-        "ENABLE_SSH={}\n",
-        if config.enable_ssh { "y" } else { "n" }
-    ));
-
-    if let (Some(ssid), Some(pass)) = (config.wifi_ssid, config.wifi_password) {
-        env_content.push_str("WIFI_CHOICE=1\n");
-        env_content.push_str(&format!("WIFI_SSID={}\n", ssid));
-        env_content.push_str(&format!("WIFI_PASS={}\n", pass));
+    push_env(&mut env, "ENABLE_SSH", yes_no(config.enable_ssh));
+
+    if let (Some(ssid), Some(psk)) = (config.wifi_ssid, config.wifi_password) {
+        push_env(&mut env, "WIFI_CHOICE", "1");
+        push_env(&mut env, "WIFI_SSID", ssid);
+        push_env(&mut env, "WIFI_PSK", psk.plain()?);
     } else {
-        env_content.push_str("WIFI_CHOICE=3\n");
+        push_env(&mut env, "WIFI_CHOICE", "3");
     }
 
-    env_content.push_str(&format!(
-        "BOARD={}\n",
+    push_env(
+        &mut env,
+        "BOARD",
         match config.board_type {
             BoardType::RaspberryPi => "1",
             BoardType::Jetson => "2",
-        }
-    ));
+        },
+    );
 
     if let Some(preset) = config.preset_image {
-        env_content.push_str("HAVE_IMG=n\n");
-        env_content.push_str(&format!(
-            "IMG_CHOICE={}\n",
+        push_env(&mut env, "HAVE_IMG", "n");
+        push_env(
+            &mut env,
+            "IMG_CHOICE",
             match preset {
                 PresetImage::RaspberryPiLite => "1",
                 PresetImage::RadxaDesktop => "2",
                 PresetImage::RadxaServer => "3",
-            }
-        ));
+            },
+        );
     } else if let Some(url) = config.base_image_url {
-        env_content.push_str("HAVE_IMG=y\n");
-        env_content.push_str(&format!("BASE_IMG={}\n", url));
+        push_env(&mut env, "HAVE_IMG", "y");
+        push_env(&mut env, "BASE_IMG", url);
     }
 
-    env_content.push_str(&format!(
-        "EXPAND_IMG={}\n",
-        if config.expand_image { "y" } else { "n" }
-    ));
+    push_env(&mut env, "EXPAND_IMG", yes_no(config.expand_image));
     if let Some(size) = config.extra_size {
-        env_content.push_str(&format!("EXTRA_SIZE={}\n", size));
+        push_env(&mut env, "EXTRA_SIZE", size);
     }
 
     if let Some(compose) = config.docker_compose_content {
-        let compose_path = format!("/tmp/imgforge-{}-compose.yml", job_id);
+        let compose_path = workspace.compose_file();
         fs::write(&compose_path, compose)
             .map_err(|e| AppError::Internal(format!("Failed to write compose file: {}", e)))?;
-        env_content.push_str("HAVE_COMPOSE=y\n");
-        env_content.push_str(&format!("COMPOSE_FILE={}\n", compose_path));
+        push_env(&mut env, "HAVE_COMPOSE", "y");
+        push_env(&mut env, "COMPOSE_FILE", compose_path.display());
     } else {
-        env_content.push_str("HAVE_COMPOSE=n\n");
+        push_env(&mut env, "HAVE_COMPOSE", "n");
     }
 
     if let Some(script) = config.custom_script_content {
-        let script_path = format!("/tmp/imgforge-{}-script.sh", job_id);
+        let script_path = workspace.script_file();
         fs::write(&script_path, script)
             .map_err(|e| AppError::Internal(format!("Failed to write script: {}", e)))?;
-        env_content.push_str("HAVE_SCRIPT=y\n");
-        env_content.push_str("SCRIPT_TYPE=1\n");
-        env_content.push_str(&format!("CUSTOM_SCRIPT={}\n", script_path));
+        push_env(&mut env, "HAVE_SCRIPT", "y");
+        push_env(&mut env, "SCRIPT_TYPE", "1");
+        push_env(&mut env, "CUSTOM_SCRIPT", script_path.display());
     } else if let Some(cmd) = config.inline_command {
-        env_content.push_str("HAVE_SCRIPT=y\n");
-        env_content.push_str("SCRIPT_TYPE=2\n");
-        env_content.push_str(&format!("INLINE_COMMAND={}\n", cmd));
+        push_env(&mut env, "HAVE_SCRIPT", "y");
+        push_env(&mut env, "SCRIPT_TYPE", "2");
+        push_env(&mut env, "INLINE_COMMAND", cmd);
     } else {
-        env_content.push_str("HAVE_SCRIPT=n\n");
+        push_env(&mut env, "HAVE_SCRIPT", "n");
     }
 
-    env_content.push_str("SKIP_WIZARD=y\n");
+    push_env(&mut env, "SKIP_WIZARD", "y");
 
-    fs::write(&env_file, &env_content)
+    fs::write(workspace.env_file(), &env)
         .map_err(|e| AppError::Internal(format!("Failed to write env file: {}", e)))?;
 
-    // Save config to ~/.imgforge/configs
-    let imgforge_home = std::env::var("IMGFORGE_HOME")
-        .unwrap_or_else(|_| {
-            let home = std::env::var("HOME").expect("HOME environment variable not set");
-            format!("{}/.imgforge", home)
-        });
-    let config_path = PathBuf::from(&imgforge_home).join("configs").join(format!("{}.env", job_id));
-    fs::write(&config_path, &env_content)
+    // Save config to ~/.imgforge/configs, which headless builds may be the
+    // first to use.
+    let configs_dir = imgforge_home().join("configs");
+    fs::create_dir_all(&configs_dir)
+        .map_err(|e| AppError::Internal(format!("Failed to create configs directory: {}", e)))?;
+    let config_path = configs_dir.join(format!("{}.env", job_id));
+    fs::write(&config_path, redact_env(&env))
         .map_err(|e| AppError::Internal(format!("Failed to write config: {}", e)))?;
 
-    fs::write(&format!("/workdir/last-run.env"), &env_content)
-        .map_err(|e| AppError::Internal(format!("Failed to write last-run.env: {}", e)))?;
-
-    let mut child = Command::new("/workdir/imgforge.sh")
-        .current_dir("/workdir")
+    let mut child = Command::new(workspace::build_script())
+        .current_dir(&workspace.dir)
+        .process_group(0)
         .stdin(Stdio::null())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
@@ -526,136 +1305,78 @@ async fn run_build(job_id: String, config: ImageConfig) -> Result<(), AppError>
             BuildMode::Flash => "1",
             BuildMode::Artifact => "2",
         })
+        .env("SKIP_WIZARD", "y")
+        .env("IMGFORGE_MNT", &workspace.mount_point)
         .spawn()
         .map_err(|e| AppError::Internal(format!("Failed to spawn imgforge.sh: {}", e)))?;
 
-    let stdout = child.stdout.take().unwrap();
-    let stderr = child.stderr.take().unwrap();
-
-    let log_file_clone = log_file.clone();
-    tokio::spawn(async move {
-        let mut reader = BufReader::new(stdout);
-        let mut line = String::new();
-        let mut log = tokio::fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&log_file_clone)
-            .await
-            .unwrap();
-
-        while reader.read_line(&mut line).await.unwrap() > 0 {
-            use tokio::io::AsyncWriteExt;
-            let _ = log.write_all(line.as_bytes()).await;
-            info!("[BUILD] {}", line.trim());
-            line.clear();
-        }
-    });
-
-    tokio::spawn(async move {
-        let mut reader = BufReader::new(stderr);
-        let mut line = String::new();
-        while reader.read_line(&mut line).await.unwrap() > 0 {
-            error!("[BUILD] {}", line.trim());
-            line.clear();
-        }
-    });
+    let output = process::capture_output(
+        &mut child,
+        "BUILD",
+        job_id,
+        logs.clone(),
+        PhaseTracker::default(),
+    );
 
-    let status = child
-        .wait()
+    let status = process::wait_or_cancel(&mut child, cancel)
         .await
         .map_err(|e| AppError::Internal(format!("Failed to wait for process: {}", e)))?;
+    // Drain remaining output so the log is complete before the job finishes.
+    let tracker = output.await.unwrap_or_default();
 
-    if status.success() {
+    if cancel.is_cancelled() {
+        info!("Build job {} was cancelled", job_id);
+    } else if status.success() {
         info!("Build job {} completed successfully", job_id);
 
-        // Move output image to ~/.imgforge/images if build was successful
+        // Move output image to the output directory if build was successful
         if let BuildMode::Artifact = config.mode {
-            let imgforge_home = std::env::var("IMGFORGE_HOME")
-                .unwrap_or_else(|_| {
-                    let home = std::env::var("HOME").expect("HOME environment variable not set");
-                    format!("{}/.imgforge", home)
-                });
-
-            let source = PathBuf::from("/workdir/custom.img");
-            if source.exists() {
+            if workspace.artifact().exists() {
                 let timestamp = chrono::Utc::now().format("%Y%m%d_%H%M%S");
                 let dest_name = format!("{}_{}.img", config.hostname, timestamp);
-                let dest = PathBuf::from(&imgforge_home).join("images").join(&dest_name);
-
-                if let Err(e) = fs::copy(&source, &dest) {
-                    error!("Failed to copy image to storage: {}", e);
-                } else {
-                    info!("Saved image to: {}", dest.display());
+                let dest = output_dir.join(&dest_name);
+
+                logs.event(job_id, &JobEvent::started(Phase::Store)).await;
+                if let Err(e) = workspace.collect_artifact(&dest) {
+                    error!("Failed to move image to storage: {}", e);
+                    logs.event(job_id, &JobEvent::failed(Phase::Store)).await;
+                    return Err(AppError::Internal(format!(
+                        "Failed to move image to storage: {}",
+                        e
+                    )));
                 }
+                info!("Saved image to: {}", dest.display());
+                let message = format!("Image saved to {}", dest.display());
+                logs.append(job_id, LogStream::System, &message).await;
+                logs.event(job_id, &JobEvent::finished(Phase::Store)).await;
             }
         }
     } else {
         error!("Build job {} failed with status: {}", job_id, status);
+        if let Some(phase) = tracker.current() {
+            logs.event(job_id, &JobEvent::failed(phase)).await;
+        }
     }
 
-    Ok(())
+    Ok(status)
 }
 
-async fn run_flash(job_id: String, image_path: String, device: String) -> Result<(), AppError> {
-    info!("Starting flash job: {} to {}", image_path, device);
-
-    let log_file = format!("/tmp/imgforge-{}.log", job_id);
-
-    let mut child = Command::new("dd")
-        .args([
-            &format!("if={}", image_path),
-            &format!("of={}", device),
-            "bs=4M",
-            "status=progress",
-            "conv=fsync",
-        ])
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .map_err(|e| AppError::Internal(format!("Failed to spawn dd: {}", e)))?;
-
-    let stdout = child.stdout.take().unwrap();
-    let stderr = child.stderr.take().unwrap();
-
-    let log_file_clone = log_file.clone();
-    tokio::spawn(async move {
-        let mut reader = BufReader::new(stdout);
-        let mut line = String::new();
-        let mut log = tokio::fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&log_file_clone)
-            .await
-            .unwrap();
-
-        while reader.read_line(&mut line).await.unwrap() > 0 {
-            use tokio::io::AsyncWriteExt;
-            let _ = log.write_all(line.as_bytes()).await;
-            info!("[FLASH] {}", line.trim());
-            line.clear();
-        }
-    });
-
-    tokio::spawn(async move {
-        let mut reader = BufReader::new(stderr);
-        let mut line = String::new();
-        while reader.read_line(&mut line).await.unwrap() > 0 {
-            info!("[FLASH] {}", line.trim());
-            line.clear();
-        }
-    });
-
-    let status = child
-        .wait()
-        .await
-        .map_err(|e| AppError::Internal(format!("Failed to wait for dd: {}", e)))?;
-
-    if status.success() {
-        info!("Flash job {} completed successfully", job_id);
-    } else {
-        error!("Flash job {} failed with status: {}", job_id, status);
-    }
-
+async fn run_flash(
+    job_id: String,
+    target: FlashTarget,
+    jobs: Arc<JobStore>,
+    logs: Arc<LogHub>,
+    cancel: CancellationToken,
+) -> Result<(), AppError> {
+    let devices: Vec<&str> = target.devices.iter().map(|d| d.device.as_str()).collect();
+    info!(
+        "Starting flash job: {} to {}",
+        target.image_path,
+        devices.join(", ")
+    );
+
+    flash::run(&job_id, target, jobs, logs, &cancel).await?;
+    info!("Flash job {} completed successfully", job_id);
     Ok(())
 }
 
@@ -663,22 +1384,23 @@ async fn run_flash(job_id: String, image_path: String, device: String) -> Result
 enum AppError {
     NotFound(String),
     BadRequest(String),
+    Conflict(String),
     Internal(String),
+    /// Rejected input: what was being checked, and the offending fields.
+    Validation(String, Vec<FieldError>),
 }
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        let (status, error_message) = match self {
-            AppError::NotFound(msg) => (StatusCode::NOT_FOUND, msg),
-            AppError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg),
-            AppError::Internal(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
+        let (status, error, fields) = match self {
+            AppError::Validation(msg, fields) => (StatusCode::BAD_REQUEST, msg, fields),
+            AppError::NotFound(msg) => (StatusCode::NOT_FOUND, msg, Vec::new()),
+            AppError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg, Vec::new()),
+            AppError::Conflict(msg) => (StatusCode::CONFLICT, msg, Vec::new()),
+            AppError::Internal(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg, Vec::new()),
         };
 
-        let body = Json(serde_json::json!({
-            "error": error_message
-        }));
-
-        (status, body).into_response()
+        (status, Json(ErrorResponse { error, fields })).into_response()
     }
 }
 
@@ -687,7 +1409,15 @@ impl std::fmt::Display for AppError {
         match self {
             AppError::NotFound(msg) => write!(f, "Not found: {}", msg),
             AppError::BadRequest(msg) => write!(f, "Bad request: {}", msg),
+            AppError::Conflict(msg) => write!(f, "Conflict: {}", msg),
             AppError::Internal(msg) => write!(f, "Internal error: {}", msg),
+            AppError::Validation(msg, fields) => {
+                write!(f, "{}:", msg)?;
+                for field in fields {
+                    write!(f, " {}: {};", field.field, field.message)?;
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -0,0 +1,68 @@
+use utoipa::{OpenApi, ToSchema};
+
+use crate::{config::ImageConfig, ProfileBuildRequest};
+
+/// OpenAPI 3 description of the HTTP API, served at `/api/openapi.json`.
+///
+/// Schemas are derived from the request and response types themselves, so
+/// the document cannot drift from what the handlers accept. A new handler
+/// only needs its `#[utoipa::path]` attribute and an entry in `paths`.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "imgforge API",
+        description = "Build, flash and manage single-board computer images.",
+        license(name = "Apache-2.0", identifier = "Apache-2.0")
+    ),
+    paths(
+        crate::health_check,
+        crate::list_devices,
+        crate::list_images,
+        crate::list_wifi_devices,
+        crate::create_build,
+        crate::flash_device,
+        crate::list_profiles,
+        crate::create_profile,
+        crate::get_profile,
+        crate::update_profile,
+        crate::delete_profile,
+        crate::get_resolved_profile,
+        crate::list_secrets,
+        crate::create_secret,
+        crate::get_secret,
+        crate::update_secret,
+        crate::delete_secret,
+        crate::list_jobs,
+        crate::get_job,
+        crate::get_job_log,
+        crate::download_job_log,
+        crate::cancel_job,
+        crate::rebuild_job,
+        crate::upload_file,
+        crate::ws_handler,
+        crate::devices_ws_handler,
+    ),
+    components(schemas(crate::events::JobEvent, crate::watcher::DeviceEvent)),
+    tags(
+        (name = "jobs", description = "Build and flash jobs, their logs and the queue"),
+        (name = "profiles", description = "Saved, composable build configurations"),
+        (name = "secrets", description = "Encrypted values referenced from configurations"),
+        (name = "devices", description = "Host devices"),
+        (name = "images", description = "Built and uploaded images"),
+        (name = "system"),
+    )
+)]
+pub struct ApiDoc;
+
+/// Body of `POST /api/build`: a full configuration, or a saved profile with
+/// optional overrides. Requests with a `profile` key are the latter.
+///
+/// Only used to describe the endpoint; the handler tells the two apart by
+/// the `profile` key so that errors name the form that was meant.
+#[allow(dead_code)]
+#[derive(ToSchema)]
+#[serde(untagged)]
+pub enum BuildRequest {
+    Profile(ProfileBuildRequest),
+    Config(Box<ImageConfig>),
+}
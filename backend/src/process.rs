@@ -0,0 +1,134 @@
+use std::{io, process::ExitStatus, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    process::Child,
+    sync::mpsc,
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::{
+    events::PhaseTracker,
+    logs::{LogHub, LogStream},
+};
+
+/// How long a cancelled process group gets to clean up after SIGTERM.
+const TERMINATE_GRACE: Duration = Duration::from_secs(10);
+
+/// Waits for the child to exit, tearing down its whole process group if
+/// `cancel` fires first.
+///
+/// The child must have been spawned with `process_group(0)` so that chroot'd
+/// package managers and qemu helpers started by the script go down with it.
+pub async fn wait_or_cancel(
+    child: &mut Child,
+    cancel: &CancellationToken,
+) -> io::Result<ExitStatus> {
+    tokio::select! {
+        status = child.wait() => return status,
+        _ = cancel.cancelled() => {}
+    }
+
+    terminate_group(child).await
+}
+
+/// Sends SIGTERM to the child's process group and escalates to SIGKILL if it
+/// has not exited within the grace period.
+pub async fn terminate_group(child: &mut Child) -> io::Result<ExitStatus> {
+    let Some(pid) = child.id() else {
+        // Already reaped.
+        return child.wait().await;
+    };
+
+    signal_group(pid, libc::SIGTERM);
+    if let Ok(status) = tokio::time::timeout(TERMINATE_GRACE, child.wait()).await {
+        return status;
+    }
+
+    warn!("Process group {} ignored SIGTERM, sending SIGKILL", pid);
+    signal_group(pid, libc::SIGKILL);
+    child.wait().await
+}
+
+fn signal_group(pid: u32, signal: libc::c_int) {
+    // SAFETY: kill(2) with a negative pid signals the process group; it has
+    // no memory-safety preconditions.
+    let rc = unsafe { libc::kill(-(pid as libc::pid_t), signal) };
+    if rc != 0 {
+        warn!(
+            "Failed to signal process group {}: {}",
+            pid,
+            io::Error::last_os_error()
+        );
+    }
+}
+
+/// Merges the child's stdout and stderr into the job log in arrival order.
+///
+/// Carriage returns end a line as well, so progress that a tool run by
+/// `imgforge.sh` redraws in place arrives as individual updates. Every line
+/// passes through `tracker`, which is handed back once both streams have
+/// closed.
+pub fn capture_output(
+    child: &mut Child,
+    label: &'static str,
+    job_id: &str,
+    logs: Arc<LogHub>,
+    mut tracker: PhaseTracker,
+) -> JoinHandle<PhaseTracker> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    if let Some(stdout) = child.stdout.take() {
+        forward_lines(stdout, LogStream::Stdout, tx.clone());
+    }
+    if let Some(stderr) = child.stderr.take() {
+        forward_lines(stderr, LogStream::Stderr, tx);
+    }
+
+    let job_id = job_id.to_string();
+    tokio::spawn(async move {
+        while let Some((stream, line)) = rx.recv().await {
+            let line = logs.scrub(&job_id, &line).await;
+            match stream {
+                LogStream::Stderr => warn!("[{}] {}", label, line.trim()),
+                _ => info!("[{}] {}", label, line.trim()),
+            }
+            if let Some(line) = tracker.rewrite(&line) {
+                logs.append(&job_id, stream, &line).await;
+            }
+        }
+        tracker
+    })
+}
+
+fn forward_lines<R>(
+    mut reader: R,
+    stream: LogStream,
+    tx: mpsc::UnboundedSender<(LogStream, String)>,
+) where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut buf = [0u8; 8192];
+        let mut line = Vec::new();
+        loop {
+            let n = match reader.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            for &byte in &buf[..n] {
+                if byte == b'\n' || byte == b'\r' {
+                    if !line.is_empty() {
+                        let _ = tx.send((stream, String::from_utf8_lossy(&line).into_owned()));
+                        line.clear();
+                    }
+                } else {
+                    line.push(byte);
+                }
+            }
+        }
+        if !line.is_empty() {
+            let _ = tx.send((stream, String::from_utf8_lossy(&line).into_owned()));
+        }
+    });
+}
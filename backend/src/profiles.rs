@@ -0,0 +1,391 @@
+use serde::{Deserialize, Serialize};
+use std::{fs, io, path::PathBuf};
+use tokio::sync::Mutex;
+use tracing::warn;
+use utoipa::ToSchema;
+
+use crate::{
+    config::{check_psk_ssid, is_valid_name, merge_layer, ImageConfig},
+    credentials, AppError,
+};
+
+/// `ImageConfig` fields stored sealed and masked in responses.
+const SECRET_FIELDS: [&str; 2] = ["root_password", "wifi_password"];
+
+/// Records the SSID a stored `wifi_password` key was derived for.
+const PSK_SSID: &str = "wifi_psk_ssid";
+
+/// A named, reusable build configuration.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Profile {
+    pub name: String,
+    pub created_at: String,
+    pub updated_at: String,
+    #[serde(flatten)]
+    pub definition: ProfileDefinition,
+}
+
+/// What a profile sets, possibly on top of other profiles.
+///
+/// The effective configuration is the `extend`ed profile, then each
+/// `include` in order, then `config`, combined with
+/// [`merge_layer`](crate::config::merge_layer). Only the complete result has
+/// to be a valid `ImageConfig`.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ProfileDefinition {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extend: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    /// `ImageConfig` fields set by this profile itself.
+    pub config: serde_json::Value,
+}
+
+impl Profile {
+    /// Copy safe to return from the API, with secrets masked. Sending the
+    /// mask back in an update keeps the stored value. References into the
+    /// secret store are only names and stay visible.
+    pub fn redacted(mut self) -> Self {
+        if let serde_json::Value::Object(config) = &mut self.definition.config {
+            for field in SECRET_FIELDS {
+                if let Some(value) = config.get_mut(field).filter(|v| v.is_string()) {
+                    *value = credentials::MASK.into();
+                }
+            }
+        }
+        self
+    }
+}
+
+/// Profiles stored as one JSON file each under `~/.imgforge/profiles`.
+pub struct ProfileStore {
+    dir: PathBuf,
+    /// Serializes writers so two creates of the same name cannot both win.
+    write_lock: Mutex<()>,
+}
+
+impl ProfileStore {
+    pub fn new(dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(ProfileStore {
+            dir,
+            write_lock: Mutex::new(()),
+        })
+    }
+
+    pub fn list(&self) -> Result<Vec<Profile>, AppError> {
+        let entries = fs::read_dir(&self.dir)
+            .map_err(|e| AppError::Internal(format!("Failed to read profiles: {}", e)))?;
+
+        let mut profiles = Vec::new();
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            match fs::read(&path).map(|data| serde_json::from_slice::<Profile>(&data)) {
+                Ok(Ok(profile)) => profiles.push(profile),
+                Ok(Err(e)) => warn!("Skipping unreadable profile {}: {}", path.display(), e),
+                Err(e) => warn!("Failed to read profile {}: {}", path.display(), e),
+            }
+        }
+        profiles.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(profiles)
+    }
+
+    pub fn get(&self, name: &str) -> Result<Profile, AppError> {
+        let path = self.path(name)?;
+        let data = fs::read(&path).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => AppError::NotFound(format!("Profile {} not found", name)),
+            _ => AppError::Internal(format!("Failed to read profile {}: {}", name, e)),
+        })?;
+        serde_json::from_slice(&data)
+            .map_err(|e| AppError::Internal(format!("Profile {} is corrupt: {}", name, e)))
+    }
+
+    pub async fn create(
+        &self,
+        name: &str,
+        definition: ProfileDefinition,
+    ) -> Result<Profile, AppError> {
+        let _guard = self.write_lock.lock().await;
+        if self.path(name)?.exists() {
+            return Err(AppError::Conflict(format!(
+                "Profile {} already exists",
+                name
+            )));
+        }
+
+        let now = chrono::Utc::now().to_rfc3339();
+        let profile = Profile {
+            name: name.to_string(),
+            created_at: now.clone(),
+            updated_at: now,
+            definition,
+        };
+        self.write(&profile)?;
+        Ok(profile)
+    }
+
+    pub async fn update(
+        &self,
+        name: &str,
+        definition: ProfileDefinition,
+    ) -> Result<Profile, AppError> {
+        let _guard = self.write_lock.lock().await;
+        let profile = Profile {
+            updated_at: chrono::Utc::now().to_rfc3339(),
+            definition,
+            ..self.get(name)?
+        };
+        self.write(&profile)?;
+        Ok(profile)
+    }
+
+    /// Deletes a profile unless another profile still extends or includes
+    /// it.
+    pub async fn delete(&self, name: &str) -> Result<(), AppError> {
+        let _guard = self.write_lock.lock().await;
+        let dependents: Vec<String> = self
+            .list()?
+            .into_iter()
+            .filter(|p| {
+                p.definition.extend.as_deref() == Some(name)
+                    || p.definition.include.iter().any(|i| i == name)
+            })
+            .map(|p| p.name)
+            .collect();
+        if !dependents.is_empty() {
+            return Err(AppError::Conflict(format!(
+                "Profile {} is used by {}",
+                name,
+                dependents.join(", ")
+            )));
+        }
+        fs::remove_file(self.path(name)?).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => AppError::NotFound(format!("Profile {} not found", name)),
+            _ => AppError::Internal(format!("Failed to delete profile {}: {}", name, e)),
+        })
+    }
+
+    /// Effective configuration of a stored profile.
+    pub fn resolve(&self, name: &str) -> Result<ImageConfig, AppError> {
+        let profile = self.get(name)?;
+        self.resolve_definition(name, &profile.definition)
+    }
+
+    /// Gets `definition` ready to be saved as `name`. Masked secrets are
+    /// restored from the stored profile, referenced profiles must exist
+    /// without forming a cycle, a complete result must be valid, and the
+    /// root password and Wi-Fi passphrase are sealed like a build config's.
+    /// A stored Wi-Fi key only carries over while the SSID it was derived
+    /// for stays the same. Incomplete results are accepted, since base
+    /// profiles and includes are meant to be partial.
+    pub fn prepare(
+        &self,
+        name: &str,
+        mut definition: ProfileDefinition,
+    ) -> Result<ProfileDefinition, AppError> {
+        let stored = self.get(name).ok();
+        if let serde_json::Value::Object(config) = &mut definition.config {
+            for field in SECRET_FIELDS {
+                let Some(value) = config.get_mut(field) else {
+                    continue;
+                };
+                if value.as_str() != Some(credentials::MASK) {
+                    continue;
+                }
+                *value = stored
+                    .as_ref()
+                    .and_then(|p| p.definition.config.get(field))
+                    .filter(|v| !v.is_null())
+                    .cloned()
+                    .ok_or_else(|| {
+                        AppError::BadRequest(format!("Profile {} has no stored {}", name, field))
+                    })?;
+                if field == "wifi_password" {
+                    // The key keeps the SSID it was derived for.
+                    match stored
+                        .as_ref()
+                        .and_then(|p| p.definition.config.get(PSK_SSID))
+                    {
+                        Some(ssid) => config.insert(PSK_SSID.to_string(), ssid.clone()),
+                        None => config.remove(PSK_SSID),
+                    };
+                }
+            }
+        }
+
+        let resolved = self.resolve_layers(&definition, &mut vec![name.to_string()])?;
+        if let Ok(config) = serde_json::from_value::<ImageConfig>(resolved.clone()) {
+            config.validate()?;
+        }
+        let field = |key: &str| resolved.get(key).and_then(|v| v.as_str());
+        if let (Some(ssid), Some(password)) = (field("wifi_ssid"), field("wifi_password")) {
+            check_psk_ssid(ssid, field(PSK_SSID), password)
+                .map_err(|e| AppError::Validation("Invalid configuration".to_string(), vec![e]))?;
+        }
+
+        let Some(config) = definition.config.as_object_mut() else {
+            return Ok(definition);
+        };
+        if let Some(password) = config.get("root_password").and_then(|v| v.as_str()) {
+            let hash = credentials::hash_root_password(password)?;
+            config.insert("root_password".to_string(), hash.into());
+        }
+        if let Some(passphrase) = config.get("wifi_password").and_then(|v| v.as_str()) {
+            let ssid = resolved
+                .get("wifi_ssid")
+                .and_then(|v| v.as_str())
+                .ok_or_else(|| {
+                    AppError::BadRequest(format!(
+                        "Profile {} sets wifi_password without a wifi_ssid to derive the key from",
+                        name
+                    ))
+                })?;
+            let psk = credentials::wpa_psk(ssid, passphrase);
+            config.insert("wifi_password".to_string(), psk.into());
+            config.insert(PSK_SSID.to_string(), ssid.into());
+        }
+        Ok(definition)
+    }
+
+    fn resolve_definition(
+        &self,
+        name: &str,
+        definition: &ProfileDefinition,
+    ) -> Result<ImageConfig, AppError> {
+        let mut chain = vec![name.to_string()];
+        let value = self.resolve_layers(definition, &mut chain)?;
+        serde_json::from_value(value).map_err(|e| {
+            AppError::BadRequest(format!(
+                "Profile {} does not resolve to a complete configuration: {}",
+                name, e
+            ))
+        })
+    }
+
+    /// Merges the parent, includes and own fields of `definition`. `chain`
+    /// holds the profiles being resolved so that cycles are reported instead
+    /// of recursing forever.
+    fn resolve_layers(
+        &self,
+        definition: &ProfileDefinition,
+        chain: &mut Vec<String>,
+    ) -> Result<serde_json::Value, AppError> {
+        if !definition.config.is_object() {
+            return Err(AppError::BadRequest(format!(
+                "Profile {} must have an object as config",
+                chain.last().map(String::as_str).unwrap_or_default()
+            )));
+        }
+
+        let mut value = serde_json::Value::Object(serde_json::Map::new());
+        for name in definition.extend.iter().chain(&definition.include) {
+            if chain.contains(name) {
+                return Err(AppError::BadRequest(format!(
+                    "Profile cycle: {} -> {}",
+                    chain.join(" -> "),
+                    name
+                )));
+            }
+            let parent = self.get(name).map_err(|e| match e {
+                AppError::NotFound(msg) => AppError::BadRequest(msg),
+                e => e,
+            })?;
+            chain.push(name.clone());
+            let layer = self.resolve_layers(&parent.definition, chain)?;
+            chain.pop();
+            merge_layer(&mut value, &layer);
+        }
+        merge_layer(&mut value, &definition.config);
+        Ok(value)
+    }
+
+    fn write(&self, profile: &Profile) -> Result<(), AppError> {
+        let path = self.path(&profile.name)?;
+        let tmp = path.with_extension("json.tmp");
+        serde_json::to_vec_pretty(profile)
+            .map_err(io::Error::from)
+            .and_then(|data| fs::write(&tmp, data))
+            .and_then(|_| fs::rename(&tmp, &path))
+            .map_err(|e| {
+                AppError::Internal(format!("Failed to save profile {}: {}", profile.name, e))
+            })
+    }
+
+    fn path(&self, name: &str) -> Result<PathBuf, AppError> {
+        if !is_valid_name(name) {
+            return Err(AppError::BadRequest(format!(
+                "Invalid profile name '{}': use 1 to 64 letters, digits, '-', '_' or '.'",
+                name
+            )));
+        }
+        Ok(self.dir.join(format!("{}.json", name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> (ProfileStore, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("imgforge-profiles-{}", uuid::Uuid::new_v4()));
+        (ProfileStore::new(dir.clone()).unwrap(), dir)
+    }
+
+    fn definition(extend: Option<&str>, config: serde_json::Value) -> ProfileDefinition {
+        ProfileDefinition {
+            extend: extend.map(str::to_string),
+            include: Vec::new(),
+            config,
+        }
+    }
+
+    async fn save(
+        store: &ProfileStore,
+        name: &str,
+        definition: ProfileDefinition,
+    ) -> Result<Profile, AppError> {
+        let definition = store.prepare(name, definition)?;
+        store.create(name, definition).await
+    }
+
+    #[tokio::test]
+    async fn inherited_psk_needs_the_passphrase_for_another_ssid() {
+        let (store, dir) = store();
+        let wifi = serde_json::json!({"wifi_ssid": "Office", "wifi_password": "correct horse"});
+        let base = save(&store, "base", definition(None, wifi)).await.unwrap();
+        assert_eq!(base.definition.config["wifi_psk_ssid"], "Office");
+
+        let lab = definition(Some("base"), serde_json::json!({"wifi_ssid": "Lab"}));
+        let result = save(&store, "lab", lab).await;
+        assert!(
+            matches!(&result, Err(AppError::Validation(_, errors)) if errors[0].field == "wifi_password"),
+            "{:?}",
+            result
+        );
+
+        let lab = definition(
+            Some("base"),
+            serde_json::json!({"wifi_ssid": "Lab", "wifi_password": "battery staple"}),
+        );
+        let lab = save(&store, "lab", lab).await.unwrap();
+        assert_eq!(lab.definition.config["wifi_psk_ssid"], "Lab");
+
+        // Updating with the mask keeps the key and the SSID it fits.
+        let same = definition(
+            None,
+            serde_json::json!({"wifi_ssid": "Office", "wifi_password": credentials::MASK}),
+        );
+        let same = store.prepare("base", same).unwrap();
+        assert_eq!(same.config["wifi_psk_ssid"], "Office");
+        let moved = definition(
+            None,
+            serde_json::json!({"wifi_ssid": "Lab", "wifi_password": credentials::MASK}),
+        );
+        assert!(store.prepare("base", moved).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
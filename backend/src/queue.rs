@@ -0,0 +1,307 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use tokio::sync::{Mutex, Notify};
+use tracing::info;
+
+use crate::{
+    jobs::{BuildJob, JobKind, JobStatus, JobStore},
+    AppError,
+};
+
+type Task = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Jobs whose queue position changed, with the slot holding the new one.
+type Moved = Vec<(String, Arc<AtomicUsize>)>;
+
+/// Queues build and flash jobs and starts them as slots become free.
+///
+/// Builds and flashes have separate concurrency limits so a long image build
+/// never blocks writing a card. Within a lane, higher priorities run first
+/// and equal priorities run in submission order.
+pub struct Scheduler {
+    jobs: Arc<JobStore>,
+    builds: Arc<Lane>,
+    flashes: Arc<Lane>,
+}
+
+struct Lane {
+    name: &'static str,
+    limit: usize,
+    jobs: Arc<JobStore>,
+    state: Mutex<LaneState>,
+    notify: Notify,
+}
+
+#[derive(Default)]
+struct LaneState {
+    running: usize,
+    /// Kept in run order: highest priority first, FIFO within a priority.
+    queue: Vec<Pending>,
+}
+
+struct Pending {
+    job_id: String,
+    priority: i32,
+    /// 1-based position last handed out; 0 until the job is first numbered.
+    position: Arc<AtomicUsize>,
+    task: Task,
+}
+
+impl Scheduler {
+    /// Reads `IMGFORGE_MAX_BUILDS` (default 1) and `IMGFORGE_MAX_FLASHES`
+    /// (default 4).
+    pub fn from_env(jobs: Arc<JobStore>) -> Self {
+        let max_builds = env_limit("IMGFORGE_MAX_BUILDS", 1);
+        let max_flashes = env_limit("IMGFORGE_MAX_FLASHES", 4);
+        info!(
+            "Scheduler limits: {} concurrent builds, {} concurrent flashes",
+            max_builds, max_flashes
+        );
+        Scheduler::new(jobs, max_builds, max_flashes)
+    }
+
+    pub fn new(jobs: Arc<JobStore>, max_builds: usize, max_flashes: usize) -> Self {
+        let builds = Lane::start("build", max_builds, jobs.clone());
+        let flashes = Lane::start("flash", max_flashes, jobs.clone());
+        Scheduler {
+            jobs,
+            builds,
+            flashes,
+        }
+    }
+
+    /// Queues `task` to run once the lane for `kind` has a free slot.
+    pub async fn submit<F>(&self, kind: &JobKind, job_id: &str, priority: i32, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let lane = self.lane(kind);
+        let moved = {
+            let mut state = lane.state.lock().await;
+            let index = state
+                .queue
+                .iter()
+                .position(|p| p.priority < priority)
+                .unwrap_or(state.queue.len());
+            state.queue.insert(
+                index,
+                Pending {
+                    job_id: job_id.to_string(),
+                    priority,
+                    position: Arc::default(),
+                    task: Box::pin(task),
+                },
+            );
+            state.renumber()
+        };
+        lane.notify.notify_one();
+        lane.publish_positions(moved).await;
+    }
+
+    /// Cancels a job, dropping it from the queue if it has not started yet.
+    pub async fn cancel(&self, id: &str) -> Result<BuildJob, AppError> {
+        let job = self.jobs.cancel(id).await?;
+        if job.status != JobStatus::Queued {
+            return Ok(job);
+        }
+
+        let lane = self.lane(&job.kind);
+        let mut state = lane.state.lock().await;
+        let Some(index) = state.queue.iter().position(|p| p.job_id == id) else {
+            // Started between the status check and taking the lock; the
+            // runner will observe the cancellation itself.
+            return Ok(job);
+        };
+        state.queue.remove(index);
+        let moved = state.renumber();
+        drop(state);
+        lane.publish_positions(moved).await;
+
+        info!("Removed job {} from the {} queue", id, lane.name);
+        Ok(self.jobs.mark_cancelled(id).await.unwrap_or(job))
+    }
+
+    fn lane(&self, kind: &JobKind) -> &Lane {
+        match kind {
+            JobKind::Build => &self.builds,
+            JobKind::Flash => &self.flashes,
+        }
+    }
+}
+
+impl Lane {
+    fn start(name: &'static str, limit: usize, jobs: Arc<JobStore>) -> Arc<Self> {
+        let lane = Arc::new(Lane {
+            name,
+            limit,
+            jobs,
+            state: Mutex::new(LaneState::default()),
+            notify: Notify::new(),
+        });
+        tokio::spawn(lane.clone().dispatch());
+        lane
+    }
+
+    /// Starts queued tasks whenever a job is submitted or a running one ends.
+    async fn dispatch(self: Arc<Self>) {
+        loop {
+            let moved = {
+                let mut state = self.state.lock().await;
+                while state.running < self.limit && !state.queue.is_empty() {
+                    let pending = state.queue.remove(0);
+                    state.running += 1;
+                    self.jobs.start(&pending.job_id).await;
+
+                    let lane = self.clone();
+                    tokio::spawn(async move {
+                        pending.task.await;
+                        lane.state.lock().await.running -= 1;
+                        lane.notify.notify_one();
+                    });
+                }
+                state.renumber()
+            };
+            self.publish_positions(moved).await;
+            self.notify.notified().await;
+        }
+    }
+
+    /// Records new queue positions on the jobs. Runs after the lane lock is
+    /// released, so it can race with later renumbering or with the job
+    /// starting; reading the slot at write time and skipping jobs that left
+    /// the queue keeps the last write correct either way.
+    async fn publish_positions(&self, moved: Moved) {
+        for (job_id, position) in moved {
+            self.jobs
+                .update(&job_id, |job| {
+                    if job.status == JobStatus::Queued {
+                        job.queue_position = Some(position.load(Ordering::Relaxed));
+                    }
+                })
+                .await;
+        }
+    }
+}
+
+impl LaneState {
+    /// Numbers the queue from 1 and returns the jobs whose position moved.
+    fn renumber(&mut self) -> Moved {
+        self.queue
+            .iter()
+            .enumerate()
+            .filter(|(index, p)| p.position.swap(index + 1, Ordering::Relaxed) != index + 1)
+            .map(|(_, p)| (p.job_id.clone(), p.position.clone()))
+            .collect()
+    }
+}
+
+fn env_limit(var: &str, default: usize) -> usize {
+    std::env::var(var)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or(default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jobs::{FlashDevice, FlashTarget};
+    use std::time::Duration;
+    use tokio::sync::{mpsc, oneshot};
+
+    fn flash_job() -> BuildJob {
+        BuildJob::flash(FlashTarget {
+            image_path: "/tmp/os.img".to_string(),
+            devices: vec![FlashDevice::new("/dev/sdx".to_string(), String::new())],
+            verify: false,
+            eject_after: false,
+            image_size: None,
+        })
+    }
+
+    /// Queues a flash job whose task reports its name once it runs.
+    async fn submit(
+        scheduler: &Scheduler,
+        jobs: &JobStore,
+        priority: i32,
+        name: &'static str,
+        ran: &mpsc::UnboundedSender<&'static str>,
+    ) -> String {
+        let job = flash_job();
+        jobs.insert(job.clone()).await;
+        let ran = ran.clone();
+        scheduler
+            .submit(&JobKind::Flash, &job.id, priority, async move {
+                ran.send(name).unwrap();
+            })
+            .await;
+        job.id
+    }
+
+    async fn position(jobs: &JobStore, id: &str) -> (JobStatus, Option<usize>) {
+        let job = jobs.get(id).await.unwrap();
+        (job.status, job.queue_position)
+    }
+
+    #[tokio::test]
+    async fn queued_jobs_run_by_priority_and_cancelled_ones_never_run() {
+        let dir = std::env::temp_dir().join(format!("imgforge-queue-{}", uuid::Uuid::new_v4()));
+        let jobs = Arc::new(JobStore::load(dir.clone()).unwrap());
+        let scheduler = Scheduler::new(jobs.clone(), 1, 1);
+        let (ran, mut order) = mpsc::unbounded_channel();
+
+        // Hold the only flash slot until everything else is queued.
+        let blocker = flash_job();
+        jobs.insert(blocker.clone()).await;
+        let (release, hold) = oneshot::channel::<()>();
+        scheduler
+            .submit(&JobKind::Flash, &blocker.id, 0, async move {
+                hold.await.ok();
+            })
+            .await;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while position(&jobs, &blocker.id).await.0 != JobStatus::Running {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+
+        let low = submit(&scheduler, &jobs, 0, "low", &ran).await;
+        let high = submit(&scheduler, &jobs, 5, "high", &ran).await;
+        let mid = submit(&scheduler, &jobs, 1, "mid", &ran).await;
+        let later_high = submit(&scheduler, &jobs, 5, "later high", &ran).await;
+
+        let queued = |n| (JobStatus::Queued, Some(n));
+        assert_eq!(position(&jobs, &high).await, queued(1));
+        assert_eq!(position(&jobs, &later_high).await, queued(2));
+        assert_eq!(position(&jobs, &mid).await, queued(3));
+        assert_eq!(position(&jobs, &low).await, queued(4));
+
+        let cancelled = scheduler.cancel(&mid).await.unwrap();
+        assert_eq!(
+            (cancelled.status, cancelled.queue_position),
+            (JobStatus::Cancelled, None)
+        );
+        assert_eq!(position(&jobs, &low).await, queued(3));
+
+        release.send(()).unwrap();
+        let mut run = Vec::new();
+        while run.len() < 3 {
+            let name = tokio::time::timeout(Duration::from_secs(5), order.recv());
+            run.push(name.await.unwrap().unwrap());
+        }
+        assert_eq!(run, ["high", "later high", "low"]);
+        assert_eq!(position(&jobs, &mid).await, (JobStatus::Cancelled, None));
+        assert!(order.try_recv().is_err());
+
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,367 @@
+use serde::Deserialize;
+use serde_json::Value;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    config::{
+        merge_layer, shell_quote, BoardType, BuildMode, ImageConfig, PresetImage, SecretValue,
+    },
+    AppError,
+};
+
+/// An image definition meant to live in version control, written in TOML or
+/// YAML:
+///
+/// ```toml
+/// extend = "base.toml"
+/// include = ["wifi/office.toml"]
+/// hostname = "gateway"
+/// board_type = "raspberrypi"
+/// preset_image = "RaspberryPiLite"
+/// enable_ssh = true
+/// new_username = "ops"
+/// extra_size = "2G"
+/// compose_file = "stack/docker-compose.yml"
+/// packages = ["htop"]
+/// scripts = ["scripts/setup.sh"]
+/// ```
+///
+/// Fields mirror `ImageConfig`. File references and local base images are
+/// resolved relative to the recipe that names them, and the
+/// `change_username`, `set_root_password` and `expand_image` flags follow
+/// from whether the corresponding value is present.
+///
+/// A recipe may `extend` one other recipe and `include` further ones; see
+/// [`Recipe::load`] for how they are combined.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Recipe {
+    pub hostname: String,
+    pub board_type: BoardType,
+    #[serde(default = "default_mode")]
+    pub mode: BuildMode,
+    #[serde(default)]
+    pub enable_ssh: bool,
+    pub new_username: Option<String>,
+    pub root_password: Option<SecretValue>,
+    pub wifi_ssid: Option<String>,
+    pub wifi_password: Option<SecretValue>,
+    pub extra_size: Option<String>,
+    pub base_image_url: Option<String>,
+    pub preset_image: Option<PresetImage>,
+    pub compose_file: Option<PathBuf>,
+    /// Debian packages installed in the image before the scripts run.
+    #[serde(default)]
+    pub packages: Vec<String>,
+    /// Scripts run inside the image, in order.
+    #[serde(default)]
+    pub scripts: Vec<PathBuf>,
+    pub inline_command: Option<String>,
+}
+
+fn default_mode() -> BuildMode {
+    BuildMode::Artifact
+}
+
+impl Recipe {
+    /// Loads a `.toml`, `.yaml` or `.yml` recipe together with the recipes
+    /// it builds on.
+    ///
+    /// Layers are applied in this order: the `extend`ed recipe, each
+    /// `include` in the order listed, then the recipe's own fields. Scalars
+    /// from later layers win, maps merge key by key, lists such as
+    /// `packages` and `scripts` are concatenated, and `null` (YAML `~`)
+    /// clears an inherited value.
+    pub fn load(path: &Path) -> Result<Self, AppError> {
+        let value = load_layers(path, &mut Vec::new())?;
+        serde_json::from_value(value)
+            .map_err(|e| AppError::BadRequest(format!("Invalid recipe {}: {}", path.display(), e)))
+    }
+
+    /// Builds the `ImageConfig`, reading referenced files relative to
+    /// `base_dir` (normally the recipe's directory).
+    pub fn into_config(self, base_dir: &Path) -> Result<ImageConfig, AppError> {
+        let read = |file: &Path| {
+            let path = base_dir.join(file);
+            fs::read_to_string(&path).map_err(|e| {
+                AppError::BadRequest(format!("Failed to read {}: {}", path.display(), e))
+            })
+        };
+        let docker_compose_content = self.compose_file.as_deref().map(read).transpose()?;
+
+        let mut scripts = Vec::new();
+        for file in &self.scripts {
+            scripts.push((file.display().to_string(), read(file)?));
+        }
+        let custom_script_content = combine_scripts(&self.packages, scripts)?;
+
+        let base_image_url = self.base_image_url.map(|source| {
+            if source.contains("://") || Path::new(&source).is_absolute() {
+                source
+            } else {
+                base_dir.join(&source).display().to_string()
+            }
+        });
+
+        Ok(ImageConfig {
+            hostname: self.hostname,
+            change_username: self.new_username.is_some(),
+            new_username: self.new_username,
+            set_root_password: self.root_password.is_some(),
+            root_password: self.root_password,
+            enable_ssh: self.enable_ssh,
+            wifi_ssid: self.wifi_ssid,
+            wifi_password: self.wifi_password,
+            wifi_psk_ssid: None,
+            board_type: self.board_type,
+            mode: self.mode,
+            expand_image: self.extra_size.is_some(),
+            extra_size: self.extra_size,
+            base_image_url,
+            preset_image: self.preset_image,
+            docker_compose_content,
+            custom_script_content,
+            inline_command: self.inline_command,
+        })
+    }
+}
+
+/// Reads `path` and everything it extends or includes into one merged
+/// document. `chain` holds the recipes currently being loaded so that
+/// cycles are reported instead of recursing forever.
+fn load_layers(path: &Path, chain: &mut Vec<PathBuf>) -> Result<Value, AppError> {
+    let path = fs::canonicalize(path)
+        .map_err(|e| AppError::BadRequest(format!("Failed to read {}: {}", path.display(), e)))?;
+    if chain.contains(&path) {
+        let cycle: Vec<String> = chain
+            .iter()
+            .chain([&path])
+            .map(|p| p.display().to_string())
+            .collect();
+        return Err(AppError::BadRequest(format!(
+            "Recipe cycle: {}",
+            cycle.join(" -> ")
+        )));
+    }
+    let dir = path.parent().unwrap_or(Path::new("/")).to_path_buf();
+
+    let mut document = parse_file(&path)?;
+    let Value::Object(fields) = &mut document else {
+        return Err(AppError::BadRequest(format!(
+            "Invalid recipe {}: expected a table of settings",
+            path.display()
+        )));
+    };
+    let extend = match fields.remove("extend") {
+        None | Some(Value::Null) => None,
+        Some(Value::String(parent)) => Some(parent),
+        Some(_) => return Err(reference_error(&path, "extend")),
+    };
+    let include = match fields.remove("include") {
+        None | Some(Value::Null) => Vec::new(),
+        Some(Value::Array(items)) => items
+            .into_iter()
+            .map(|item| match item {
+                Value::String(s) => Ok(s),
+                _ => Err(reference_error(&path, "include")),
+            })
+            .collect::<Result<_, _>>()?,
+        Some(_) => return Err(reference_error(&path, "include")),
+    };
+    absolutize_paths(fields, &dir);
+
+    chain.push(path);
+    let mut merged = Value::Object(serde_json::Map::new());
+    for reference in extend.iter().chain(&include) {
+        let layer = load_layers(&dir.join(reference), chain)?;
+        merge_layer(&mut merged, &layer);
+    }
+    chain.pop();
+
+    merge_layer(&mut merged, &document);
+    Ok(merged)
+}
+
+fn parse_file(path: &Path) -> Result<Value, AppError> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| AppError::BadRequest(format!("Failed to read {}: {}", path.display(), e)))?;
+
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    let parsed = match extension {
+        "toml" => toml::from_str(&text).map_err(|e| e.to_string()),
+        "yaml" | "yml" => serde_yaml::from_str(&text).map_err(|e| e.to_string()),
+        _ => {
+            return Err(AppError::BadRequest(format!(
+                "Unsupported recipe format for {}: expected .toml, .yaml or .yml",
+                path.display()
+            )))
+        }
+    };
+    parsed.map_err(|e| AppError::BadRequest(format!("Invalid recipe {}: {}", path.display(), e)))
+}
+
+fn reference_error(path: &Path, key: &str) -> AppError {
+    let expected = match key {
+        "extend" => "a recipe path",
+        _ => "a list of recipe paths",
+    };
+    AppError::BadRequest(format!(
+        "Invalid recipe {}: {} must be {}",
+        path.display(),
+        key,
+        expected
+    ))
+}
+
+/// Rewrites file references relative to `dir`, so that they still point at
+/// the right files once merged into a recipe in another directory.
+fn absolutize_paths(fields: &mut serde_json::Map<String, Value>, dir: &Path) {
+    let absolutize = |value: &mut Value| {
+        if let Value::String(s) = value {
+            if !s.contains("://") && !Path::new(s.as_str()).is_absolute() {
+                *s = dir.join(s.as_str()).display().to_string();
+            }
+        }
+    };
+    for key in ["compose_file", "base_image_url"] {
+        if let Some(value) = fields.get_mut(key) {
+            absolutize(value);
+        }
+    }
+    if let Some(Value::Array(scripts)) = fields.get_mut("scripts") {
+        scripts.iter_mut().for_each(absolutize);
+    }
+}
+
+/// Turns `packages` and `scripts` into the single script `imgforge.sh` runs
+/// inside the image. Each script runs in its own shell so an early `exit`
+/// does not skip the ones after it. Scripts are written out to files and run
+/// by path rather than passed with `bash -c`, since a single argument is
+/// limited to 128 KiB.
+fn combine_scripts(
+    packages: &[String],
+    scripts: Vec<(String, String)>,
+) -> Result<Option<String>, AppError> {
+    if packages.is_empty() && scripts.len() <= 1 {
+        return Ok(scripts.into_iter().next().map(|(_, content)| content));
+    }
+
+    let mut combined = String::from("#!/bin/bash\nset -e\n");
+    if !packages.is_empty() {
+        let mut install = String::from("DEBIAN_FRONTEND=noninteractive apt-get install -y");
+        for package in packages {
+            let valid = !package.is_empty()
+                && !package.starts_with('-')
+                && package
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '+' | '-' | ':' | '='));
+            if !valid {
+                return Err(AppError::BadRequest(format!(
+                    "Invalid package name '{}'",
+                    package
+                )));
+            }
+            install.push(' ');
+            install.push_str(package);
+        }
+        combined.push_str("apt-get update\n");
+        combined.push_str(&install);
+        combined.push('\n');
+    }
+    if scripts.is_empty() {
+        return Ok(Some(combined));
+    }
+
+    combined.push_str("scripts=$(mktemp -d)\ntrap 'rm -rf \"$scripts\"' EXIT\n");
+    let delimiter = format!("IMGFORGE_SCRIPT_{}", uuid::Uuid::new_v4().simple());
+    for (index, (name, mut content)) in scripts.into_iter().enumerate() {
+        let file_name = Path::new(&name)
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let path = shell_quote(&format!("{}-{}", index + 1, file_name));
+        if !content.ends_with('\n') {
+            content.push('\n');
+        }
+        combined.push_str(&format!("cat > \"$scripts\"/{} <<'{}'\n", path, delimiter));
+        combined.push_str(&content);
+        combined.push_str(&format!("{}\n/bin/bash \"$scripts\"/{}\n", delimiter, path));
+    }
+    Ok(Some(combined))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn combined_scripts_run_in_order_whatever_their_size() {
+        let dir = std::env::temp_dir().join(format!("imgforge-recipe-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let out = dir.join("out");
+
+        // Past the 128 KiB a single `bash -c` argument may hold.
+        let large = format!(
+            "{}echo \"first $0\" >> \"$OUT\"\nexit 0\necho unreachable >> \"$OUT\"\n",
+            "# padding\n".repeat(20_000)
+        );
+        let tricky = "cat >> \"$OUT\" <<'EOF'\nsecond 'quoted' $HOME\nEOF".to_string();
+        let scripts = vec![
+            ("/recipes/scripts/large.sh".to_string(), large),
+            ("it's.sh".to_string(), tricky),
+        ];
+        let combined = combine_scripts(&[], scripts).unwrap().unwrap();
+        let script = dir.join("combined.sh");
+        fs::write(&script, combined).unwrap();
+
+        let status = Command::new("/bin/bash")
+            .arg(&script)
+            .env("OUT", &out)
+            .status()
+            .unwrap();
+        let output = fs::read_to_string(&out).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(status.success());
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 2, "{}", output);
+        assert!(lines[0].starts_with("first /") && lines[0].ends_with("/1-large.sh"));
+        assert_eq!(lines[1], "second 'quoted' $HOME");
+    }
+
+    #[test]
+    fn combined_scripts_stop_at_the_first_failure() {
+        let scripts = vec![
+            ("a.sh".to_string(), "exit 3".to_string()),
+            ("b.sh".to_string(), "touch \"$OUT\"".to_string()),
+        ];
+        let combined = combine_scripts(&[], scripts).unwrap().unwrap();
+        let out = std::env::temp_dir().join(format!("imgforge-recipe-{}", uuid::Uuid::new_v4()));
+        let status = Command::new("/bin/bash")
+            .args(["-c", &combined])
+            .env("OUT", &out)
+            .status()
+            .unwrap();
+        assert_eq!(status.code(), Some(3));
+        assert!(!out.exists());
+    }
+
+    #[test]
+    fn packages_are_installed_before_the_scripts() {
+        let combined = combine_scripts(&["htop".to_string(), "vim=2:9.0".to_string()], Vec::new())
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            combined,
+            "#!/bin/bash\nset -e\napt-get update\n\
+             DEBIAN_FRONTEND=noninteractive apt-get install -y htop vim=2:9.0\n"
+        );
+        for package in ["", "-o=evil", "a;b", "$(id)"] {
+            assert!(combine_scripts(&[package.to_string()], Vec::new()).is_err());
+        }
+    }
+}
@@ -0,0 +1,478 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit, OsRng, Payload},
+    AeadCore, Aes256Gcm, Key, Nonce,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs, io,
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::{
+    config::{is_valid_name, ImageConfig, SecretValue},
+    credentials, AppError,
+};
+
+/// Public details of a stored secret. The value itself never leaves the
+/// store except to a running build.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SecretInfo {
+    pub name: String,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+/// On-disk form: the value encrypted with AES-256-GCM under the master key,
+/// with the secret's name as associated data so files cannot be swapped.
+#[derive(Serialize, Deserialize)]
+struct SecretFile {
+    name: String,
+    created_at: String,
+    updated_at: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Secrets encrypted at rest under `~/.imgforge/secrets`.
+///
+/// The master key comes from `IMGFORGE_MASTER_KEY` (64 hex digits) or, when
+/// unset, from `~/.imgforge/master.key`, which is generated on first start.
+pub struct SecretStore {
+    dir: PathBuf,
+    cipher: Aes256Gcm,
+    write_lock: Mutex<()>,
+}
+
+impl SecretStore {
+    pub fn open(home: &Path) -> io::Result<Self> {
+        let dir = home.join("secrets");
+        fs::create_dir_all(&dir)?;
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+
+        let key = master_key(&home.join("master.key"))?;
+        Ok(SecretStore {
+            dir,
+            cipher: Aes256Gcm::new(&key),
+            write_lock: Mutex::new(()),
+        })
+    }
+
+    pub fn list(&self) -> Result<Vec<SecretInfo>, AppError> {
+        let entries = fs::read_dir(&self.dir)
+            .map_err(|e| AppError::Internal(format!("Failed to read secrets: {}", e)))?;
+
+        let mut secrets = Vec::new();
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            match fs::read(&path).map(|data| serde_json::from_slice::<SecretFile>(&data)) {
+                Ok(Ok(file)) => secrets.push(file.info()),
+                Ok(Err(e)) => warn!("Skipping unreadable secret {}: {}", path.display(), e),
+                Err(e) => warn!("Failed to read secret {}: {}", path.display(), e),
+            }
+        }
+        secrets.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(secrets)
+    }
+
+    pub fn get(&self, name: &str) -> Result<SecretInfo, AppError> {
+        self.read(name).map(|file| file.info())
+    }
+
+    pub async fn create(&self, name: &str, value: &str) -> Result<SecretInfo, AppError> {
+        let _guard = self.write_lock.lock().await;
+        if self.path(name)?.exists() {
+            return Err(AppError::Conflict(format!(
+                "Secret {} already exists",
+                name
+            )));
+        }
+        let now = chrono::Utc::now().to_rfc3339();
+        self.write(name, value, now.clone(), now)
+    }
+
+    pub async fn update(&self, name: &str, value: &str) -> Result<SecretInfo, AppError> {
+        let _guard = self.write_lock.lock().await;
+        let created_at = self.read(name)?.created_at;
+        self.write(name, value, created_at, chrono::Utc::now().to_rfc3339())
+    }
+
+    pub async fn delete(&self, name: &str) -> Result<(), AppError> {
+        let _guard = self.write_lock.lock().await;
+        fs::remove_file(self.path(name)?).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => AppError::NotFound(format!("Secret {} not found", name)),
+            _ => AppError::Internal(format!("Failed to delete secret {}: {}", name, e)),
+        })
+    }
+
+    /// Fails unless every secret `config` refers to exists, so a typo is
+    /// reported when the build is submitted rather than when it runs.
+    pub fn check_references(&self, config: &ImageConfig) -> Result<(), AppError> {
+        for value in [&config.root_password, &config.wifi_password] {
+            if let Some(SecretValue::Stored { secret }) = value {
+                self.get(secret).map_err(|e| match e {
+                    AppError::NotFound(msg) => AppError::BadRequest(msg),
+                    e => e,
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces references in `config` with the decrypted values. Only the
+    /// build runner calls this, right before writing the job's env file.
+    pub fn resolve(&self, config: &mut ImageConfig) -> Result<(), AppError> {
+        for value in [&mut config.root_password, &mut config.wifi_password] {
+            if let Some(SecretValue::Stored { secret }) = value {
+                *value = Some(SecretValue::Plain(self.reveal(secret)?));
+            }
+        }
+        Ok(())
+    }
+
+    /// Keeps the sealed root password hash and Wi-Fi key of build `job_id`,
+    /// whose record only holds its config masked, so the build can be re-run.
+    pub fn keep_job_secrets(&self, job_id: &str, config: &ImageConfig) -> Result<(), AppError> {
+        let sealed = JobSecrets {
+            root_password: unmasked(&config.root_password),
+            wifi_password: unmasked(&config.wifi_password),
+        };
+        if sealed.root_password.is_none() && sealed.wifi_password.is_none() {
+            return Ok(());
+        }
+
+        let value = serde_json::to_string(&sealed)
+            .map_err(|e| AppError::Internal(format!("Failed to serialize secrets: {}", e)))?;
+        let now = chrono::Utc::now().to_rfc3339();
+        let file = self.encrypt(job_id, &value, &job_aad(job_id), now.clone(), now)?;
+        let dir = self.dir.join("jobs");
+        fs::create_dir_all(&dir)
+            .and_then(|_| save(&job_secrets_path(&dir, job_id)?, &file))
+            .map_err(|e| {
+                AppError::Internal(format!("Failed to save secrets of job {}: {}", job_id, e))
+            })
+    }
+
+    /// Puts the values kept by [`keep_job_secrets`](Self::keep_job_secrets)
+    /// back in place of their masks. Masks stay where nothing was kept.
+    pub fn restore_job_secrets(
+        &self,
+        job_id: &str,
+        config: &mut ImageConfig,
+    ) -> Result<(), AppError> {
+        let path = job_secrets_path(&self.dir.join("jobs"), job_id)
+            .map_err(|e| AppError::BadRequest(e.to_string()))?;
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => {
+                return Err(AppError::Internal(format!(
+                    "Failed to read secrets of job {}: {}",
+                    job_id, e
+                )))
+            }
+        };
+        let corrupt = |e: serde_json::Error| {
+            AppError::Internal(format!("Secrets of job {} are corrupt: {}", job_id, e))
+        };
+        let file: SecretFile = serde_json::from_slice(&data).map_err(corrupt)?;
+        let sealed: JobSecrets =
+            serde_json::from_str(&self.decrypt(&file, &job_aad(job_id))?).map_err(corrupt)?;
+
+        for (value, kept) in [
+            (&mut config.root_password, sealed.root_password),
+            (&mut config.wifi_password, sealed.wifi_password),
+        ] {
+            if let (Some(SecretValue::Plain(masked)), Some(kept)) = (value.as_mut(), kept) {
+                if masked == credentials::MASK {
+                    *masked = kept;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn reveal(&self, name: &str) -> Result<String, AppError> {
+        self.decrypt(&self.read(name)?, name.as_bytes())
+    }
+
+    /// Opens `file`, whose ciphertext was sealed with `aad`.
+    fn decrypt(&self, file: &SecretFile, aad: &[u8]) -> Result<String, AppError> {
+        let name = &file.name;
+        let decode = |field: &str, value: &str| {
+            hex::decode(value).map_err(|e| {
+                AppError::Internal(format!("Secret {} has a bad {}: {}", name, field, e))
+            })
+        };
+        let nonce = decode("nonce", &file.nonce)?;
+        let ciphertext = decode("ciphertext", &file.ciphertext)?;
+        if nonce.len() != 12 {
+            return Err(AppError::Internal(format!(
+                "Secret {} has a bad nonce",
+                name
+            )));
+        }
+
+        let plaintext = self
+            .cipher
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| {
+                AppError::Internal(format!(
+                    "Failed to decrypt secret {}; was the master key changed?",
+                    name
+                ))
+            })?;
+        String::from_utf8(plaintext)
+            .map_err(|_| AppError::Internal(format!("Secret {} is not valid UTF-8", name)))
+    }
+
+    fn read(&self, name: &str) -> Result<SecretFile, AppError> {
+        let data = fs::read(self.path(name)?).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => AppError::NotFound(format!("Secret {} not found", name)),
+            _ => AppError::Internal(format!("Failed to read secret {}: {}", name, e)),
+        })?;
+        serde_json::from_slice(&data)
+            .map_err(|e| AppError::Internal(format!("Secret {} is corrupt: {}", name, e)))
+    }
+
+    fn write(
+        &self,
+        name: &str,
+        value: &str,
+        created_at: String,
+        updated_at: String,
+    ) -> Result<SecretInfo, AppError> {
+        let file = self.encrypt(name, value, name.as_bytes(), created_at, updated_at)?;
+        save(&self.path(name)?, &file)
+            .map_err(|e| AppError::Internal(format!("Failed to save secret {}: {}", name, e)))?;
+        Ok(file.info())
+    }
+
+    /// Seals `value` with `aad` as associated data.
+    fn encrypt(
+        &self,
+        name: &str,
+        value: &str,
+        aad: &[u8],
+        created_at: String,
+        updated_at: String,
+    ) -> Result<SecretFile, AppError> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: value.as_bytes(),
+                    aad,
+                },
+            )
+            .map_err(|_| AppError::Internal(format!("Failed to encrypt secret {}", name)))?;
+        Ok(SecretFile {
+            name: name.to_string(),
+            created_at,
+            updated_at,
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(ciphertext),
+        })
+    }
+
+    fn path(&self, name: &str) -> Result<PathBuf, AppError> {
+        if !is_valid_name(name) {
+            return Err(AppError::BadRequest(format!(
+                "Invalid secret name '{}': use 1 to 64 letters, digits, '-', '_' or '.'",
+                name
+            )));
+        }
+        Ok(self.dir.join(format!("{}.json", name)))
+    }
+}
+
+impl SecretFile {
+    fn info(&self) -> SecretInfo {
+        SecretInfo {
+            name: self.name.clone(),
+            created_at: self.created_at.clone(),
+            updated_at: self.updated_at.clone(),
+        }
+    }
+}
+
+/// Sealed values of one build's config; see
+/// [`SecretStore::keep_job_secrets`].
+#[derive(Serialize, Deserialize)]
+struct JobSecrets {
+    root_password: Option<String>,
+    wifi_password: Option<String>,
+}
+
+fn unmasked(value: &Option<SecretValue>) -> Option<String> {
+    match value {
+        Some(SecretValue::Plain(value)) if value != credentials::MASK => Some(value.clone()),
+        _ => None,
+    }
+}
+
+/// Job secrets live in `secrets/jobs`, out of [`SecretStore::list`]'s
+/// sight, and are bound to their job so they cannot pass for a named
+/// secret or another job's.
+fn job_aad(job_id: &str) -> Vec<u8> {
+    format!("job:{}", job_id).into_bytes()
+}
+
+fn job_secrets_path(dir: &Path, job_id: &str) -> io::Result<PathBuf> {
+    if uuid::Uuid::parse_str(job_id).is_err() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid job id '{}'", job_id),
+        ));
+    }
+    Ok(dir.join(format!("{}.json", job_id)))
+}
+
+/// Replaces `path` with `file` atomically.
+fn save(path: &Path, file: &SecretFile) -> io::Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    serde_json::to_vec_pretty(file)
+        .map_err(io::Error::from)
+        .and_then(|data| write_private(&tmp, &data))
+        .and_then(|_| fs::rename(&tmp, path))
+}
+
+fn master_key(path: &Path) -> io::Result<Key<Aes256Gcm>> {
+    let invalid = |what: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} must be 64 hex digits", what),
+        )
+    };
+
+    if let Ok(hex_key) = std::env::var("IMGFORGE_MASTER_KEY") {
+        let bytes = hex::decode(hex_key.trim()).map_err(|_| invalid("IMGFORGE_MASTER_KEY"))?;
+        if bytes.len() != 32 {
+            return Err(invalid("IMGFORGE_MASTER_KEY"));
+        }
+        return Ok(*Key::<Aes256Gcm>::from_slice(&bytes));
+    }
+
+    match fs::read_to_string(path) {
+        Ok(hex_key) => {
+            let bytes = hex::decode(hex_key.trim()).map_err(|_| invalid("master.key"))?;
+            if bytes.len() != 32 {
+                return Err(invalid("master.key"));
+            }
+            Ok(*Key::<Aes256Gcm>::from_slice(&bytes))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let key = Aes256Gcm::generate_key(OsRng);
+            write_private(path, hex::encode(key).as_bytes())?;
+            info!("Generated secret store master key at {}", path.display());
+            Ok(key)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Writes a file only the backend's user can read.
+fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+    use std::io::Write;
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jobs::BuildJob;
+
+    fn store() -> (SecretStore, PathBuf) {
+        let home = std::env::temp_dir().join(format!("imgforge-secrets-{}", uuid::Uuid::new_v4()));
+        (SecretStore::open(&home).unwrap(), home)
+    }
+
+    fn sealed_config() -> ImageConfig {
+        let mut config: ImageConfig = serde_json::from_value(serde_json::json!({
+            "hostname": "gateway",
+            "change_username": false,
+            "set_root_password": true,
+            "root_password": "hunter22",
+            "enable_ssh": true,
+            "wifi_ssid": "Office",
+            "wifi_password": "correct horse",
+            "board_type": "raspberrypi",
+            "mode": "artifact",
+            "expand_image": false,
+            "preset_image": "RaspberryPiLite",
+        }))
+        .unwrap();
+        config.seal().unwrap();
+        config
+    }
+
+    #[test]
+    fn job_secrets_restore_the_masked_record() {
+        let (store, home) = store();
+        let sealed = sealed_config();
+        let job = BuildJob::build(&sealed);
+        store.keep_job_secrets(&job.id, &sealed).unwrap();
+
+        let mut config = job.config.unwrap();
+        let mask = Some(SecretValue::Plain(credentials::MASK.to_string()));
+        assert_eq!(config.root_password, mask);
+        assert_eq!(config.wifi_password, mask);
+
+        store.restore_job_secrets(&job.id, &mut config).unwrap();
+        assert_eq!(config.root_password, sealed.root_password);
+        assert_eq!(config.wifi_password, sealed.wifi_password);
+        assert!(config.validate().is_ok());
+        assert!(store.list().unwrap().is_empty());
+
+        fs::remove_dir_all(home).unwrap();
+    }
+
+    #[test]
+    fn job_secrets_are_bound_to_their_job() {
+        let (store, home) = store();
+        let sealed = sealed_config();
+        let (a, b) = (
+            uuid::Uuid::new_v4().to_string(),
+            uuid::Uuid::new_v4().to_string(),
+        );
+        store.keep_job_secrets(&a, &sealed).unwrap();
+        let jobs = home.join("secrets/jobs");
+        fs::copy(
+            jobs.join(format!("{}.json", a)),
+            jobs.join(format!("{}.json", b)),
+        )
+        .unwrap();
+
+        let mut config = sealed.redacted();
+        assert!(store.restore_job_secrets(&b, &mut config).is_err());
+        assert!(store.restore_job_secrets("../x", &mut config).is_err());
+
+        // Without kept values the masks stay, and validation asks for them.
+        let other = uuid::Uuid::new_v4().to_string();
+        store.restore_job_secrets(&other, &mut config).unwrap();
+        assert!(config.validate().is_err());
+
+        fs::remove_dir_all(home).unwrap();
+    }
+}
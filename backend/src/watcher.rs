@@ -0,0 +1,259 @@
+use serde::Serialize;
+use std::{
+    io, mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::unix::AsyncFd,
+    sync::{broadcast, Mutex},
+    time::MissedTickBehavior,
+};
+use tracing::{error, info, warn};
+use utoipa::ToSchema;
+
+use crate::devices::{self, Device};
+
+/// How often the disks and mount table are checked for changes uevents do
+/// not announce (mounts), or that never arrive (containers without the
+/// host's network namespace).
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Inserting a card brings a burst of uevents, one for the disk and one per
+/// partition; they are taken in one rescan.
+const SETTLE_DELAY: Duration = Duration::from_millis(300);
+
+const CHANNEL_CAPACITY: usize = 64;
+
+/// Messages on `/api/ws/devices`. `devices` comes first with the full list;
+/// the rest report changes to it.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum DeviceEvent {
+    Devices {
+        devices: Vec<Device>,
+    },
+    Added {
+        device: Device,
+    },
+    /// Carries the device as it was last seen.
+    Removed {
+        device: Device,
+    },
+    /// Something about the device changed, e.g. its partitions or mounts,
+    /// or another card went into the same reader.
+    Changed {
+        device: Device,
+    },
+}
+
+/// Keeps the list of flashable devices current and broadcasts its changes.
+pub struct DeviceWatcher {
+    devices: Mutex<Vec<Device>>,
+    tx: broadcast::Sender<DeviceEvent>,
+}
+
+impl DeviceWatcher {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(CHANNEL_CAPACITY);
+        DeviceWatcher {
+            devices: Mutex::new(Vec::new()),
+            tx,
+        }
+    }
+
+    pub async fn list(&self) -> Vec<Device> {
+        self.devices.lock().await.clone()
+    }
+
+    /// The current list and a receiver for every change after it.
+    pub async fn subscribe(&self) -> (Vec<Device>, broadcast::Receiver<DeviceEvent>) {
+        let devices = self.devices.lock().await;
+        (devices.clone(), self.tx.subscribe())
+    }
+
+    /// Rescans on block device uevents, and whenever polling finds the disk
+    /// layout or mount table changed.
+    pub async fn run(self: Arc<Self>) {
+        let mut uevents = match Uevents::open() {
+            Ok(uevents) => Some(uevents),
+            Err(e) => {
+                warn!("No uevents ({}), polling for devices instead", e);
+                None
+            }
+        };
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut layout = None;
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                result = next_uevent(&uevents) => {
+                    if let Err(e) = result {
+                        warn!("Lost the uevent socket ({}), polling for devices instead", e);
+                        uevents = None;
+                        continue;
+                    }
+                    tokio::time::sleep(SETTLE_DELAY).await;
+                    if let Some(uevents) = &uevents {
+                        uevents.drain();
+                    }
+                    // Make the next poll compare against the rescanned state.
+                    layout = None;
+                }
+            }
+
+            let current = tokio::task::spawn_blocking(devices::layout)
+                .await
+                .ok()
+                .and_then(Result::ok);
+            if current.is_some() && current == layout {
+                continue;
+            }
+            layout = current;
+            self.rescan().await;
+        }
+    }
+
+    async fn rescan(&self) {
+        let scanned = match tokio::task::spawn_blocking(devices::scan).await {
+            Ok(Ok(scanned)) => scanned,
+            Ok(Err(e)) => {
+                warn!("Failed to scan devices: {}", e);
+                return;
+            }
+            Err(e) => {
+                error!("Device scan panicked: {}", e);
+                return;
+            }
+        };
+
+        // Sending under the lock keeps each subscriber's list and events in
+        // step.
+        let mut devices = self.devices.lock().await;
+        for device in devices.iter() {
+            if !scanned.iter().any(|d| d.path == device.path) {
+                info!("Device removed: {}", device.path);
+                let _ = self.tx.send(DeviceEvent::Removed {
+                    device: device.clone(),
+                });
+            }
+        }
+        for device in &scanned {
+            match devices.iter().find(|d| d.path == device.path) {
+                None => {
+                    info!("Device added: {} ({})", device.path, device.size);
+                    let _ = self.tx.send(DeviceEvent::Added {
+                        device: device.clone(),
+                    });
+                }
+                Some(old) if old != device => {
+                    let _ = self.tx.send(DeviceEvent::Changed {
+                        device: device.clone(),
+                    });
+                }
+                Some(_) => {}
+            }
+        }
+        *devices = scanned;
+    }
+}
+
+async fn next_uevent(uevents: &Option<Uevents>) -> io::Result<()> {
+    match uevents {
+        Some(uevents) => uevents.next_block_event().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The kernel's uevent broadcast, read from a netlink socket. Messages only
+/// serve as a hint to rescan, so where they come from is not checked.
+struct Uevents {
+    fd: AsyncFd<OwnedFd>,
+}
+
+impl Uevents {
+    fn open() -> io::Result<Self> {
+        // SAFETY: socket(2) takes integer arguments only.
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                libc::NETLINK_KOBJECT_UEVENT,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the descriptor was just created and nothing else owns it.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        // SAFETY: sockaddr_nl is plain integers, for which zero is valid.
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        // Group 1 carries the kernel's own events; udev rebroadcasts on 2.
+        addr.nl_groups = 1;
+        // SAFETY: bind(2) reads `addr` for the length given, while it lives.
+        let rc = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if rc < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Uevents {
+            fd: AsyncFd::new(fd)?,
+        })
+    }
+
+    /// Waits for an event about a block device. A socket that overflowed
+    /// counts as one, since whatever was dropped may have been.
+    async fn next_block_event(&self) -> io::Result<()> {
+        let mut buf = [0u8; 8192];
+        loop {
+            let mut guard = self.fd.readable().await?;
+            match guard.try_io(|fd| recv(fd.get_ref(), &mut buf)) {
+                Ok(Ok(n)) if is_block_event(&buf[..n]) => return Ok(()),
+                Ok(Ok(_)) | Err(_) => {}
+                Ok(Err(e)) if e.raw_os_error() == Some(libc::ENOBUFS) => return Ok(()),
+                Ok(Err(e)) => return Err(e),
+            }
+        }
+    }
+
+    /// Discards queued events.
+    fn drain(&self) {
+        let mut buf = [0u8; 8192];
+        while recv(self.fd.get_ref(), &mut buf).is_ok() {}
+    }
+}
+
+fn recv(fd: &OwnedFd, buf: &mut [u8]) -> io::Result<usize> {
+    // SAFETY: recv(2) writes at most `buf.len()` bytes into `buf`.
+    let n = unsafe {
+        libc::recv(
+            fd.as_raw_fd(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+            0,
+        )
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+/// Messages are `ACTION@DEVPATH` followed by NUL-separated `KEY=value`
+/// pairs.
+fn is_block_event(message: &[u8]) -> bool {
+    message
+        .split(|&b| b == 0)
+        .any(|field| field == b"SUBSYSTEM=block")
+}
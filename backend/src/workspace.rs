@@ -0,0 +1,113 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+use tokio::process::Command;
+use tracing::{info, warn};
+
+/// Location of the build script shipped in the container image.
+const BUILD_SCRIPT: &str = "/workdir/imgforge.sh";
+
+/// The build script to run: `IMGFORGE_BUILD_SCRIPT` if set, e.g. to use a
+/// checkout outside the container, or else [`BUILD_SCRIPT`].
+pub fn build_script() -> PathBuf {
+    std::env::var_os("IMGFORGE_BUILD_SCRIPT")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(BUILD_SCRIPT))
+}
+
+/// Private scratch area for a single build job.
+///
+/// `imgforge.sh` runs with the workspace as its working directory, so the
+/// downloaded base image, `last-run.env` and the resulting `custom.img` all
+/// live here, and the image is loop-mounted at a mount point of its own.
+/// Concurrent builds therefore never touch each other's files.
+pub struct Workspace {
+    pub dir: PathBuf,
+    pub mount_point: PathBuf,
+}
+
+impl Workspace {
+    pub fn create(root: &Path, job_id: &str) -> io::Result<Self> {
+        let workspace = Workspace {
+            dir: root.join(job_id),
+            mount_point: PathBuf::from(format!("/mnt/imgforge-{}", job_id)),
+        };
+        fs::create_dir_all(&workspace.dir)?;
+        Ok(workspace)
+    }
+
+    /// Env file `imgforge.sh` sources instead of running its wizard.
+    pub fn env_file(&self) -> PathBuf {
+        self.dir.join("last-run.env")
+    }
+
+    pub fn compose_file(&self) -> PathBuf {
+        self.dir.join("docker-compose.yml")
+    }
+
+    pub fn script_file(&self) -> PathBuf {
+        self.dir.join("custom.sh")
+    }
+
+    /// Image produced by an artifact build.
+    pub fn artifact(&self) -> PathBuf {
+        self.dir.join("custom.img")
+    }
+
+    /// Moves the built image to `dest`, falling back to a copy when the
+    /// workspace and image storage are on different filesystems.
+    pub fn collect_artifact(&self, dest: &Path) -> io::Result<()> {
+        let source = self.artifact();
+        if fs::rename(&source, dest).is_err() {
+            fs::copy(&source, dest)?;
+            fs::remove_file(&source)?;
+        }
+        Ok(())
+    }
+
+    /// Tears down anything a failed or cancelled script may have left behind:
+    /// mounts under the job's mount point, loop devices backed by its image,
+    /// and finally the workspace directory itself.
+    pub async fn cleanup(&self) {
+        if self.mount_point.exists() {
+            let _ = Command::new("umount")
+                .arg("-R")
+                .arg(&self.mount_point)
+                .output()
+                .await;
+            if let Err(e) = fs::remove_dir(&self.mount_point) {
+                warn!(
+                    "Failed to remove mount point {}: {}",
+                    self.mount_point.display(),
+                    e
+                );
+            }
+        }
+
+        let artifact = self.artifact();
+        if artifact.exists() {
+            if let Ok(output) = Command::new("losetup")
+                .arg("-j")
+                .arg(&artifact)
+                .output()
+                .await
+            {
+                // Lines look like "/dev/loop3: []: (/path/custom.img)".
+                for line in String::from_utf8_lossy(&output.stdout).lines() {
+                    if let Some((loop_dev, _)) = line.split_once(':') {
+                        let _ = Command::new("losetup")
+                            .args(["-d", loop_dev])
+                            .output()
+                            .await;
+                    }
+                }
+            }
+        }
+
+        match fs::remove_dir_all(&self.dir) {
+            Ok(()) => info!("Removed workspace {}", self.dir.display()),
+            Err(e) => warn!("Failed to remove workspace {}: {}", self.dir.display(), e),
+        }
+    }
+}
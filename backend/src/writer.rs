@@ -0,0 +1,665 @@
+use sha2::{Digest, Sha256};
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Read},
+    os::unix::{
+        fs::{FileExt, OpenOptionsExt},
+        io::AsRawFd,
+    },
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver, SyncSender},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tokio_util::sync::CancellationToken;
+
+/// Bytes per write: large enough to keep USB readers streaming, small enough
+/// that cancellation and progress stay responsive.
+pub const BLOCK_SIZE: usize = 4 * 1024 * 1024;
+
+/// `O_DIRECT` needs buffers, offsets and lengths aligned to the device's
+/// logical block size; 4 KiB covers every common device.
+const ALIGN: usize = 4096;
+
+/// How often progress is reported while writing.
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Blocks read ahead of each device's writer.
+const QUEUE_DEPTH: usize = 4;
+
+/// `BLKFLSBUF` from `<linux/fs.h>`: drops a block device's buffer cache.
+const BLKFLSBUF: libc::Ioctl = 0x1261;
+
+/// Bytes processed so far, reported while [`write_image`] or
+/// [`verify_device`] runs.
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+    pub written: u64,
+    pub total: Option<u64>,
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    /// Average throughput since the write started.
+    pub fn bytes_per_sec(&self) -> u64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            (self.written as f64 / secs) as u64
+        } else {
+            0
+        }
+    }
+
+    /// Time left at the average throughput, when the total is known.
+    pub fn eta(&self) -> Option<Duration> {
+        let rate = self.bytes_per_sec();
+        let remaining = self.total?.saturating_sub(self.written);
+        (rate > 0).then(|| Duration::from_secs(remaining / rate))
+    }
+
+    pub fn percent(&self) -> Option<f32> {
+        let total = self.total.filter(|&t| t > 0)?;
+        Some((self.written as f64 * 100.0 / total as f64).min(100.0) as f32)
+    }
+}
+
+/// SHA-256 of an image as it is written, overall and per `BLOCK_SIZE`
+/// block, so that a read-back can tell where the device first differs.
+#[derive(Debug, Clone, Default)]
+pub struct Digests {
+    whole: Sha256,
+    blocks: Vec<[u8; 32]>,
+}
+
+impl Digests {
+    fn update(&mut self, block: &[u8]) {
+        self.whole.update(block);
+        self.blocks.push(Sha256::digest(block).into());
+    }
+
+    pub fn sha256(&self) -> String {
+        hex::encode(self.whole.clone().finalize())
+    }
+}
+
+#[derive(Debug)]
+pub enum FlashError {
+    Open(io::Error),
+    Read {
+        offset: u64,
+        source: io::Error,
+    },
+    Write {
+        offset: u64,
+        source: io::Error,
+    },
+    Sync(io::Error),
+    /// Reading the device back failed.
+    ReadBack {
+        offset: u64,
+        source: io::Error,
+    },
+    /// The device does not hold what was written, from `offset` on.
+    Mismatch {
+        offset: u64,
+    },
+    Cancelled,
+}
+
+impl fmt::Display for FlashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlashError::Open(e) => write!(f, "failed to open device: {}", e),
+            FlashError::Read { offset, source } => {
+                write!(f, "failed to read image at byte {}: {}", offset, source)
+            }
+            FlashError::Write { offset, source } => {
+                write!(f, "write failed at device offset {}: {}", offset, source)
+            }
+            FlashError::Sync(e) => write!(f, "failed to flush device: {}", e),
+            FlashError::ReadBack { offset, source } => {
+                write!(
+                    f,
+                    "read-back failed at device offset {}: {}",
+                    offset, source
+                )
+            }
+            FlashError::Mismatch { offset } => write!(
+                f,
+                "verification failed: device differs from the image at offset {}",
+                offset
+            ),
+            FlashError::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+/// Copies `source` onto every device in `devices` at once and flushes them.
+/// The source is read once: each block is handed to one writer thread per
+/// device through a queue of [`QUEUE_DEPTH`] blocks, so the slowest device
+/// sets the pace but memory use does not grow with the number of devices.
+/// With `digests`, every block is hashed for [`verify_device`].
+///
+/// `on_progress` is called with `None` for the bytes read from the source
+/// and with the index of a device for its bytes written, every
+/// [`REPORT_INTERVAL`] and once at the end. `on_done` gets each device's
+/// outcome as soon as it is known: the bytes written, or why it stopped.
+///
+/// A device that fails drops out without stopping the others. Only a source
+/// that cannot be read or `cancel` stops them all, which is the error
+/// returned. This blocks, so call it from `spawn_blocking`.
+pub fn write_image(
+    source: &mut dyn Read,
+    devices: &[PathBuf],
+    total: Option<u64>,
+    cancel: &CancellationToken,
+    digests: Option<&mut Digests>,
+    on_progress: &(dyn Fn(Option<usize>, Progress) + Sync),
+    on_done: &(dyn Fn(usize, Result<u64, FlashError>) + Sync),
+) -> Result<u64, FlashError> {
+    // Also stops the writers when the source fails.
+    let stop = cancel.child_token();
+
+    thread::scope(|scope| {
+        let mut queues = Vec::new();
+        let mut writers = Vec::new();
+        for (index, device) in devices.iter().enumerate() {
+            let (tx, rx) = mpsc::sync_channel(QUEUE_DEPTH);
+            queues.push(Some(tx));
+            let stop = &stop;
+            writers.push(scope.spawn(move || {
+                let result = write_device(device, rx, total, stop, |progress| {
+                    on_progress(Some(index), progress)
+                });
+                on_done(index, result);
+            }));
+        }
+
+        let read = read_source(source, &mut queues, total, cancel, digests, |progress| {
+            on_progress(None, progress)
+        });
+        if read.is_err() {
+            stop.cancel();
+        }
+        drop(queues);
+
+        for writer in writers {
+            writer
+                .join()
+                .unwrap_or_else(|e| std::panic::resume_unwind(e));
+        }
+        read
+    })
+}
+
+/// Reads `source` block by block and queues each block for every writer
+/// that is still running. Stops early once all writers have given up.
+fn read_source(
+    source: &mut dyn Read,
+    queues: &mut [Option<SyncSender<Arc<[u8]>>>],
+    total: Option<u64>,
+    cancel: &CancellationToken,
+    mut digests: Option<&mut Digests>,
+    on_progress: impl Fn(Progress),
+) -> Result<u64, FlashError> {
+    let started = Instant::now();
+    let mut last_report = started;
+    let mut offset = 0u64;
+    loop {
+        if cancel.is_cancelled() {
+            return Err(FlashError::Cancelled);
+        }
+        let mut block = vec![0u8; BLOCK_SIZE];
+        let n = fill(source, &mut block).map_err(|source| FlashError::Read { offset, source })?;
+        if n == 0 {
+            break;
+        }
+        block.truncate(n);
+        if let Some(digests) = digests.as_deref_mut() {
+            digests.update(&block);
+        }
+        offset += n as u64;
+
+        let block: Arc<[u8]> = block.into();
+        for queue in queues.iter_mut() {
+            // A writer that failed has dropped its receiver.
+            if queue
+                .as_ref()
+                .is_some_and(|tx| tx.send(block.clone()).is_err())
+            {
+                *queue = None;
+            }
+        }
+        if queues.iter().all(Option::is_none) {
+            break;
+        }
+
+        if last_report.elapsed() >= REPORT_INTERVAL {
+            last_report = Instant::now();
+            on_progress(Progress {
+                written: offset,
+                total,
+                elapsed: started.elapsed(),
+            });
+        }
+    }
+
+    on_progress(Progress {
+        written: offset,
+        total,
+        elapsed: started.elapsed(),
+    });
+    Ok(offset)
+}
+
+/// Writes the blocks queued for one device until the reader is done.
+///
+/// The device is opened exclusively and with `O_DIRECT` where supported, so
+/// the page cache neither hides slow writes from the progress numbers nor
+/// fills up with a whole image.
+fn write_device(
+    device: &Path,
+    blocks: Receiver<Arc<[u8]>>,
+    total: Option<u64>,
+    stop: &CancellationToken,
+    on_progress: impl Fn(Progress),
+) -> Result<u64, FlashError> {
+    let (file, mut direct) =
+        open_device(device, OpenOptions::new().write(true)).map_err(FlashError::Open)?;
+
+    let mut storage = aligned_buffer();
+    let buf = aligned(&mut storage);
+
+    let started = Instant::now();
+    let mut last_report = started;
+    let mut offset = 0u64;
+    for block in blocks {
+        if stop.is_cancelled() {
+            return Err(FlashError::Cancelled);
+        }
+        let n = block.len();
+        if direct && !n.is_multiple_of(ALIGN) {
+            // Only the last block of an image can be short; write it
+            // through the page cache instead.
+            disable_direct(&file).map_err(|source| FlashError::Write { offset, source })?;
+            direct = false;
+        }
+        let data = if direct {
+            buf[..n].copy_from_slice(&block);
+            &buf[..n]
+        } else {
+            &block[..]
+        };
+        write_all_at(&file, data, offset)?;
+        offset += n as u64;
+
+        if last_report.elapsed() >= REPORT_INTERVAL {
+            last_report = Instant::now();
+            on_progress(Progress {
+                written: offset,
+                total,
+                elapsed: started.elapsed(),
+            });
+        }
+    }
+    // The queue also closes when the source failed.
+    if stop.is_cancelled() {
+        return Err(FlashError::Cancelled);
+    }
+
+    file.sync_all().map_err(FlashError::Sync)?;
+    on_progress(Progress {
+        written: offset,
+        total,
+        elapsed: started.elapsed(),
+    });
+    Ok(offset)
+}
+
+/// Reads the first `length` bytes of `device` back and checks them against
+/// the `digests` taken while writing, reporting progress like
+/// [`write_image`]. Returns the SHA-256 of what was read.
+///
+/// The device's caches are dropped first and reads use `O_DIRECT`, so the
+/// data comes from the medium rather than from memory. On a mismatch,
+/// `image` (the plain image file, when there is one) pinpoints the first
+/// differing byte; otherwise the offset is that of the first differing
+/// block.
+pub fn verify_device(
+    device: &Path,
+    length: u64,
+    digests: &Digests,
+    image: Option<&File>,
+    cancel: &CancellationToken,
+    mut on_progress: impl FnMut(Progress),
+) -> Result<String, FlashError> {
+    let (file, mut direct) =
+        open_device(device, OpenOptions::new().read(true)).map_err(FlashError::Open)?;
+    drop_caches(&file);
+
+    let mut storage = aligned_buffer();
+    let buf = aligned(&mut storage);
+
+    let started = Instant::now();
+    let mut last_report = started;
+    let mut whole = Sha256::new();
+    let mut offset = 0u64;
+    for expected in &digests.blocks {
+        if cancel.is_cancelled() {
+            return Err(FlashError::Cancelled);
+        }
+        let n = (length - offset).min(BLOCK_SIZE as u64) as usize;
+        if direct && !n.is_multiple_of(ALIGN) {
+            disable_direct(&file).map_err(|source| FlashError::ReadBack { offset, source })?;
+            direct = false;
+        }
+        let block = &mut buf[..n];
+        file.read_exact_at(block, offset)
+            .map_err(|source| FlashError::ReadBack { offset, source })?;
+
+        if Sha256::digest(&*block).as_slice() != expected {
+            let within = image.and_then(|image| first_difference(image, block, offset));
+            return Err(FlashError::Mismatch {
+                offset: offset + within.unwrap_or(0),
+            });
+        }
+        whole.update(&*block);
+        offset += n as u64;
+
+        if last_report.elapsed() >= REPORT_INTERVAL {
+            last_report = Instant::now();
+            on_progress(Progress {
+                written: offset,
+                total: Some(length),
+                elapsed: started.elapsed(),
+            });
+        }
+    }
+
+    on_progress(Progress {
+        written: offset,
+        total: Some(length),
+        elapsed: started.elapsed(),
+    });
+    Ok(hex::encode(whole.finalize()))
+}
+
+/// Offset within `block` of the first byte that differs from the image.
+fn first_difference(image: &File, block: &[u8], offset: u64) -> Option<u64> {
+    let mut original = vec![0u8; block.len()];
+    image.read_exact_at(&mut original, offset).ok()?;
+    let index = original.iter().zip(block).position(|(a, b)| a != b)?;
+    Some(index as u64)
+}
+
+/// Over-allocated so that an aligned window of `BLOCK_SIZE` bytes fits; see
+/// [`aligned`].
+fn aligned_buffer() -> Vec<u8> {
+    vec![0u8; BLOCK_SIZE + ALIGN]
+}
+
+fn aligned(storage: &mut [u8]) -> &mut [u8] {
+    let start = storage.as_ptr().align_offset(ALIGN);
+    &mut storage[start..start + BLOCK_SIZE]
+}
+
+/// Opens `device` exclusively, with `O_DIRECT` unless the target does not
+/// support it. Returns whether direct I/O is on.
+fn open_device(device: &Path, options: &mut OpenOptions) -> io::Result<(File, bool)> {
+    let mut open = |flags| options.custom_flags(libc::O_EXCL | flags).open(device);
+    match open(libc::O_DIRECT) {
+        Ok(file) => Ok((file, true)),
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => Ok((open(0)?, false)),
+        Err(e) => Err(e),
+    }
+}
+
+/// Flushes the kernel's buffer cache for the device and drops its cached
+/// pages, so a read-back cannot be served from memory. Best effort: the
+/// ioctl needs `CAP_SYS_ADMIN`, and `O_DIRECT` reads bypass the cache anyway.
+fn drop_caches(file: &File) {
+    let fd = file.as_raw_fd();
+    // SAFETY: both calls take a descriptor we own and integer arguments.
+    unsafe {
+        libc::ioctl(fd, BLKFLSBUF, 0);
+        libc::posix_fadvise(fd, 0, 0, libc::POSIX_FADV_DONTNEED);
+    }
+}
+
+fn disable_direct(file: &File) -> io::Result<()> {
+    let fd = file.as_raw_fd();
+    // SAFETY: fcntl(2) on a descriptor we own, with integer arguments only.
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: as above.
+    if unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_DIRECT) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Reads until `buf` is full or the source ends; returns the bytes read.
+fn fill(source: &mut dyn Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match source.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Like `FileExt::write_all_at`, but reports the exact offset that failed.
+fn write_all_at(file: &File, mut data: &[u8], mut offset: u64) -> Result<(), FlashError> {
+    while !data.is_empty() {
+        match file.write_at(data, offset) {
+            Ok(0) => {
+                return Err(FlashError::Write {
+                    offset,
+                    source: io::Error::new(io::ErrorKind::WriteZero, "device is full"),
+                })
+            }
+            Ok(n) => {
+                data = &data[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(source) => return Err(FlashError::Write { offset, source }),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, sync::Mutex};
+
+    /// Two and a half blocks, so that the last one is short.
+    fn image() -> Vec<u8> {
+        (0..BLOCK_SIZE * 2 + BLOCK_SIZE / 2 + 123)
+            .map(|i| (i % 251) as u8 ^ (i >> 16) as u8)
+            .collect()
+    }
+
+    struct Dir(PathBuf);
+
+    impl Dir {
+        fn new() -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("imgforge-writer-{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&dir).unwrap();
+            Dir(dir)
+        }
+
+        /// An empty file to write to; devices are never created.
+        fn target(&self, name: &str) -> PathBuf {
+            let path = self.0.join(name);
+            File::create(&path).unwrap();
+            path
+        }
+    }
+
+    impl Drop for Dir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// What `on_done` reported for a target, if anything.
+    type Outcome = Option<Result<u64, FlashError>>;
+
+    /// Writes `image` to `targets`; returns what `write_image` and
+    /// `on_done` reported, by target.
+    fn write(
+        image: &[u8],
+        targets: &[PathBuf],
+        digests: Option<&mut Digests>,
+    ) -> (Result<u64, FlashError>, Vec<Outcome>) {
+        let done = Mutex::new((0..targets.len()).map(|_| None).collect::<Vec<_>>());
+        let result = write_image(
+            &mut &image[..],
+            targets,
+            Some(image.len() as u64),
+            &CancellationToken::new(),
+            digests,
+            &|_, _| {},
+            &|index, result| done.lock().unwrap()[index] = Some(result),
+        );
+        (result, done.into_inner().unwrap())
+    }
+
+    #[test]
+    fn writes_every_target_identically() {
+        let dir = Dir::new();
+        let image = image();
+        let targets: Vec<_> = (0..3).map(|i| dir.target(&format!("card{}", i))).collect();
+
+        let (result, done) = write(&image, &targets, None);
+        assert_eq!(result.unwrap(), image.len() as u64);
+        for (target, done) in targets.iter().zip(done) {
+            assert_eq!(done.unwrap().unwrap(), image.len() as u64);
+            assert!(fs::read(target).unwrap() == image, "{}", target.display());
+        }
+    }
+
+    #[test]
+    fn a_failing_target_leaves_the_others_alone() {
+        let dir = Dir::new();
+        let image = image();
+        let targets = vec![
+            dir.target("first"),
+            // Every write fails with ENOSPC.
+            PathBuf::from("/dev/full"),
+            dir.target("second"),
+            dir.0.join("missing"),
+        ];
+
+        let (result, done) = write(&image, &targets, None);
+        assert_eq!(result.unwrap(), image.len() as u64);
+        let mut done = done.into_iter().map(Option::unwrap);
+        assert_eq!(done.next().unwrap().unwrap(), image.len() as u64);
+        match done.next().unwrap() {
+            Err(FlashError::Write { offset: 0, source }) => {
+                assert_eq!(source.raw_os_error(), Some(libc::ENOSPC))
+            }
+            other => panic!("expected a write error, got {:?}", other),
+        }
+        assert_eq!(done.next().unwrap().unwrap(), image.len() as u64);
+        assert!(matches!(done.next().unwrap(), Err(FlashError::Open(_))));
+
+        assert!(fs::read(&targets[0]).unwrap() == image);
+        assert!(fs::read(&targets[2]).unwrap() == image);
+    }
+
+    #[test]
+    fn cancelling_stops_every_target() {
+        let dir = Dir::new();
+        let image = image();
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let result = write_image(
+            &mut &image[..],
+            &[dir.target("card")],
+            None,
+            &cancel,
+            None,
+            &|_, _| {},
+            &|_, result| assert!(matches!(result, Err(FlashError::Cancelled))),
+        );
+        assert!(matches!(result, Err(FlashError::Cancelled)));
+    }
+
+    #[test]
+    fn verify_matches_what_was_written() {
+        let dir = Dir::new();
+        let image = image();
+        let target = dir.target("card");
+        let mut digests = Digests::default();
+        write(&image, std::slice::from_ref(&target), Some(&mut digests))
+            .0
+            .unwrap();
+
+        let mut last = None;
+        let sha256 = verify_device(
+            &target,
+            image.len() as u64,
+            &digests,
+            None,
+            &CancellationToken::new(),
+            |progress| last = Some(progress.written),
+        )
+        .unwrap();
+        assert_eq!(sha256, hex::encode(Sha256::digest(&image)));
+        assert_eq!(sha256, digests.sha256());
+        assert_eq!(last, Some(image.len() as u64));
+    }
+
+    #[test]
+    fn verify_reports_where_the_device_differs() {
+        let dir = Dir::new();
+        let image = image();
+        let target = dir.target("card");
+        let mut digests = Digests::default();
+        write(&image, std::slice::from_ref(&target), Some(&mut digests))
+            .0
+            .unwrap();
+
+        let bad = BLOCK_SIZE as u64 + 777;
+        let file = OpenOptions::new().write(true).open(&target).unwrap();
+        file.write_all_at(&[!image[bad as usize]], bad).unwrap();
+        drop(file);
+        let raw = dir.0.join("image.img");
+        fs::write(&raw, &image).unwrap();
+
+        let verify = |original: Option<&File>| {
+            verify_device(
+                &target,
+                image.len() as u64,
+                &digests,
+                original,
+                &CancellationToken::new(),
+                |_| {},
+            )
+        };
+        // The plain image pinpoints the byte; without it, the block.
+        let raw_file = File::open(&raw).unwrap();
+        assert!(matches!(
+            verify(Some(&raw_file)),
+            Err(FlashError::Mismatch { offset }) if offset == bad
+        ));
+        assert!(matches!(
+            verify(None),
+            Err(FlashError::Mismatch { offset }) if offset == BLOCK_SIZE as u64
+        ));
+    }
+}
@@ -0,0 +1,46 @@
+use std::{fs, os::unix::fs::PermissionsExt, process::Command};
+
+/// `imgforge-backend build` on a machine where the server never ran: the
+/// home directory is empty, so everything it writes must be created first.
+#[test]
+fn build_with_empty_home() {
+    let root = std::env::temp_dir().join(format!("imgforge-cli-{}", uuid::Uuid::new_v4()));
+    let home = root.join("home");
+    fs::create_dir_all(&home).unwrap();
+
+    // Stands in for imgforge.sh: leaves an image where the real one would.
+    let script = root.join("imgforge.sh");
+    fs::write(
+        &script,
+        "#!/bin/sh\necho building\nhead -c 4096 /dev/zero > custom.img\n",
+    )
+    .unwrap();
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let recipe = root.join("r.toml");
+    fs::write(
+        &recipe,
+        "hostname = \"gateway\"\nboard_type = \"raspberrypi\"\npreset_image = \"RaspberryPiLite\"\n",
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_imgforge-backend"))
+        .arg("build")
+        .arg(&recipe)
+        .arg("--out")
+        .arg(root.join("out"))
+        .env("IMGFORGE_HOME", &home)
+        .env("IMGFORGE_BUILD_SCRIPT", &script)
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    let count = |dir: &str| fs::read_dir(root.join(dir)).map_or(0, |entries| entries.count());
+    let (configs, images) = (count("home/configs"), count("out"));
+    fs::remove_dir_all(&root).unwrap();
+
+    assert_eq!(output.status.code(), Some(0), "{}", stderr);
+    assert!(stdout.contains("building"), "{}", stdout);
+    assert_eq!(configs, 1);
+    assert_eq!(images, 1);
+}
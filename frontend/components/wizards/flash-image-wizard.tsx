@@ -4,6 +4,8 @@ import { useState, useEffect } from "react";
 import { Button } from "@/components/ui/button";
 import { Input } from "@/components/ui/input";
 import { Label } from "@/components/ui/label";
+import { Progress } from "@/components/ui/progress";
+import { Switch } from "@/components/ui/switch";
 import {
   Select,
   SelectContent,
@@ -38,12 +40,95 @@ interface StoredImage {
   modified: number;
 }
 
+interface Partition {
+  name: string;
+  path: string;
+  size_bytes: number;
+  fs_type: string | null;
+  label: string | null;
+  mountpoints: string[];
+}
+
 interface Device {
   name: string;
   path: string;
   size: string;
+  size_bytes: number;
+  fingerprint: string;
+  vendor: string | null;
+  model: string | null;
+  serial: string | null;
+  transport: string | null;
+  read_only: boolean;
+  partitions: Partition[];
+}
+
+interface Transfer {
+  phase: string;
+  device?: string;
+  bytes: number;
+  total: number | null;
+  bytes_per_sec: number;
+  eta_secs: number | null;
+}
+
+const describeDevice = (device: Device) => {
+  const title = [device.vendor, device.model].filter(Boolean).join(" ");
+  const details = [];
+  if (device.transport) details.push(device.transport.toUpperCase());
+  const mounted = device.partitions.filter((p) => p.mountpoints.length > 0);
+  if (mounted.length > 0) {
+    details.push(
+      `${mounted.length} partition${mounted.length === 1 ? "" : "s"} mounted`
+    );
+  } else if (device.partitions.length > 0) {
+    details.push(
+      `${device.partitions.length} partition${
+        device.partitions.length === 1 ? "" : "s"
+      }`
+    );
+  }
+  if (device.read_only) details.push("read-only");
+  return `${title || device.name} ${device.size}${
+    details.length > 0 ? ` (${details.join(", ")})` : ""
+  }`;
+};
+
+const formatBytes = (bytes: number) => {
+  const units = ["B", "KB", "MB", "GB", "TB"];
+  let value = bytes;
+  let unit = 0;
+  while (value >= 1024 && unit < units.length - 1) {
+    value /= 1024;
+    unit++;
+  }
+  return `${value.toFixed(unit === 0 ? 0 : 1)} ${units[unit]}`;
+};
+
+const formatEta = (secs: number) =>
+  secs >= 60 ? `${Math.floor(secs / 60)}m ${secs % 60}s` : `${secs}s`;
+
+interface FlashDeviceResult {
+  device: string;
+  status: string;
+  bytes_written: number;
+  bytes_verified: number;
+  verified: boolean | null;
+  error: string | null;
 }
 
+const describeResult = (result: FlashDeviceResult) => {
+  const parts = [`wrote ${formatBytes(result.bytes_written)}`];
+  if (result.verified !== null || result.bytes_verified > 0) {
+    parts.push(
+      `verified ${formatBytes(result.bytes_verified)}${
+        result.verified === false ? " (mismatch)" : ""
+      }`,
+    );
+  }
+  return `${result.device}: ${parts.join(", ")}`;
+};
+
 interface FlashImageWizardProps {
   onBack: () => void;
   preSelectedImage?: string;
@@ -59,6 +144,8 @@ export function FlashImageWizard({
   const [flashStatus, setFlashStatus] = useState<
     "idle" | "flashing" | "success" | "error"
   >("idle");
+  const [transfer, setTransfer] = useState<Transfer | null>(null);
+  const [results, setResults] = useState<FlashDeviceResult[]>([]);
 
   // Step 1: Select Image
   const [imageSource, setImageSource] = useState<"stored" | "upload">("stored");
@@ -69,6 +156,8 @@ export function FlashImageWizard({
   // Step 2: Select Device
   const [devices, setDevices] = useState<Device[]>([]);
   const [selectedDevice, setSelectedDevice] = useState("");
+  const [verify, setVerify] = useState(true);
+  const [ejectAfter, setEjectAfter] = useState(false);
   const [isLoadingDevices, setIsLoadingDevices] = useState(false);
 
   const totalSteps = 3;
@@ -79,6 +168,46 @@ export function FlashImageWizard({
     }
   }, [step]);
 
+  // Follow cards being inserted and removed while choosing a device.
+  useEffect(() => {
+    if (step !== 2) return;
+    const ws = new WebSocket(
+      `ws://${window.location.hostname}:3000/api/ws/devices`,
+    );
+    ws.onmessage = (event) => {
+      const message = JSON.parse(event.data);
+      if (message.type === "devices") {
+        setDevices(message.devices);
+      } else if (message.type === "added") {
+        setDevices((prev) => [
+          ...prev.filter((d) => d.path !== message.device.path),
+          message.device,
+        ]);
+      } else if (message.type === "removed") {
+        setDevices((prev) =>
+          prev.filter((d) => d.path !== message.device.path),
+        );
+        setSelectedDevice((prev) =>
+          prev === message.device.path ? "" : prev,
+        );
+      } else if (message.type === "changed") {
+        setDevices((prev) => {
+          // Another card in the same reader must be chosen again.
+          const old = prev.find((d) => d.path === message.device.path);
+          if (old && old.fingerprint !== message.device.fingerprint) {
+            setSelectedDevice((selected) =>
+              selected === message.device.path ? "" : selected,
+            );
+          }
+          return prev.map((d) =>
+            d.path === message.device.path ? message.device : d,
+          );
+        });
+      }
+    };
+    return () => ws.close();
+  }, [step]);
+
   useEffect(() => {
     if (preSelectedImage && storedImages.length > 0) {
       setSelectedImage(preSelectedImage);
@@ -112,6 +241,8 @@ export function FlashImageWizard({
   const handleFlash = async () => {
     setIsFlashing(true);
     setFlashStatus("flashing");
+    setTransfer(null);
+    setResults([]);
     setFlashLogs(["🚀 Starting flash process..."]);
 
     const imagePath =
@@ -124,10 +255,21 @@ export function FlashImageWizard({
         body: JSON.stringify({
           image_path: imagePath,
           device: selectedDevice,
+          fingerprint: devices.find((d) => d.path === selectedDevice)
+            ?.fingerprint,
+          verify,
+          eject_after: ejectAfter,
         }),
       });
 
-      if (!response.ok) throw new Error("Flash failed to start");
+      if (!response.ok) {
+        const body = await response.json().catch(() => null);
+        const reason =
+          body?.fields
+            ?.map((f: { message: string }) => f.message)
+            .join("; ") || body?.error;
+        throw new Error(reason || "Flash failed to start");
+      }
 
       const job = await response.json();
       setFlashLogs((prev) => [...prev, `✨ Flash job created: ${job.id}`]);
@@ -136,14 +278,52 @@ export function FlashImageWizard({
         `ws://${window.location.hostname}:3000/api/ws/${job.id}`,
       );
 
+      let finalStatus: string | null = null;
+
       ws.onmessage = (event) => {
-        setFlashLogs((prev) => [...prev, event.data]);
+        let message;
+        try {
+          message = JSON.parse(event.data);
+        } catch {
+          setFlashLogs((prev) => [...prev, event.data]);
+          return;
+        }
+        if (message.type === "transfer") {
+          // Events without a device track reading the image.
+          if (message.device) setTransfer(message);
+        } else if (message.type === "device") {
+          setFlashLogs((prev) => [
+            ...prev,
+            `${message.device}: ${message.status}`,
+          ]);
+        } else if (message.type === "status") {
+          finalStatus = message.status;
+        } else if (message.type === "warning") {
+          setFlashLogs((prev) => [...prev, `⚠️ ${message.message}`]);
+        } else if (message.type !== "progress") {
+          setFlashLogs((prev) => [...prev, event.data]);
+        }
       };
 
       ws.onclose = () => {
-        setFlashStatus("success");
         setIsFlashing(false);
-        setFlashLogs((prev) => [...prev, "✅ Flash completed successfully!"]);
+        fetch(`/api/jobs/${job.id}`)
+          .then((response) => (response.ok ? response.json() : null))
+          .then((finished) => setResults(finished?.flash?.devices ?? []))
+          .catch(() => setResults([]));
+        if (finalStatus === "success") {
+          setFlashStatus("success");
+          setFlashLogs((prev) => [
+            ...prev,
+            "✅ Flash completed successfully!",
+          ]);
+        } else {
+          setFlashStatus("error");
+          setFlashLogs((prev) => [
+            ...prev,
+            `❌ Flash ${finalStatus ?? "connection lost"}`,
+          ]);
+        }
       };
 
       ws.onerror = () => {
@@ -301,7 +481,7 @@ export function FlashImageWizard({
                     <div className="space-y-2">
                       <Input
                         type="file"
-                        accept=".img,.img.xz,.iso"
+                        accept=".img,.img.xz,.img.gz,.img.zst,.zip,.iso"
                         className="text-white"
                         onChange={(e) => {
                           const file = e.target.files?.[0];
@@ -311,7 +491,7 @@ export function FlashImageWizard({
                         }}
                       />
                       <p className="text-xs text-blue-200/50">
-                        Supported formats: .img, .img.xz, .iso
+                        Supported formats: .img, .img.xz, .img.gz, .img.zst, .zip, .iso
                       </p>
                     </div>
                   )}
@@ -367,7 +547,7 @@ export function FlashImageWizard({
                               {device.path}
                             </div>
                             <div className="text-sm text-blue-200/70">
-                              {device.name} - {device.size}
+                              {describeDevice(device)}
                             </div>
                           </div>
                           {selectedDevice === device.path && (
@@ -421,6 +601,23 @@ export function FlashImageWizard({
                       </CardContent>
                     </Card>
 
+                    <div className="flex items-center justify-between p-4 rounded-lg border border-blue-500/30 bg-blue-500/5">
+                      <Label className="text-white">
+                        Verify after writing
+                      </Label>
+                      <Switch checked={verify} onCheckedChange={setVerify} />
+                    </div>
+
+                    <div className="flex items-center justify-between p-4 rounded-lg border border-blue-500/30 bg-blue-500/5">
+                      <Label className="text-white">
+                        Eject when done
+                      </Label>
+                      <Switch
+                        checked={ejectAfter}
+                        onCheckedChange={setEjectAfter}
+                      />
+                    </div>
+
                     <div className="p-4 rounded-lg bg-red-500/10 border border-red-500/30">
                       <div className="flex items-start gap-3">
                         <AlertCircle className="w-5 h-5 text-red-400 flex-shrink-0 mt-0.5" />
@@ -449,8 +646,35 @@ export function FlashImageWizard({
                   <div className="space-y-4">
                     <div className="flex items-center justify-center gap-3 text-blue-200">
                       <Loader2 className="w-6 h-6 animate-spin" />
-                      <span className="text-lg font-medium">Flashing...</span>
+                      <span className="text-lg font-medium">
+                        {transfer?.phase === "verify"
+                          ? "Verifying..."
+                          : "Flashing..."}
+                      </span>
                     </div>
+                    {transfer && (
+                      <div className="space-y-2">
+                        <Progress
+                          value={
+                            transfer.total
+                              ? (transfer.bytes * 100) / transfer.total
+                              : 0
+                          }
+                        />
+                        <div className="flex justify-between text-sm text-blue-200/70">
+                          <span>
+                            {formatBytes(transfer.bytes)}
+                            {transfer.total !== null &&
+                              ` of ${formatBytes(transfer.total)}`}
+                          </span>
+                          <span>
+                            {formatBytes(transfer.bytes_per_sec)}/s
+                            {transfer.eta_secs !== null &&
+                              ` · ${formatEta(transfer.eta_secs)} left`}
+                          </span>
+                        </div>
+                      </div>
+                    )}
                     <div className="bg-slate-950/80 text-green-400 p-4 rounded-lg font-mono text-xs h-96 overflow-y-auto border border-blue-500/30">
                       {flashLogs.map((line, i) => (
                         <div key={i}>{line}</div>
@@ -471,6 +695,14 @@ export function FlashImageWizard({
                     <p className="text-blue-200/70">
                       Your device is ready to use. You can safely remove it now.
                     </p>
+                    {results.map((result) => (
+                      <p
+                        key={result.device}
+                        className="text-sm text-blue-200/70 font-mono"
+                      >
+                        {describeResult(result)}
+                      </p>
+                    ))}
                     <Button onClick={onBack} variant="outline">
                       Back to Home
                     </Button>
@@ -486,6 +718,14 @@ export function FlashImageWizard({
                     <p className="text-blue-200/70">
                       Check the logs above for details
                     </p>
+                    {results.map((result) => (
+                      <p
+                        key={result.device}
+                        className="text-sm text-blue-200/70 font-mono"
+                      >
+                        {describeResult(result)}
+                      </p>
+                    ))}
                     <div className="flex gap-4 justify-center">
                       <Button
                         onClick={() => setFlashStatus("idle")}
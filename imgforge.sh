@@ -20,7 +20,24 @@ persist_var() {
     local key="$1" val="$2"
     grep -v "^${key}=" "$STATE_FILE" 2>/dev/null > "$STATE_FILE.tmp" || true
     mv "$STATE_FILE.tmp" "$STATE_FILE" 2>/dev/null || true
-    echo "${key}=${val}" >> "$STATE_FILE"
+    # %q keeps the value intact when the state file is sourced again.
+    printf '%s=%q\n' "$key" "$val" >> "$STATE_FILE"
+}
+
+# Derives the raw 64-hex WPA PSK so the passphrase itself is never stored.
+wifi_psk() {
+    wpa_passphrase "$1" "$2" | sed -n 's/^[[:space:]]*psk=\([0-9a-f]\{64\}\)$/\1/p'
+}
+
+# Progress markers parsed by the backend into structured job events.
+phase_start() { echo "::phase-start::$1"; }
+phase_end()   { echo "::phase-end::$1"; }
+warn()        { echo "::warning::$*"; }
+
+# Download with curl's progress bar on stdout, one update per line.
+download() {
+    local url="$1" out="$2"
+    curl -fL --progress-bar "$url" -o "$out" 2>&1 | stdbuf -o0 tr '\r' '\n'
 }
 
 list_safe_devices() {
@@ -94,6 +111,7 @@ expand_rootfs_in_img() {
   local img="$1"
   local add_bytes="$2"   # e.g. +2G or +4096M
 
+  phase_start resize
   echo "Resizing image by $add_bytes ..."
   # 1) Enlarge the raw image
   truncate -s +"$add_bytes" "$img"
@@ -132,27 +150,29 @@ expand_rootfs_in_img() {
     echo "Expanding filesystem on $root_dev ..."
     sudo resize2fs "$root_dev"
   else
-    echo "Filesystem on $root_dev is not ext2/3/4; skipping resize2fs."
+    warn "Filesystem on $root_dev is not ext2/3/4; skipping resize2fs."
   fi
 
   # 7) Detach loop
   sudo losetup -d "$loop"
+  phase_end resize
 }
 
 
+# Decompresses into the working directory, leaving the source untouched:
+# uploads are shared by every build that uses them.
 decompress_if_needed() {
     local FILE="$1"
     if [[ "$FILE" == *.xz ]]; then
         echo "Decompressing $FILE..." >&2
-        unxz -k "$FILE"
-        echo "${FILE%.xz}"
+        xz -dc "$FILE" > ./base.img
+        FILE=./base.img
     elif [[ "$FILE" == *.gz ]]; then
         echo "Decompressing $FILE..." >&2
-        gunzip -k "$FILE"
-        echo "${FILE%.gz}"
-    else
-        echo "$FILE"
+        gzip -dc "$FILE" > ./base.img
+        FILE=./base.img
     fi
+    echo "$FILE"
 }
 
 # -------------------------
@@ -173,7 +193,9 @@ collect_customizations() {
     persist_var SET_ROOTPW "$SET_ROOTPW"
     if [[ "$SET_ROOTPW" == "y" ]]; then
         read -s -p "Enter root password: " ROOTPW; echo
-        persist_var ROOTPW "$ROOTPW"
+        ROOTPW_HASH=$(printf '%s' "$ROOTPW" | openssl passwd -6 -stdin)
+        unset ROOTPW
+        persist_var ROOTPW_HASH "$ROOTPW_HASH"
     fi
 
     read -p "Enable SSH on the device? (y/n) " ENABLE_SSH
@@ -211,7 +233,9 @@ collect_customizations() {
     persist_var WIFI_CHOICE "$WIFI_CHOICE"
     case $WIFI_CHOICE in
         1) read -p "SSID: " WIFI_SSID; persist_var WIFI_SSID "$WIFI_SSID"
-           read -s -p "Password: " WIFI_PASS; echo; persist_var WIFI_PASS "$WIFI_PASS";;
+           read -s -p "Password: " WIFI_PASS; echo
+           WIFI_PSK=$(wifi_psk "$WIFI_SSID" "$WIFI_PASS"); unset WIFI_PASS
+           persist_var WIFI_PSK "$WIFI_PSK";;
         2) SSIDS=$(sudo grep -r '^ssid=' /etc/NetworkManager/system-connections/ 2>/dev/null | cut -d= -f2)
            echo "$SSIDS" | nl -w2 -s') '
            read -p "Select SSID number: " N
@@ -220,7 +244,8 @@ collect_customizations() {
            if [[ -z "$WIFI_PASS" ]]; then
                read -s -p "Enter Wi-Fi password: " WIFI_PASS; echo
            fi
-           persist_var WIFI_PASS "$WIFI_PASS";;
+           WIFI_PSK=$(wifi_psk "$WIFI_SSID" "$WIFI_PASS"); unset WIFI_PASS
+           persist_var WIFI_PSK "$WIFI_PSK";;
     esac
 }
 
@@ -238,7 +263,7 @@ apply_customizations() {
     sudo sed -i "s/127.0.1.1.*/127.0.1.1\t$HOSTNAME/" "$MNT/etc/hosts" || true
 
     if [[ "${SET_ROOTPW:-n}" == "y" ]]; then
-        echo "root:$ROOTPW" | sudo chroot "$MNT" chpasswd
+        echo "root:$ROOTPW_HASH" | sudo chroot "$MNT" chpasswd -e
     fi
 
     if [[ "${CHANGE_USERNAME:-n}" == "y" && -n "${NEW_USERNAME:-}" ]]; then
@@ -250,11 +275,11 @@ apply_customizations() {
     fi
 
     # Wi-Fi config
-    if [[ -n "${WIFI_SSID:-}" && -n "${WIFI_PASS:-}" ]]; then
+    if [[ -n "${WIFI_SSID:-}" && -n "${WIFI_PSK:-}" ]]; then
         # Detect Radxa-style /boot/config support
         if [[ -d "$BOOT/config" && -f "$BOOT/config/before.txt" ]]; then
             echo "Detected Radxa image with /boot/config support."
-            echo "connect_wi-fi ${WIFI_SSID} ${WIFI_PASS}" | sudo tee "$BOOT/config/before.txt" >/dev/null
+            echo "connect_wi-fi ${WIFI_SSID} ${WIFI_PSK}" | sudo tee "$BOOT/config/before.txt" >/dev/null
             sudo touch "$BOOT/config/enable_ssh"
             echo "Configured Wi-Fi via before.txt (Radxa first-boot hook)."
         else
@@ -266,7 +291,7 @@ country=DE
 
 network={
     ssid="${WIFI_SSID}"
-    psk="${WIFI_PASS}"
+    psk=${WIFI_PSK}
     key_mgmt=WPA-PSK
 }
 CONF
@@ -342,12 +367,17 @@ UNIT
 # -------------------------
 # Main flow
 # -------------------------
-echo "Do you want to:"
-echo "1) Flash a device now"
-echo "2) Create a reproducible artifact"
-read -p "Choice [1/2]: " MODE
+if [[ -z "${MODE:-}" ]]; then
+    echo "Do you want to:"
+    echo "1) Flash a device now"
+    echo "2) Create a reproducible artifact"
+    read -p "Choice [1/2]: " MODE
+fi
 if [[ "$MODE" == "2" ]]; then
-    if [[ -f "$STATE_FILE" ]]; then
+    if [[ "${SKIP_WIZARD:-n}" == "y" && -f "$STATE_FILE" ]]; then
+        # Non-interactive run (e.g. from the backend): config is pre-seeded.
+        source "$STATE_FILE"
+    elif [[ -f "$STATE_FILE" ]]; then
         echo "Found previous configuration in $STATE_FILE."
         read -p "Do you want to reuse it? (y/n) " REUSE
         if [[ "$REUSE" == "y" ]]; then
@@ -358,7 +388,7 @@ if [[ "$MODE" == "2" ]]; then
 fi
 
 # only get BOARD if it's not already set
-if [[ -z "$BOARD" ]]; then
+if [[ -z "${BOARD:-}" ]]; then
     echo "Select board type:"
     echo "1) Raspberry Pi / Radxa (.img)"
     echo "2) NVIDIA Jetson (rootfs bundle + flash.sh)"
@@ -377,11 +407,6 @@ if [[ "$MODE" == "2" && "$BOARD" == "1" ]]; then
         if [[ "$HAVE_IMG" == "y" ]]; then
             read -p "Path or URL to base .img: " BASE_IMG
             persist_var BASE_IMG "$BASE_IMG"
-            if [[ "$BASE_IMG" =~ ^https?:// ]]; then
-                ensure_curl
-                curl -L "$BASE_IMG" -o ./base.img
-                BASE_IMG=./base.img
-            fi
         else
             echo "Select base image:"
             echo "1) Raspberry Pi OS Lite (64-bit)"
@@ -389,17 +414,41 @@ if [[ "$MODE" == "2" && "$BOARD" == "1" ]]; then
             echo "3) Radxa Zero3W Ubuntu 22.04 LTS Server with Linux 6.1"
             read -p "Choice [1/2/3]: " IMG_CHOICE
             persist_var IMG_CHOICE "$IMG_CHOICE"
-            ensure_curl; ensure_tools
-            case $IMG_CHOICE in
-                1) curl -L -o rpi-os.zip https://downloads.raspberrypi.org/raspios_lite_arm64_latest; unzip -p rpi-os.zip "*.img" > base.img;;
-                2) curl -L -o base.img.xz https://github.com/Joshua-Riek/ubuntu-rockchip/releases/download/v2.4.0/ubuntu-22.04-preinstalled-desktop-arm64-radxa-zero3.img.xz; unxz -f base.img.xz;;
-                3) curl -L -o base.img.xz https://github.com/Joshua-Riek/ubuntu-rockchip/releases/download/v2.4.0/ubuntu-22.04-preinstalled-server-arm64-radxa-zero3.img.xz; unxz -f base.img.xz;;
-            esac
+        fi
+    fi
+
+    # Fetch the base image (also runs for pre-seeded, non-interactive configs)
+    if [[ "${HAVE_IMG:-n}" == "y" ]]; then
+        if [[ "$BASE_IMG" =~ ^https?:// ]]; then
+            ensure_curl
+            phase_start download
+            download "$BASE_IMG" ./base.img
+            phase_end download
             BASE_IMG=./base.img
         fi
+    else
+        ensure_curl; ensure_tools
+        phase_start download
+        case $IMG_CHOICE in
+            1) download https://downloads.raspberrypi.org/raspios_lite_arm64_latest rpi-os.zip;;
+            2) download https://github.com/Joshua-Riek/ubuntu-rockchip/releases/download/v2.4.0/ubuntu-22.04-preinstalled-desktop-arm64-radxa-zero3.img.xz base.img.xz;;
+            3) download https://github.com/Joshua-Riek/ubuntu-rockchip/releases/download/v2.4.0/ubuntu-22.04-preinstalled-server-arm64-radxa-zero3.img.xz base.img.xz;;
+        esac
+        phase_end download
+        phase_start decompress
+        case $IMG_CHOICE in
+            1) unzip -p rpi-os.zip "*.img" > base.img;;
+            2|3) unxz -f base.img.xz;;
+        esac
+        phase_end decompress
+        BASE_IMG=./base.img
     fi
 
-    BASE_IMG=$(decompress_if_needed "$BASE_IMG")
+    if [[ "$BASE_IMG" == *.xz || "$BASE_IMG" == *.gz ]]; then
+        phase_start decompress
+        BASE_IMG=$(decompress_if_needed "$BASE_IMG")
+        phase_end decompress
+    fi
     cp "$BASE_IMG" custom.img
 
     # --- optional resize ---
@@ -418,8 +467,9 @@ if [[ "$MODE" == "2" && "$BOARD" == "1" ]]; then
     fi
     # --- end resize ---
 
+    phase_start mount
     LOOP_DEV=$(sudo losetup -Pf --show custom.img)
-    MNT=/mnt/custom
+    MNT=${IMGFORGE_MNT:-/mnt/custom}
 
     # cleanup from previous runs
     sudo umount -R "$MNT" 2>/dev/null || true
@@ -445,17 +495,23 @@ if [[ "$MODE" == "2" && "$BOARD" == "1" ]]; then
         sudo mount --bind /$d "$MNT/$d"
     done
 
+    phase_end mount
+
     ensure_tools
     sudo cp /usr/bin/qemu-aarch64-static "$MNT/usr/bin/"
 
+    phase_start customize
     apply_customizations "$MNT" "$MNT/boot"
+    phase_end customize
 
     # cleanup
+    phase_start unmount
     for d in run dev/pts proc sys dev; do
         sudo umount "$MNT/$d"
     done
     sudo umount "$MNT/boot" "$MNT"
     sudo losetup -d "$LOOP_DEV"
+    phase_end unmount
 
     echo "Artifact created: custom.img"
 fi
@@ -483,9 +539,15 @@ if [[ "$MODE" == "2" && "$BOARD" == "2" ]]; then
     fi
     (cd $WORKDIR/Linux_for_Tegra && sudo ./apply_binaries.sh)
     sudo cp /usr/bin/qemu-aarch64-static $L4T_DIR/usr/bin/ || true
+    phase_start mount
     for d in dev sys proc; do sudo mount --bind /$d $L4T_DIR/$d; done
+    phase_end mount
+    phase_start customize
     apply_customizations $L4T_DIR
+    phase_end customize
+    phase_start unmount
     for d in dev sys proc; do sudo umount $L4T_DIR/$d; done
+    phase_end unmount
     tar czf custom-jetson-rootfs.tar.gz -C $WORKDIR/Linux_for_Tegra rootfs
     echo "Artifact created: custom-jetson-rootfs.tar.gz"
     echo "To flash: put Jetson in recovery mode and run flash.sh from Linux_for_Tegra."